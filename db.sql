@@ -11,6 +11,15 @@ comment on column crates.name is 'crate names are limited to 64 characters, see
 create unique index if not exists crates_name_uindex
   on crates (name);
 
+-- crates.io considers names that differ only in case and `-`/`_` to be the same crate
+create or replace function normalize_crate_name(_name varchar(64))
+    RETURNS varchar(64)
+    LANGUAGE sql
+    IMMUTABLE
+AS $$
+    select lower(replace(_name, '-', '_'))
+$$;
+
 create table if not exists subscriptions
 (
   user_id bigint not null,
@@ -31,18 +40,37 @@ alter table subscriptions
     foreign key (crate_id) references crates
       on delete cascade;
 
-create or replace procedure subscribe(_user_id bigint, _crate varchar(64))
+alter table subscriptions
+  add column if not exists filter varchar(256) default 'all' not null;
+
+comment on column subscriptions.filter is 'filter of versions: `all`, `major`, `stable` or a semver requirement';
+
+alter table subscriptions
+  add column if not exists events int default 15 not null;
+
+comment on column subscriptions.events is 'bitmask of kinds of updates: 1 - releases, 2 - yanks, 4 - unyanks, 8 - deletions, 16 - releases raising MSRV';
+
+alter table subscriptions
+  add column if not exists manifest varchar(256);
+
+comment on column subscriptions.manifest is 'name of the uploaded manifest (Cargo.lock/Cargo.toml) the subscription came from, null for manual subscriptions';
+
+drop procedure if exists subscribe(bigint, varchar);
+
+create or replace procedure subscribe(_user_id bigint, _crate varchar(64), _filter varchar(256))
     LANGUAGE plpgsql
 AS $$
 begin
-    if not exists (select * from crates where crates.name = _crate) then
+    if not exists (select * from crates where normalize_crate_name(crates.name) = normalize_crate_name(_crate)) then
         insert into crates (name) values (_crate) on conflict do nothing;
     end if;
 
-    insert into subscriptions (user_id, crate_id)
-        select _user_id, id from crates
-            where crates.name = _crate
-        on conflict do nothing;
+    insert into subscriptions (user_id, crate_id, filter)
+        select _user_id, id, _filter from crates
+            where normalize_crate_name(crates.name) = normalize_crate_name(_crate)
+        on conflict (crate_id, user_id) do update
+            set filter = excluded.filter,
+                manifest = null;
 end
 $$;
 
@@ -51,31 +79,995 @@ create or replace procedure unsubscribe(_user_id bigint, _crate varchar(64))
 AS $$
 begin
     delete from subscriptions
-        where crate_id = (select id from crates where name = _crate)
+        where crate_id = (select id from crates where normalize_crate_name(name) = normalize_crate_name(_crate))
             and user_id = _user_id;
 end
 $$;
 
+create or replace function set_events(_user_id bigint, _crate varchar(64), _events int)
+    RETURNS boolean
+    LANGUAGE plpgsql
+AS $$
+begin
+    update subscriptions
+        set events = _events
+        where crate_id = (select id from crates where normalize_crate_name(name) = normalize_crate_name(_crate))
+            and user_id = _user_id;
+
+    RETURN found;
+end
+$$;
+
+drop function if exists list_subscriptions(bigint);
+
 create or replace function list_subscriptions(_user_id bigint)
-RETURNS TABLE(crate_name varchar(64))
+RETURNS TABLE(crate_name varchar(64), filter varchar(256), events int, manifest varchar(256))
     LANGUAGE plpgsql
 AS $$
 begin
-    RETURN QUERY select c.name as crate_name
+    RETURN QUERY select c.name as crate_name, s.filter as filter, s.events as events, s.manifest as manifest
         from subscriptions as s
             inner join crates as c on c.id = s.crate_id
         where s.user_id = _user_id;
 end
 $$;
 
-create or replace function list_subscribers(_crate varchar(64))
+create or replace function sync_manifest(_user_id bigint, _manifest varchar(256), _crates varchar(64)[])
+    RETURNS TABLE(added bigint, removed bigint)
+    LANGUAGE plpgsql
+AS $$
+declare
+    _added bigint;
+    _removed bigint;
+begin
+    insert into crates (name)
+        select unnest(_crates)
+        on conflict do nothing;
+
+    -- don't touch existing (e.g. manual) subscriptions
+    insert into subscriptions (user_id, crate_id, manifest)
+        select _user_id, c.id, _manifest from crates as c
+            where normalize_crate_name(c.name) in (select normalize_crate_name(n) from unnest(_crates) as n)
+        on conflict do nothing;
+    GET DIAGNOSTICS _added = ROW_COUNT;
+
+    delete from subscriptions as s
+        where s.user_id = _user_id
+            and s.manifest = _manifest
+            and s.crate_id not in (
+                select c.id from crates as c
+                    where normalize_crate_name(c.name) in (select normalize_crate_name(n) from unnest(_crates) as n)
+            );
+    GET DIAGNOSTICS _removed = ROW_COUNT;
+
+    RETURN QUERY select _added, _removed;
+end
+$$;
+
+drop function if exists list_subscribers(varchar);
+drop function if exists list_subscribers(varchar, int);
+
+create or replace function list_subscribers(_crate varchar(64), _events int)
+    RETURNS TABLE(user_id bigint, filter varchar(256), events int, digest bool, language varchar(16),
+                  toolchain varchar(16))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select s.user_id as user_id, s.filter as filter, s.events as events,
+                        coalesce(cs.delivery <> 'immediate', false) as digest, cs.language as language,
+                        cs.toolchain as toolchain
+         from subscriptions as s
+              inner join crates as c on c.id = s.crate_id
+              left join chat_settings as cs on cs.user_id = s.user_id
+         where normalize_crate_name(c.name) = normalize_crate_name(_crate)
+             and s.events & _events <> 0;
+end
+$$;
+
+create table if not exists index_cursor
+(
+  id bool default true not null
+    constraint index_cursor_pk
+      primary key
+    constraint index_cursor_single_row
+      check (id),
+  commit_oid varchar(40) not null,
+  processed_at timestamp with time zone default now() not null
+);
+
+comment on table index_cursor is 'the last processed commit of crates.io-index (there is at most one row)';
+
+create or replace procedure set_index_cursor(_commit_oid varchar(40))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into index_cursor (commit_oid) values (_commit_oid)
+        on conflict (id) do update
+            set commit_oid = excluded.commit_oid,
+                processed_at = now();
+end
+$$;
+
+create or replace function get_index_cursor()
+    RETURNS TABLE(commit_oid varchar(40), processed_at timestamp with time zone)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select c.commit_oid, c.processed_at
+         from index_cursor as c;
+end
+$$;
+
+create table if not exists sparse_files
+(
+  crate_name varchar(64) not null
+    constraint sparse_files_pk
+      primary key,
+  etag varchar(256),
+  last_modified varchar(64),
+  versions varchar(64)[] not null,
+  yanked boolean[] not null
+);
+
+comment on table sparse_files is 'state of crate files of the sparse index as of the last poll, so polling resumes where it left off after a restart';
+
+comment on column sparse_files.crate_name is 'lowercase name of the crate';
+
+comment on column sparse_files.yanked is 'whether the corresponding version in `versions` is yanked';
+
+create or replace procedure set_sparse_file(_crate varchar(64), _etag varchar(256), _last_modified varchar(64), _versions varchar(64)[], _yanked boolean[])
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into sparse_files (crate_name, etag, last_modified, versions, yanked)
+        values (_crate, _etag, _last_modified, _versions, _yanked)
+        on conflict (crate_name) do update
+            set etag = excluded.etag,
+                last_modified = excluded.last_modified,
+                versions = excluded.versions,
+                yanked = excluded.yanked;
+end
+$$;
+
+create or replace procedure delete_sparse_file(_crate varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from sparse_files
+        where crate_name = _crate;
+end
+$$;
+
+create or replace function list_sparse_files()
+    RETURNS TABLE(crate_name varchar(64), etag varchar(256), last_modified varchar(64), versions varchar(64)[], yanked boolean[])
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select f.crate_name, f.etag, f.last_modified, f.versions, f.yanked
+         from sparse_files as f;
+end
+$$;
+
+create or replace function list_subscribed_crates()
+    RETURNS TABLE(crate_name varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select distinct c.name as crate_name
+         from crates as c
+              inner join subscriptions as s on s.crate_id = c.id;
+end
+$$;
+
+create table if not exists skipped_commits
+(
+  commit_oid varchar(40) not null
+    constraint skipped_commits_pk
+      primary key,
+  author varchar(256),
+  message text,
+  reason text not null,
+  skipped_at timestamp with time zone default now() not null
+);
+
+comment on table skipped_commits is 'commits of crates.io-index that couldn''t be parsed, for later review';
+
+create or replace procedure add_skipped_commit(_commit_oid varchar(40), _author varchar(256), _message text, _reason text)
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into skipped_commits (commit_oid, author, message, reason)
+        values (_commit_oid, _author, _message, _reason)
+        on conflict do nothing;
+end
+$$;
+
+create table if not exists pins
+(
+  user_id bigint not null,
+  crate_id int not null
+    constraint pins_crates_id_fk
+      references crates
+        on delete cascade,
+  vers varchar(64) not null,
+  project varchar(256) not null,
+  constraint pins_pk
+    primary key (crate_id, vers, user_id, project)
+);
+
+comment on table pins is 'exact versions of crates pinned in lockfiles of users'' projects';
+
+create index if not exists pins_user_id_project_index
+  on pins (user_id, project);
+
+create or replace procedure sync_pins(_user_id bigint, _project varchar(256), _crates varchar(64)[], _versions varchar(64)[])
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from pins
+        where user_id = _user_id
+            and project = _project;
+
+    insert into crates (name)
+        select unnest(_crates)
+        on conflict do nothing;
+
+    insert into pins (user_id, crate_id, vers, project)
+        select _user_id, c.id, p.vers, _project
+            from unnest(_crates, _versions) as p(name, vers)
+                inner join crates as c on normalize_crate_name(c.name) = normalize_crate_name(p.name)
+        on conflict do nothing;
+end
+$$;
+
+create or replace function list_pinned(_crate varchar(64), _vers varchar(64))
+    RETURNS TABLE(user_id bigint, project varchar(256))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select p.user_id as user_id, p.project as project
+         from pins as p
+              inner join crates as c on c.id = p.crate_id
+         where normalize_crate_name(c.name) = normalize_crate_name(_crate)
+             and p.vers = _vers;
+end
+$$;
+
+-- crates which names differ only in case and `-`/`_` are the same crate, merge rows that were added before names were
+-- normalized (spelling of names is fixed by the bot on startup using the index)
+do $$
+declare
+    _dup record;
+begin
+    for _dup in
+        select c.id as id, first_value(c.id) over (partition by normalize_crate_name(c.name) order by c.id) as keep
+            from crates as c
+    loop
+        continue when _dup.id = _dup.keep;
+
+        insert into subscriptions (user_id, crate_id, filter, events, manifest)
+            select s.user_id, _dup.keep, s.filter, s.events, s.manifest
+                from subscriptions as s
+                where s.crate_id = _dup.id
+            on conflict do nothing;
+
+        insert into pins (user_id, crate_id, vers, project)
+            select p.user_id, _dup.keep, p.vers, p.project
+                from pins as p
+                where p.crate_id = _dup.id
+            on conflict do nothing;
+
+        -- cascades to subscriptions and pins
+        delete from crates where id = _dup.id;
+    end loop;
+end
+$$;
+
+create unique index if not exists crates_name_normalized_uindex
+  on crates (normalize_crate_name(name));
+
+create or replace procedure rename_crate(_old varchar(64), _new varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    update crates
+        set name = _new
+        where name = _old;
+end
+$$;
+
+create table if not exists outbox
+(
+  id bigserial not null
+    constraint outbox_pk
+      primary key,
+  chat_id bigint not null,
+  payload text not null,
+  silent bool default false not null,
+  dedup_key varchar(512) not null,
+  attempts int default 0 not null,
+  next_attempt_at timestamp with time zone default now() not null,
+  created_at timestamp with time zone default now() not null,
+  delivered_at timestamp with time zone,
+  last_error text
+);
+
+comment on table outbox is 'notifications waiting to be delivered (delivered ones are kept for a while to deduplicate re-processed updates)';
+
+comment on column outbox.dedup_key is 'identifies the notification, the same notification is never enqueued twice';
+
+create unique index if not exists outbox_dedup_key_uindex
+  on outbox (dedup_key);
+
+create index if not exists outbox_pending_index
+  on outbox (next_attempt_at)
+    where delivered_at is null;
+
+alter table outbox
+  add column if not exists coalesce bool default false not null;
+
+comment on column outbox.coalesce is 'the message may be sent as a part of a combined message with other such messages to the same chat';
+
+drop procedure if exists enqueue_messages(bigint[], text[], bool[], varchar[]);
+
+create or replace procedure enqueue_messages(_chat_ids bigint[], _payloads text[], _silent bool[], _dedup_keys varchar(512)[], _coalesce bool[], _next_attempts_at timestamp with time zone[])
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into outbox (chat_id, payload, silent, dedup_key, coalesce, next_attempt_at)
+        select * from unnest(_chat_ids, _payloads, _silent, _dedup_keys, _coalesce, _next_attempts_at)
+        on conflict (dedup_key) do nothing;
+end
+$$;
+
+drop function if exists next_messages(bigint, int);
+
+create or replace function next_messages(_limit bigint, _max_attempts int)
+    RETURNS TABLE(id bigint, chat_id bigint, payload text, silent bool, attempts int, coalesce bool)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select o.id, o.chat_id, o.payload, o.silent, o.attempts, o.coalesce
+         from outbox as o
+         where o.delivered_at is null
+             and o.attempts < _max_attempts
+             and o.next_attempt_at <= now()
+         order by o.id
+         limit _limit;
+end
+$$;
+
+-- all undelivered messages to a chat that may be combined, even if they are not due yet
+create or replace function list_coalesced(_chat_id bigint, _limit bigint, _max_attempts int)
+    RETURNS TABLE(id bigint, chat_id bigint, payload text, silent bool, attempts int, coalesce bool)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select o.id, o.chat_id, o.payload, o.silent, o.attempts, o.coalesce
+         from outbox as o
+         where o.chat_id = _chat_id
+             and o.coalesce
+             and o.delivered_at is null
+             and o.attempts < _max_attempts
+         order by o.id
+         limit _limit;
+end
+$$;
+
+create or replace procedure message_delivered(_id bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    update outbox
+        set delivered_at = now()
+        where id = _id;
+end
+$$;
+
+create or replace procedure message_failed(_id bigint, _error text, _next_attempt_at timestamp with time zone)
+    LANGUAGE plpgsql
+AS $$
+begin
+    update outbox
+        set attempts = attempts + 1,
+            last_error = _error,
+            next_attempt_at = _next_attempt_at
+        where id = _id;
+end
+$$;
+
+create or replace procedure delete_delivered_messages(_before timestamp with time zone)
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from outbox
+        where delivered_at < _before;
+end
+$$;
+
+create or replace procedure postpone_message(_id bigint, _next_attempt_at timestamp with time zone)
+    LANGUAGE plpgsql
+AS $$
+begin
+    update outbox
+        set next_attempt_at = _next_attempt_at
+        where id = _id;
+end
+$$;
+
+create table if not exists chat_settings
+(
+  user_id bigint not null
+    constraint chat_settings_pk
+      primary key,
+  delivery varchar(16) default 'immediate' not null,
+  digest_minute int default 540 not null,
+  utc_offset_minutes int default 0 not null,
+  last_digest_at timestamp with time zone
+);
+
+comment on column chat_settings.delivery is 'how notifications are delivered: `immediate`, `hourly` or `daily` (digests)';
+
+comment on column chat_settings.digest_minute is 'local time (minutes after midnight) of daily digests';
+
+alter table chat_settings
+  add column if not exists language varchar(16);
+
+comment on column chat_settings.language is 'language of messages (e.g. `en`), null for the default';
+
+alter table chat_settings
+  add column if not exists toolchain varchar(16);
+
+comment on column chat_settings.toolchain is 'rust toolchain version of the chat (e.g. `1.56`), releases requiring a newer one are flagged';
+
+alter table chat_settings
+  add column if not exists typosquat_alerts boolean default false not null;
+
+comment on column chat_settings.typosquat_alerts is 'whether the chat is alerted about new crates with names similar to the crates it is subscribed to';
+
+create table if not exists digest_items
+(
+  id bigserial not null
+    constraint digest_items_pk
+      primary key,
+  user_id bigint not null,
+  crate_name varchar(64) not null,
+  vers varchar(64) not null,
+  action varchar(16) not null,
+  dedup_key varchar(512) not null,
+  created_at timestamp with time zone default now() not null
+);
+
+comment on table digest_items is 'updates waiting to be sent as a part of a digest';
+
+create unique index if not exists digest_items_dedup_key_uindex
+  on digest_items (dedup_key);
+
+create index if not exists digest_items_user_id_index
+  on digest_items (user_id);
+
+create or replace procedure set_delivery(_user_id bigint, _delivery varchar(16), _digest_minute int, _utc_offset_minutes int)
+    LANGUAGE plpgsql
+AS $$
+begin
+    -- the first digest is due only after a whole period
+    insert into chat_settings (user_id, delivery, digest_minute, utc_offset_minutes, last_digest_at)
+        values (_user_id, _delivery, _digest_minute, _utc_offset_minutes, now())
+        on conflict (user_id) do update
+            set delivery = excluded.delivery,
+                digest_minute = excluded.digest_minute,
+                utc_offset_minutes = excluded.utc_offset_minutes,
+                last_digest_at = excluded.last_digest_at;
+end
+$$;
+
+create or replace function get_delivery(_user_id bigint)
+    RETURNS TABLE(delivery varchar(16), digest_minute int, utc_offset_minutes int)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select s.delivery, s.digest_minute, s.utc_offset_minutes
+         from chat_settings as s
+         where s.user_id = _user_id;
+end
+$$;
+
+create or replace procedure add_digest_items(_user_ids bigint[], _dedup_keys varchar(512)[], _crate varchar(64), _vers varchar(64), _action varchar(16))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into digest_items (user_id, dedup_key, crate_name, vers, action)
+        select u.user_id, u.dedup_key, _crate, _vers, _action
+            from unnest(_user_ids, _dedup_keys) as u(user_id, dedup_key)
+        on conflict (dedup_key) do nothing;
+end
+$$;
+
+-- the last moment (not in the future) at which local time was `_minute` minutes after midnight
+create or replace function last_daily_slot(_minute int, _utc_offset_minutes int)
+    RETURNS timestamp with time zone
+    LANGUAGE sql
+    STABLE
+AS $$
+    select (slot - case when slot > now() at time zone 'utc' then interval '1 day' else interval '0' end) at time zone 'utc'
+        from (
+            select date_trunc('day', now() at time zone 'utc' + make_interval(mins => _utc_offset_minutes))
+                + make_interval(mins => _minute - _utc_offset_minutes) as slot
+        ) as t
+$$;
+
+create or replace function due_digests()
     RETURNS TABLE(user_id bigint)
     LANGUAGE plpgsql
 AS $$
 begin
-    RETURN QUERY select s.user_id as user_id
+    RETURN QUERY select distinct d.user_id
+         from digest_items as d
+              left join chat_settings as s on s.user_id = d.user_id
+         where s.user_id is null
+             -- flush items left after switching back to immediate delivery
+             or s.delivery = 'immediate'
+             or s.last_digest_at is null
+             or (s.delivery = 'hourly' and s.last_digest_at <= now() - interval '1 hour')
+             or (s.delivery = 'daily' and s.last_digest_at < last_daily_slot(s.digest_minute, s.utc_offset_minutes));
+end
+$$;
+
+create or replace function list_digest_items(_user_id bigint)
+    RETURNS TABLE(id bigint, crate_name varchar(64), vers varchar(64), action varchar(16))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select d.id, d.crate_name, d.vers, d.action
+         from digest_items as d
+         where d.user_id = _user_id
+         order by d.id;
+end
+$$;
+
+create or replace procedure finish_digest(_user_id bigint, _last_item_id bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from digest_items
+        where user_id = _user_id
+            and id <= _last_item_id;
+
+    update chat_settings
+        set last_digest_at = now()
+        where user_id = _user_id;
+end
+$$;
+
+create or replace procedure set_language(_user_id bigint, _language varchar(16))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into chat_settings (user_id, language)
+        values (_user_id, _language)
+        on conflict (user_id) do update
+            set language = excluded.language;
+end
+$$;
+
+-- sets the language only if it wasn't set before (e.g. from the language of the user's telegram client)
+create or replace procedure init_language(_user_id bigint, _language varchar(16))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into chat_settings (user_id, language)
+        values (_user_id, _language)
+        on conflict (user_id) do update
+            set language = coalesce(chat_settings.language, excluded.language);
+end
+$$;
+
+create or replace function get_language(_user_id bigint)
+    RETURNS TABLE(language varchar(16))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select s.language
+         from chat_settings as s
+         where s.user_id = _user_id;
+end
+$$;
+
+create or replace procedure set_toolchain(_user_id bigint, _toolchain varchar(16))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into chat_settings (user_id, toolchain)
+        values (_user_id, _toolchain)
+        on conflict (user_id) do update
+            set toolchain = excluded.toolchain;
+end
+$$;
+
+create or replace function get_toolchain(_user_id bigint)
+    RETURNS TABLE(toolchain varchar(16))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select s.toolchain
+         from chat_settings as s
+         where s.user_id = _user_id;
+end
+$$;
+
+create or replace procedure set_typosquat_alerts(_user_id bigint, _enabled boolean)
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into chat_settings (user_id, typosquat_alerts)
+        values (_user_id, _enabled)
+        on conflict (user_id) do update
+            set typosquat_alerts = excluded.typosquat_alerts;
+end
+$$;
+
+create or replace function get_typosquat_alerts(_user_id bigint)
+    RETURNS TABLE(typosquat_alerts boolean)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select s.typosquat_alerts
+         from chat_settings as s
+         where s.user_id = _user_id;
+end
+$$;
+
+-- subscriptions of chats which opted in for typosquatting alerts
+create or replace function list_typosquat_watches()
+    RETURNS TABLE(user_id bigint, crate_name varchar(64), language varchar(16))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select s.user_id as user_id, c.name as crate_name, cs.language as language
          from subscriptions as s
               inner join crates as c on c.id = s.crate_id
-         where c.name = _crate;
+              inner join chat_settings as cs on cs.user_id = s.user_id
+         where cs.typosquat_alerts
+         order by c.name, s.user_id;
+end
+$$;
+
+create table if not exists reverse_deps
+(
+  dependency varchar(64) not null,
+  dependent varchar(64) not null,
+  constraint reverse_deps_pk
+    primary key (dependency, dependent)
+);
+
+comment on table reverse_deps is 'crates which the most recently published version of `dependent` depends on (normalized names, dev-dependencies are not counted)';
+
+create index if not exists reverse_deps_dependent_index
+  on reverse_deps (dependent);
+
+-- replaces dependencies of `_crates` with pairs from `_dependents` and `_dependencies`
+create or replace procedure set_dependencies(_crates varchar(64)[], _dependents varchar(64)[], _dependencies varchar(64)[])
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from reverse_deps
+        where dependent in (select normalize_crate_name(n) from unnest(_crates) as n);
+
+    insert into reverse_deps (dependent, dependency)
+        select normalize_crate_name(d.dependent), normalize_crate_name(d.dependency)
+            from unnest(_dependents, _dependencies) as d(dependent, dependency)
+        on conflict do nothing;
+end
+$$;
+
+create table if not exists reverse_deps_built
+(
+  id bool default true not null
+    constraint reverse_deps_built_pk
+      primary key
+    constraint reverse_deps_built_single_row
+      check (id),
+  built_at timestamp with time zone default now() not null
+);
+
+comment on table reverse_deps_built is 'marker that building of `reverse_deps` from the index checkout was finished (there is at most one row)';
+
+-- like `set_dependencies`, but also marks the reverse-dependency index as built, so the last batch and the marker are written together
+create or replace procedure finish_reverse_deps(_crates varchar(64)[], _dependents varchar(64)[], _dependencies varchar(64)[])
+    LANGUAGE plpgsql
+AS $$
+begin
+    call set_dependencies(_crates, _dependents, _dependencies);
+
+    insert into reverse_deps_built default values
+        on conflict (id) do update
+            set built_at = now();
+end
+$$;
+
+create or replace function has_reverse_deps()
+    RETURNS boolean
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN exists(select * from reverse_deps_built);
+end
+$$;
+
+create or replace function count_dependents(_crate varchar(64))
+    RETURNS bigint
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN (select count(*) from reverse_deps where dependency = normalize_crate_name(_crate));
+end
+$$;
+
+create table if not exists dependents_subscriptions
+(
+  user_id bigint not null,
+  crate_id int not null
+    constraint dependents_subscriptions_crates_id_fk
+      references crates
+        on delete cascade,
+  constraint dependents_subscriptions_pk
+    primary key (crate_id, user_id)
+);
+
+comment on table dependents_subscriptions is 'subscriptions to new versions of crates which depend on a crate';
+
+create or replace procedure subscribe_dependents(_user_id bigint, _crate varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    if not exists (select * from crates where normalize_crate_name(crates.name) = normalize_crate_name(_crate)) then
+        insert into crates (name) values (_crate) on conflict do nothing;
+    end if;
+
+    insert into dependents_subscriptions (user_id, crate_id)
+        select _user_id, id from crates
+            where normalize_crate_name(crates.name) = normalize_crate_name(_crate)
+        on conflict do nothing;
+end
+$$;
+
+create or replace procedure unsubscribe_dependents(_user_id bigint, _crate varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from dependents_subscriptions
+        where crate_id = (select id from crates where normalize_crate_name(name) = normalize_crate_name(_crate))
+            and user_id = _user_id;
+end
+$$;
+
+create or replace function list_dependents_subscriptions(_user_id bigint)
+    RETURNS TABLE(crate_name varchar(64), dependents bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select c.name as crate_name,
+                        (select count(*) from reverse_deps as r where r.dependency = normalize_crate_name(c.name))
+                            as dependents
+        from dependents_subscriptions as s
+            inner join crates as c on c.id = s.crate_id
+        where s.user_id = _user_id;
+end
+$$;
+
+-- chats subscribed to dependents of any crate which `_crate` depends on, along with these crates (comma-separated)
+create or replace function list_dependents_subscribers(_crate varchar(64))
+    RETURNS TABLE(user_id bigint, dependencies text, digest bool, language varchar(16))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select s.user_id as user_id, string_agg(c.name, ', ' order by c.name) as dependencies,
+                        coalesce(bool_or(cs.delivery <> 'immediate'), false) as digest, max(cs.language) as language
+         from dependents_subscriptions as s
+              inner join crates as c on c.id = s.crate_id
+              inner join reverse_deps as r on r.dependency = normalize_crate_name(c.name)
+              left join chat_settings as cs on cs.user_id = s.user_id
+         where r.dependent = normalize_crate_name(_crate)
+         group by s.user_id;
+end
+$$;
+
+create table if not exists migrations
+(
+  dependency varchar(64) not null,
+  dependent varchar(64) not null,
+  new_range varchar(32) not null,
+  old_range varchar(32) not null,
+  vers varchar(64) not null,
+  migrated_at timestamp with time zone default now() not null,
+  constraint migrations_pk
+    primary key (dependency, new_range, dependent)
+);
+
+comment on table migrations is 'crates (`dependent`) which moved their requirement on `dependency` (normalized name) to a newer semver-compatibility range (e.g. `1` -> `2`) in version `vers`';
+
+-- migrations are recorded by `record_update`
+drop function if exists add_migrations(varchar, varchar, varchar[], varchar[], varchar[]);
+
+-- the number of crates which migrated to each of the ranges, counting `_dependent` as migrated
+create or replace function count_new_migrations(_dependent varchar(64), _dependencies varchar(64)[], _new_ranges varchar(32)[])
+    RETURNS TABLE(crates bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select (select count(*) from migrations as mm
+                             where mm.dependency = normalize_crate_name(m.dependency)
+                                 and mm.new_range = m.new_range
+                                 and mm.dependent <> normalize_crate_name(_dependent)) + 1
+        from unnest(_dependencies, _new_ranges) with ordinality as m(dependency, new_range, n)
+        order by m.n;
+end
+$$;
+
+-- everything the notifier writes about a single update (digest items, messages and migrations), in a single statement
+-- so it's either written completely or not at all
+create or replace procedure record_update(_crate varchar(64), _vers varchar(64), _action varchar(16),
+                                          _digest_user_ids bigint[], _digest_dedup_keys varchar(512)[],
+                                          _chat_ids bigint[], _payloads text[], _silent bool[], _dedup_keys varchar(512)[],
+                                          _coalesce bool[], _next_attempts_at timestamp with time zone[],
+                                          _dependencies varchar(64)[], _old_ranges varchar(32)[], _new_ranges varchar(32)[])
+    LANGUAGE plpgsql
+AS $$
+begin
+    call add_digest_items(_digest_user_ids, _digest_dedup_keys, _crate, _vers, _action);
+    call enqueue_messages(_chat_ids, _payloads, _silent, _dedup_keys, _coalesce, _next_attempts_at);
+
+    insert into migrations (dependency, dependent, new_range, old_range, vers)
+        select normalize_crate_name(m.dependency), normalize_crate_name(_crate), m.new_range, m.old_range, _vers
+            from unnest(_dependencies, _old_ranges, _new_ranges) as m(dependency, old_range, new_range)
+        on conflict do nothing;
+end
+$$;
+
+create or replace function count_migrations(_crate varchar(64))
+    RETURNS TABLE(new_range varchar(32), crates bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select m.new_range, count(*)
+        from migrations as m
+        where m.dependency = normalize_crate_name(_crate)
+        group by m.new_range
+        order by max(m.migrated_at) desc;
+end
+$$;
+
+create table if not exists migration_watches
+(
+  user_id bigint not null,
+  crate_id int not null
+    constraint migration_watches_crates_id_fk
+      references crates
+        on delete cascade,
+  constraint migration_watches_pk
+    primary key (crate_id, user_id)
+);
+
+comment on table migration_watches is 'chats which want to be notified when dependents of a crate migrate to its new semver-incompatible versions';
+
+create or replace procedure watch_migrations(_user_id bigint, _crate varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    if not exists (select * from crates where normalize_crate_name(crates.name) = normalize_crate_name(_crate)) then
+        insert into crates (name) values (_crate) on conflict do nothing;
+    end if;
+
+    insert into migration_watches (user_id, crate_id)
+        select _user_id, id from crates
+            where normalize_crate_name(crates.name) = normalize_crate_name(_crate)
+        on conflict do nothing;
+end
+$$;
+
+create or replace procedure unwatch_migrations(_user_id bigint, _crate varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from migration_watches
+        where crate_id = (select id from crates where normalize_crate_name(name) = normalize_crate_name(_crate))
+            and user_id = _user_id;
+end
+$$;
+
+create or replace function list_migration_watches(_user_id bigint)
+    RETURNS TABLE(crate_name varchar(64))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select c.name as crate_name
+        from migration_watches as w
+            inner join crates as c on c.id = w.crate_id
+        where w.user_id = _user_id;
+end
+$$;
+
+create or replace function list_migration_watchers(_crate varchar(64))
+    RETURNS TABLE(user_id bigint, language varchar(16))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select w.user_id as user_id, cs.language as language
+         from migration_watches as w
+              inner join crates as c on c.id = w.crate_id
+              left join chat_settings as cs on cs.user_id = w.user_id
+         where normalize_crate_name(c.name) = normalize_crate_name(_crate);
+end
+$$;
+
+create table if not exists new_crate_watches
+(
+  user_id bigint not null,
+  pattern varchar(256) not null,
+  constraint new_crate_watches_pk
+    primary key (user_id, pattern)
+);
+
+comment on table new_crate_watches is 'patterns (globs or regexes) of names of new crates a chat wants to be notified about';
+
+create or replace procedure watch_new(_user_id bigint, _pattern varchar(256))
+    LANGUAGE plpgsql
+AS $$
+begin
+    insert into new_crate_watches (user_id, pattern)
+        values (_user_id, _pattern)
+        on conflict do nothing;
+end
+$$;
+
+create or replace function unwatch_new(_user_id bigint, _pattern varchar(256))
+    RETURNS boolean
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from new_crate_watches
+        where user_id = _user_id
+            and pattern = _pattern;
+
+    RETURN found;
+end
+$$;
+
+create or replace function list_new_watches(_user_id bigint)
+    RETURNS TABLE(pattern varchar(256))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select w.pattern
+        from new_crate_watches as w
+        where w.user_id = _user_id
+        order by w.pattern;
+end
+$$;
+
+create or replace function list_new_crate_watchers()
+    RETURNS TABLE(user_id bigint, pattern varchar(256), language varchar(16))
+    LANGUAGE plpgsql
+AS $$
+begin
+    RETURN QUERY select w.user_id as user_id, w.pattern as pattern, cs.language as language
+         from new_crate_watches as w
+              left join chat_settings as cs on cs.user_id = w.user_id
+         order by w.pattern, w.user_id;
+end
+$$;
+
+-- removes everything the bot knows about a chat (e.g. when it blocked the bot)
+create or replace procedure forget_chat(_user_id bigint)
+    LANGUAGE plpgsql
+AS $$
+begin
+    delete from subscriptions where user_id = _user_id;
+    delete from pins where user_id = _user_id;
+    delete from dependents_subscriptions where user_id = _user_id;
+    delete from migration_watches where user_id = _user_id;
+    delete from new_crate_watches where user_id = _user_id;
+    delete from digest_items where user_id = _user_id;
+    delete from chat_settings where user_id = _user_id;
 end
 $$;
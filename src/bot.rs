@@ -1,16 +1,30 @@
-use std::{fmt::Debug, path::Path, sync::Arc};
+use std::{collections::BTreeMap, fmt::Debug, path::Path, sync::Arc};
 
 use futures::{future, Future, FutureExt};
 use log::{error, warn};
 use teloxide::{
+    net::Download,
     prelude::{Requester, *},
-    types::{Me, Message},
+    types::{File, Me, Message, User},
     utils::command::{BotCommand, ParseError},
-    RequestError,
+    DownloadError, RequestError,
 };
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
-use crate::{cfg::Config, db::Database, krate::Crate, util::crate_path, Bot, VERSION};
+use crate::{
+    cfg::{Config, SourceConfig},
+    db::{Database, Subscription},
+    digest::Delivery,
+    filter::{EventMask, VersionFilter},
+    krate::Crate,
+    manifest,
+    msrv::RustVersion,
+    pattern::Pattern,
+    source::sparse,
+    template::{Args, Key, Templates, DEFAULT_LANGUAGE},
+    util::{crate_path, find_crate_file, normalize_crate_name},
+    Bot, VERSION,
+};
 
 type OptString = Option<String>;
 
@@ -18,11 +32,35 @@ type OptString = Option<String>;
 #[command(rename = "lowercase", parse_with = "split")]
 enum Command {
     Start,
-    #[command(parse_with = "opt")]
-    Subscribe(OptString),
+    #[command(parse_with = "crate_and_rest")]
+    Subscribe(OptString, OptString),
     #[command(parse_with = "opt")]
     Unsubscribe(OptString),
+    #[command(rename = "subscribe_dependents", parse_with = "opt")]
+    SubscribeDependents(OptString),
+    #[command(rename = "unsubscribe_dependents", parse_with = "opt")]
+    UnsubscribeDependents(OptString),
+    #[command(rename = "watch_migrations", parse_with = "opt")]
+    WatchMigrations(OptString),
+    #[command(rename = "unwatch_migrations", parse_with = "opt")]
+    UnwatchMigrations(OptString),
+    #[command(parse_with = "opt")]
+    Migrations(OptString),
+    #[command(rename = "watch_new", parse_with = "rest")]
+    WatchNew(OptString),
+    #[command(rename = "unwatch_new", parse_with = "rest")]
+    UnwatchNew(OptString),
+    #[command(parse_with = "crate_and_rest")]
+    Events(OptString, OptString),
     List,
+    #[command(parse_with = "rest")]
+    Digest(OptString),
+    #[command(parse_with = "opt")]
+    Language(OptString),
+    #[command(parse_with = "opt")]
+    Toolchain(OptString),
+    #[command(parse_with = "opt")]
+    Typosquat(OptString),
 }
 
 fn opt(input: String) -> Result<(Option<String>,), ParseError> {
@@ -37,14 +75,56 @@ fn opt(input: String) -> Result<(Option<String>,), ParseError> {
     }
 }
 
+/// Parse the whole (possibly empty) input as a single argument.
+fn rest(input: String) -> Result<(Option<String>,), ParseError> {
+    let input = input.trim();
+    if input.is_empty() {
+        Ok((None,))
+    } else {
+        Ok((Some(input.to_owned()),))
+    }
+}
+
+/// Parse crate name followed by an optional argument which may contain
+/// whitespace (e.g. `serde >=1.0, <2`).
+fn crate_and_rest(input: String) -> Result<(Option<String>, Option<String>), ParseError> {
+    let input = input.trim();
+    match input.split_once(char::is_whitespace) {
+        None if input.is_empty() => Ok((None, None)),
+        None => Ok((Some(input.to_owned()), None)),
+        Some((krate, rest)) => Ok((Some(krate.to_owned()), Some(rest.trim().to_owned()))),
+    }
+}
+
 #[derive(Debug, derive_more::Display, derive_more::From, derive_more::Error)]
 enum HErr {
     Tg(RequestError),
     Bd(tokio_postgres::Error),
+    Sparse(sparse::Error),
+    Download(DownloadError),
     GetUser,
     NotAdmin,
 }
 
+async fn messages_handler(
+    update_with_cx: UpdateWithCx<Bot, Message>,
+    (db, cfg, bot_name): (Database, Arc<Config>, String),
+) -> Result<(), HErr> {
+    if update_with_cx.update.document().is_some() {
+        return document_handler(update_with_cx, (db, cfg)).await;
+    }
+
+    let cmd = update_with_cx
+        .update
+        .text()
+        .and_then(|text| Command::parse(text, bot_name).ok());
+
+    match cmd {
+        Some(cmd) => commands_handler((update_with_cx, cmd), (db, cfg)).await,
+        None => Ok(()),
+    }
+}
+
 async fn commands_handler(
     (update_with_cx, cmd): (UpdateWithCx<Bot, Message>, Command),
     (db, cfg): (Database, Arc<Config>),
@@ -57,103 +137,469 @@ async fn commands_handler(
 
     check_privileges(&bot, &msg).await?;
 
+    let language = chat_language(chat_id, msg.from(), &db, &cfg).await?;
+    let templates = cfg.locales.get(language.as_deref());
+
     match cmd {
         Command::Start => {
-            let greeting = format!(
-                    "Hi! I will notify you about updates of crates. \
-                     Use /subscribe to subscribe for updates of crates you want to be notified about.\n\
-                     \n\
-                     In case you want to see <b>all</b> updates go to @crates_updates\n\
-                     \n\
-                     Author: @wafflelapkin\n\
-                     His channel [ru]: @ihatereality\n\
-                     My source: <a href='https://github.com/WaffleLapkin/crate_upd_bot'>[github]</a>\n\
-                     Version: <code>{version}</code>", 
-                    version = VERSION
-                );
+            let greeting = templates.render(Key::Start, &Args::new().text("version", VERSION));
             bot.send_message(chat_id, greeting).await?;
         }
-        Command::Subscribe(Some(krate)) => match subscribe(chat_id, &krate, &db, &cfg).await? {
-            Some(ver) => {
-                bot.send_message(
-                    chat_id,
-                    format!(
-                        "You've successfully subscribed for updates on <code>{}</code>{} crate. \
-                         Use /unsubscribe to unsubscribe.",
-                        krate, ver
-                    ),
-                )
-                .disable_web_page_preview(true)
+        Command::Subscribe(Some(krate), filter) => {
+            let filter = match filter
+                .as_deref()
+                .map(str::parse::<VersionFilter>)
+                .transpose()
+            {
+                Ok(filter) => filter.unwrap_or_default(),
+                Err(err) => {
+                    let args = Args::new().text("error", err);
+                    bot.send_message(chat_id, templates.render(Key::InvalidFilter, &args))
+                        .await?;
+                    return Ok(());
+                }
+            };
+
+            subscribe_and_reply(&bot, chat_id, &krate, &filter, &db, &cfg, templates).await?;
+        }
+        Command::Subscribe(None, _) => {
+            bot.send_message(chat_id, templates.render(Key::SubscribeUsage, &Args::new()))
+                .await?;
+        }
+        Command::Unsubscribe(Some(krate)) => {
+            db.unsubscribe(chat_id, &krate).await?;
+            let args = Args::new().text("crate", krate);
+            bot.send_message(chat_id, templates.render(Key::Unsubscribed, &args))
                 .await?;
+        }
+        Command::Unsubscribe(None) => {
+            bot.send_message(
+                chat_id,
+                templates.render(Key::UnsubscribeUsage, &Args::new()),
+            )
+            .await?;
+        }
+        Command::SubscribeDependents(Some(_)) if !cfg.source.tracks_dependencies() => {
+            bot.send_message(
+                chat_id,
+                templates.render(Key::DependentsUnsupported, &Args::new()),
+            )
+            .await?;
+        }
+        Command::SubscribeDependents(Some(_)) if !db.has_reverse_deps().await? => {
+            bot.send_message(
+                chat_id,
+                templates.render(Key::DependentsNotReady, &Args::new()),
+            )
+            .await?;
+        }
+        Command::SubscribeDependents(Some(krate)) => match find_crate(&krate, &cfg).await? {
+            Some(krate) => {
+                db.subscribe_dependents(chat_id, &krate.id.name).await?;
+                let dependents = db.count_dependents(&krate.id.name).await?;
+
+                let args = Args::new()
+                    .text("crate", &krate.id.name)
+                    .text("dependents", dependents);
+                bot.send_message(chat_id, templates.render(Key::SubscribedDependents, &args))
+                    .await?;
             }
             None => {
-                bot.send_message(
-                    chat_id,
-                    format!("Error: there is no such crate <code>{}</code>.", krate),
-                )
-                .await?;
+                let args = Args::new().text("crate", krate);
+                bot.send_message(chat_id, templates.render(Key::NoSuchCrate, &args))
+                    .await?;
             }
         },
-        Command::Subscribe(None) => {
+        Command::SubscribeDependents(None) => {
             bot.send_message(
                 chat_id,
-                "You need to specify the crate you want to subscribe. Like this: <pre>/subscribe \
-                 serde</pre>",
+                templates.render(Key::SubscribeDependentsUsage, &Args::new()),
             )
             .await?;
         }
-        Command::Unsubscribe(Some(krate)) => {
-            db.unsubscribe(chat_id, &krate).await?;
+        Command::UnsubscribeDependents(Some(krate)) => {
+            db.unsubscribe_dependents(chat_id, &krate).await?;
+            let args = Args::new().text("crate", krate);
             bot.send_message(
                 chat_id,
-                format!(
-                    "You've successfully unsubscribed for updates on <code>{}</code> crate. Use \
-                     /subscribe to subscribe back.",
-                    krate
-                ),
+                templates.render(Key::UnsubscribedDependents, &args),
             )
             .await?;
         }
-        Command::Unsubscribe(None) => {
+        Command::UnsubscribeDependents(None) => {
             bot.send_message(
                 chat_id,
-                "You need to specify the crate you want to unsubscribe. Like this: \
-                 <code>/unsubscribe serde</code>",
+                templates.render(Key::UnsubscribeDependentsUsage, &Args::new()),
             )
             .await?;
         }
+        Command::WatchMigrations(Some(krate)) => match find_crate(&krate, &cfg).await? {
+            Some(krate) => {
+                db.watch_migrations(chat_id, &krate.id.name).await?;
+                let args = Args::new().text("crate", &krate.id.name);
+                bot.send_message(chat_id, templates.render(Key::WatchingMigrations, &args))
+                    .await?;
+            }
+            None => {
+                let args = Args::new().text("crate", krate);
+                bot.send_message(chat_id, templates.render(Key::NoSuchCrate, &args))
+                    .await?;
+            }
+        },
+        Command::WatchMigrations(None) => {
+            bot.send_message(
+                chat_id,
+                templates.render(Key::WatchMigrationsUsage, &Args::new()),
+            )
+            .await?;
+        }
+        Command::UnwatchMigrations(Some(krate)) => {
+            db.unwatch_migrations(chat_id, &krate).await?;
+            let args = Args::new().text("crate", krate);
+            bot.send_message(chat_id, templates.render(Key::UnwatchedMigrations, &args))
+                .await?;
+        }
+        Command::UnwatchMigrations(None) => {
+            bot.send_message(
+                chat_id,
+                templates.render(Key::UnwatchMigrationsUsage, &Args::new()),
+            )
+            .await?;
+        }
+        Command::Migrations(Some(krate)) => {
+            let list: Vec<_> = db
+                .count_migrations(&krate)
+                .await?
+                .map(|(range, count)| {
+                    let args = Args::new().text("range", range).text("count", count);
+                    templates.render(Key::MigrationsItem, &args)
+                })
+                .collect();
+
+            let reply = if list.is_empty() {
+                templates.render(Key::NoMigrations, &Args::new().text("crate", krate))
+            } else {
+                let args = Args::new()
+                    .text("crate", krate)
+                    .markup("list", list.join("\n"));
+                templates.render(Key::Migrations, &args)
+            };
+            bot.send_message(chat_id, reply).await?;
+        }
+        Command::Migrations(None) => {
+            bot.send_message(
+                chat_id,
+                templates.render(Key::MigrationsUsage, &Args::new()),
+            )
+            .await?;
+        }
+        Command::WatchNew(Some(pattern)) => match pattern.parse::<Pattern>() {
+            Ok(pattern) => {
+                db.watch_new(chat_id, &pattern.to_string()).await?;
+                let args = Args::new().text("pattern", pattern);
+                bot.send_message(chat_id, templates.render(Key::WatchingNew, &args))
+                    .await?;
+            }
+            Err(err) => {
+                let args = Args::new().text("error", err);
+                bot.send_message(chat_id, templates.render(Key::InvalidPattern, &args))
+                    .await?;
+            }
+        },
+        Command::WatchNew(None) => {
+            bot.send_message(chat_id, templates.render(Key::WatchNewUsage, &Args::new()))
+                .await?;
+        }
+        Command::UnwatchNew(Some(pattern)) => {
+            let key = if db.unwatch_new(chat_id, &pattern).await? {
+                Key::UnwatchedNew
+            } else {
+                Key::NotWatchingNew
+            };
+
+            let args = Args::new().text("pattern", pattern);
+            bot.send_message(chat_id, templates.render(key, &args))
+                .await?;
+        }
+        Command::UnwatchNew(None) => {
+            bot.send_message(
+                chat_id,
+                templates.render(Key::UnwatchNewUsage, &Args::new()),
+            )
+            .await?;
+        }
+        Command::Events(Some(krate), Some(events)) => {
+            let events = match events.parse::<EventMask>() {
+                Ok(events) => events,
+                Err(err) => {
+                    let args = Args::new().text("error", err);
+                    bot.send_message(chat_id, templates.render(Key::InvalidEvents, &args))
+                        .await?;
+                    return Ok(());
+                }
+            };
+
+            let key = if db.set_events(chat_id, &krate, events.bits()).await? {
+                Key::EventsSet
+            } else {
+                Key::NotSubscribed
+            };
+
+            let args = Args::new().text("crate", krate).text("events", events);
+            bot.send_message(chat_id, templates.render(key, &args))
+                .await?;
+        }
+        Command::Events(_, _) => {
+            bot.send_message(chat_id, templates.render(Key::EventsUsage, &Args::new()))
+                .await?;
+        }
+        Command::Digest(Some(delivery)) => match delivery.parse::<Delivery>() {
+            Ok(delivery) => {
+                let (mode, minute, offset) = delivery.to_db();
+                db.set_delivery(chat_id, mode, minute, offset).await?;
+
+                let description = match delivery {
+                    Delivery::Immediate => Key::DeliveryImmediate,
+                    Delivery::Hourly => Key::DeliveryHourly,
+                    Delivery::Daily { .. } => Key::DeliveryDaily,
+                };
+
+                let args = Args::new()
+                    .text("delivery", delivery)
+                    .markup("description", templates.render(description, &Args::new()));
+                bot.send_message(chat_id, templates.render(Key::DeliverySet, &args))
+                    .await?;
+            }
+            Err(err) => {
+                let args = Args::new()
+                    .text("error", err)
+                    .markup("usage", templates.render(Key::DigestUsage, &Args::new()));
+                bot.send_message(chat_id, templates.render(Key::InvalidDelivery, &args))
+                    .await?;
+            }
+        },
+        Command::Digest(None) => {
+            let delivery = db
+                .get_delivery(chat_id)
+                .await?
+                .map(|(mode, minute, offset)| Delivery::from_db(&mode, minute, offset))
+                .unwrap_or_default();
+
+            let args = Args::new()
+                .text("delivery", delivery)
+                .markup("usage", templates.render(Key::DigestUsage, &Args::new()));
+            bot.send_message(chat_id, templates.render(Key::CurrentDelivery, &args))
+                .await?;
+        }
         Command::List => {
-            let subscriptions = list_subscriptions(chat_id, &db, &cfg).await?;
+            let subscriptions = list_subscriptions(chat_id, &db, &cfg, templates).await?;
 
             if subscriptions.is_empty() {
-                bot.send_message(
-                    chat_id,
-                    String::from(
-                        "Currently you aren't subscribed to anything. Use /subscribe to subscribe \
-                         to some crate.",
-                    ),
-                )
-                .await?;
+                bot.send_message(chat_id, templates.render(Key::ListEmpty, &Args::new()))
+                    .await?;
             } else {
-                bot.send_message(
-                    chat_id,
-                    format!(
-                        "You are currently subscribed to:\n— <code>{}",
-                        subscriptions.join("\n— <code>")
-                    ),
-                )
-                .disable_web_page_preview(true)
+                let args = Args::new().markup("subscriptions", subscriptions.join("\n"));
+                bot.send_message(chat_id, templates.render(Key::List, &args))
+                    .disable_web_page_preview(true)
+                    .await?;
+            }
+        }
+        Command::Language(Some(language)) => match cfg.locales.resolve(&language) {
+            Some(language) => {
+                db.set_language(chat_id, language).await?;
+
+                // Reply in the new language
+                let args = Args::new().text("language", language);
+                let templates = cfg.locales.get(Some(language));
+                bot.send_message(chat_id, templates.render(Key::LanguageSet, &args))
+                    .await?;
+            }
+            None => {
+                let languages: Vec<_> = cfg.locales.languages().collect();
+                let args = Args::new()
+                    .text("language", language)
+                    .text("languages", languages.join(", "));
+                bot.send_message(chat_id, templates.render(Key::UnknownLanguage, &args))
+                    .await?;
+            }
+        },
+        Command::Language(None) => {
+            let languages: Vec<_> = cfg.locales.languages().collect();
+            let args = Args::new()
+                .text("language", language.as_deref().unwrap_or(DEFAULT_LANGUAGE))
+                .text("languages", languages.join(", "));
+            bot.send_message(chat_id, templates.render(Key::CurrentLanguage, &args))
+                .await?;
+        }
+        Command::Toolchain(Some(toolchain)) if toolchain == "off" => {
+            db.set_toolchain(chat_id, None).await?;
+            bot.send_message(chat_id, templates.render(Key::ToolchainUnset, &Args::new()))
                 .await?;
+        }
+        Command::Toolchain(Some(toolchain)) => match toolchain.parse::<RustVersion>() {
+            Ok(toolchain) => {
+                db.set_toolchain(chat_id, Some(&toolchain.to_string()))
+                    .await?;
+                let args = Args::new().text("toolchain", toolchain);
+                bot.send_message(chat_id, templates.render(Key::ToolchainSet, &args))
+                    .await?;
+            }
+            Err(err) => {
+                let args = Args::new().text("error", err);
+                bot.send_message(chat_id, templates.render(Key::InvalidToolchain, &args))
+                    .await?;
             }
+        },
+        Command::Toolchain(None) => {
+            let reply = match db.get_toolchain(chat_id).await? {
+                Some(toolchain) => {
+                    let args = Args::new().text("toolchain", toolchain);
+                    templates.render(Key::CurrentToolchain, &args)
+                }
+                None => templates.render(Key::NoToolchain, &Args::new()),
+            };
+            bot.send_message(chat_id, reply).await?;
+        }
+        Command::Typosquat(Some(enabled)) => {
+            let reply = match enabled.as_str() {
+                "on" => {
+                    db.set_typosquat_alerts(chat_id, true).await?;
+                    templates.render(Key::TyposquatEnabled, &Args::new())
+                }
+                "off" => {
+                    db.set_typosquat_alerts(chat_id, false).await?;
+                    templates.render(Key::TyposquatDisabled, &Args::new())
+                }
+                _ => templates.render(Key::TyposquatUsage, &Args::new()),
+            };
+            bot.send_message(chat_id, reply).await?;
+        }
+        Command::Typosquat(None) => {
+            let key = if db.get_typosquat_alerts(chat_id).await? {
+                Key::TyposquatEnabled
+            } else {
+                Key::TyposquatDisabled
+            };
+            bot.send_message(chat_id, templates.render(key, &Args::new()))
+                .await?;
         }
     }
 
     Ok::<_, HErr>(())
 }
 
+/// Handle uploaded `Cargo.lock`/`Cargo.toml`: subscribe to all crates.io
+/// dependencies listed in it.
+///
+/// Subscriptions are marked with the name of the manifest (caption of the
+/// message or the file name), so re-uploading the same manifest synchronizes
+/// the set of subscriptions.
+async fn document_handler(
+    update_with_cx: UpdateWithCx<Bot, Message>,
+    (db, cfg): (Database, Arc<Config>),
+) -> Result<(), HErr> {
+    let UpdateWithCx {
+        update: msg,
+        requester: bot,
+    } = update_with_cx;
+    let chat_id = msg.chat.id;
+
+    let document = match msg.document() {
+        Some(document) => document,
+        None => return Ok(()),
+    };
+
+    let file_name = document.file_name.as_deref().unwrap_or_default();
+    if file_name != "Cargo.lock" && file_name != "Cargo.toml" {
+        return Ok(());
+    }
+
+    check_privileges(&bot, &msg).await?;
+
+    let language = chat_language(chat_id, msg.from(), &db, &cfg).await?;
+    let templates = cfg.locales.get(language.as_deref());
+
+    let manifest = match msg.caption().map(str::trim) {
+        Some(project) if !project.is_empty() => format!("{}/{}", project, file_name),
+        _ => file_name.to_owned(),
+    };
+
+    let File { file_path, .. } = bot.get_file(&document.file_id).await?;
+    let mut content = Vec::new();
+    bot.download_file(&file_path, &mut content).await?;
+
+    let deps = match String::from_utf8(content)
+        .map_err(|err| err.to_string())
+        .and_then(|content| manifest::parse(&content).map_err(|err| err.to_string()))
+    {
+        Ok(deps) => deps,
+        Err(err) => {
+            let args = Args::new().text("file", file_name).text("error", err);
+            bot.send_message(chat_id, templates.render(Key::ManifestError, &args))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    // normalized name => canonical name
+    let mut found = BTreeMap::new();
+    let mut missing = Vec::new();
+    for name in &deps.crates {
+        match find_crate(name, &cfg).await? {
+            Some(krate) => {
+                found.insert(normalize_crate_name(name), krate.id.name);
+            }
+            None => missing.push(name.as_str()),
+        }
+    }
+
+    let crates: Vec<_> = found.values().map(String::as_str).collect();
+    let (added, removed) = db.sync_manifest(chat_id, &manifest, &crates).await?;
+
+    // Only lockfiles have exact versions
+    let (pinned_crates, pinned_versions): (Vec<_>, Vec<_>) = deps
+        .pins
+        .iter()
+        .filter_map(|(name, vers)| {
+            let name = found.get(&normalize_crate_name(name))?;
+            Some((name.as_str(), vers.as_str()))
+        })
+        .unzip();
+    if file_name == "Cargo.lock" {
+        db.sync_pins(chat_id, &manifest, &pinned_crates, &pinned_versions)
+            .await?;
+    }
+
+    let pinned = if pinned_crates.is_empty() {
+        String::new()
+    } else {
+        let args = Args::new().text("count", pinned_crates.len());
+        templates.render(Key::ManifestPinned, &args)
+    };
+
+    let missing = if missing.is_empty() {
+        String::new()
+    } else {
+        let args = Args::new().text("crates", missing.join(", "));
+        templates.render(Key::ManifestMissing, &args)
+    };
+
+    let args = Args::new()
+        .text("manifest", manifest)
+        .text("found", found.len())
+        .text("added", added)
+        .text("removed", removed)
+        .markup("pinned", pinned)
+        .markup("missing", missing);
+    bot.send_message(chat_id, templates.render(Key::ManifestSynced, &args))
+        .await?;
+
+    Ok(())
+}
+
 async fn unblock_handler(
     update_with_cx: UpdateWithCx<Bot, ChatMemberUpdated>,
-    (db, _cfg): (Database, Arc<Config>),
+    (db, cfg): (Database, Arc<Config>),
 ) -> Result<(), HErr> {
     let UpdateWithCx {
         update,
@@ -170,15 +616,12 @@ async fn unblock_handler(
     if old_chat_member.is_present() && !new_chat_member.is_present() {
         // FIXME: ideally the bot should just mark the user as temporary unavailable
         // (that is: untill unblock/restart), but I'm too lazy to implement it rn.
-        for sub in db.list_subscriptions(from.id).await? {
-            db.unsubscribe(from.id, &sub).await?;
-        }
+        db.forget_chat(from.id).await?;
     } else if !old_chat_member.is_present() && new_chat_member.is_present() {
-        bot.send_message(
-            from.id,
-            "You have previously blocked this bot. This removed all your subsctiptions.",
-        )
-        .await?;
+        let language = chat_language(from.id, Some(from), &db, &cfg).await?;
+        let templates = cfg.locales.get(language.as_deref());
+        bot.send_message(from.id, templates.render(Key::Unblocked, &Args::new()))
+            .await?;
     } else {
         warn!("Got weird MyChatMember update: {:?}", update);
     }
@@ -190,14 +633,13 @@ pub async fn run(bot: Bot, db: Database, cfg: Arc<Config>) {
     let Me { user, .. } = bot.get_me().await.expect("Couldn't get myself :(");
     let name = user.username.expect("Bots *must* have usernames");
 
-    let ctx_cloned = (db.clone(), cfg.clone());
+    let ctx_cloned = (db.clone(), cfg.clone(), name);
     let ctx = (db, cfg);
 
     let mut dp = Dispatcher::new(bot)
         .messages_handler(move |rx| async move {
             UnboundedReceiverStream::new(rx)
-                .commands(name)
-                .for_each_concurrent(None, err(with(ctx_cloned, commands_handler)))
+                .for_each_concurrent(None, err(with(ctx_cloned, messages_handler)))
                 .await
         })
         .my_chat_members_handler(move |rx| async move {
@@ -214,25 +656,99 @@ async fn list_subscriptions(
     chat_id: i64,
     db: &Database,
     cfg: &Config,
+    templates: &Templates,
 ) -> Result<Vec<String>, HErr> {
-    let mut subscriptions: Vec<_> = db.list_subscriptions(chat_id).await?.collect();
-    for sub in &mut subscriptions {
-        let path = Path::new(cfg.index_path.as_str()).join(crate_path(sub));
-        match Crate::read_last(&path).await {
-            Ok(krate) => {
-                sub.push('#');
-                sub.push_str(&krate.id.vers);
-                sub.push_str("</code> ");
-                sub.push_str(&krate.html_links());
-            }
-            Err(_) => {
-                sub.push_str(" </code>");
-                /* silently ignore error & just don't add links */
-            }
+    let subscriptions = db.list_subscriptions(chat_id).await?;
+    let mut res = Vec::new();
+    for Subscription {
+        crate_name,
+        filter,
+        events,
+        manifest,
+    } in subscriptions
+    {
+        let mut details = String::new();
+
+        if filter != VersionFilter::All.to_string() {
+            let args = Args::new().text("filter", filter);
+            details.push_str(&templates.render(Key::ListFilter, &args));
+        }
+
+        let events = EventMask::from_bits(events);
+        if events != EventMask::ALL {
+            let args = Args::new().text("events", events);
+            details.push_str(&templates.render(Key::ListEvents, &args));
         }
+
+        if let Some(manifest) = manifest {
+            let args = Args::new().text("manifest", manifest);
+            details.push_str(&templates.render(Key::ListManifest, &args));
+        }
+
+        let path = Path::new(cfg.index_path.as_str()).join(crate_path(&crate_name));
+        let item = match Crate::read_last(&path).await {
+            Ok(krate) => templates.render(
+                Key::ListItem,
+                &Args::new()
+                    .text("crate", crate_name)
+                    .text("version", &krate.id.vers)
+                    .markup("links", templates.format().crate_links(&krate))
+                    .markup("details", details),
+            ),
+            // silently ignore error & just don't add links
+            Err(_) => templates.render(
+                Key::ListItemMissing,
+                &Args::new()
+                    .text("crate", crate_name)
+                    .markup("details", details),
+            ),
+        };
+
+        res.push(item);
     }
 
-    Ok(subscriptions)
+    for (krate, dependents) in db.list_dependents_subscriptions(chat_id).await? {
+        let args = Args::new()
+            .text("crate", krate)
+            .text("dependents", dependents);
+        res.push(templates.render(Key::ListDependents, &args));
+    }
+
+    for krate in db.list_migration_watches(chat_id).await? {
+        let args = Args::new().text("crate", krate);
+        res.push(templates.render(Key::ListMigrations, &args));
+    }
+
+    for pattern in db.list_new_watches(chat_id).await? {
+        let args = Args::new().text("pattern", pattern);
+        res.push(templates.render(Key::ListNewCrates, &args));
+    }
+
+    Ok(res)
+}
+
+/// Returns language of a chat: the chosen one or, if there is none, the
+/// language of the user's telegram client (which is then remembered for
+/// notifications).
+async fn chat_language(
+    chat_id: i64,
+    user: Option<&User>,
+    db: &Database,
+    cfg: &Config,
+) -> Result<Option<String>, HErr> {
+    if let Some(language) = db.get_language(chat_id).await? {
+        return Ok(Some(language));
+    }
+
+    let language = user
+        .and_then(|user| user.language_code.as_deref())
+        .and_then(|code| cfg.locales.resolve(code));
+
+    if let Some(language) = language {
+        db.init_language(chat_id, language).await?;
+    }
+
+    Ok(language.map(str::to_owned))
 }
 
 async fn check_privileges(bot: &Bot, msg: &Message) -> Result<(), HErr> {
@@ -248,28 +764,101 @@ async fn check_privileges(bot: &Bot, msg: &Message) -> Result<(), HErr> {
     Ok(())
 }
 
+async fn subscribe_and_reply(
+    bot: &Bot,
+    chat_id: i64,
+    krate: &str,
+    filter: &VersionFilter,
+    db: &Database,
+    cfg: &Config,
+    templates: &Templates,
+) -> Result<(), HErr> {
+    match subscribe(chat_id, krate, filter, db, cfg).await? {
+        Some(krate) => {
+            let filter = match filter {
+                VersionFilter::All => String::new(),
+                filter => {
+                    let args = Args::new().text("filter", filter);
+                    templates.render(Key::SubscribedFilter, &args)
+                }
+            };
+
+            let args = Args::new()
+                .text("crate", &krate.id.name)
+                .text("version", &krate.id.vers)
+                .markup("links", templates.format().crate_links(&krate))
+                .markup("filter", filter);
+            bot.send_message(chat_id, templates.render(Key::Subscribed, &args))
+                .disable_web_page_preview(true)
+                .await?;
+        }
+        None => {
+            let args = Args::new().text("crate", krate);
+            bot.send_message(chat_id, templates.render(Key::NoSuchCrate, &args))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Subscribe to a crate using its canonical name.
+///
+/// Returns the last version of the crate or `None` if there is no such crate.
 async fn subscribe(
     chat_id: i64,
     krate: &str,
+    filter: &VersionFilter,
     db: &Database,
     cfg: &Config,
-) -> Result<Option<String>, HErr> {
-    let path = Path::new(cfg.index_path.as_str()).join(crate_path(krate));
-    if path.exists() {
-        db.subscribe(chat_id, krate).await?;
-
-        let ver = match Crate::read_last(&path).await {
-            Ok(krate) => format!(
-                " (current version <code>{}</code> {})",
-                krate.id.vers,
-                krate.html_links()
-            ),
-            Err(_) => String::new(),
-        };
+) -> Result<Option<Crate>, HErr> {
+    let krate = match find_crate(krate, cfg).await? {
+        Some(krate) => krate,
+        None => return Ok(None),
+    };
 
-        Ok(Some(ver))
-    } else {
-        Ok(None)
+    db.subscribe(chat_id, &krate.id.name, &filter.to_string())
+        .await?;
+
+    Ok(Some(krate))
+}
+
+/// Find the last version of a crate in the local index (downloading it first,
+/// if the sparse index is used).
+///
+/// Like crates.io, `krate` is matched ignoring case and `-`/`_` differences,
+/// so the returned crate has the canonical (published) spelling of the name.
+async fn find_crate(krate: &str, cfg: &Config) -> Result<Option<Crate>, HErr> {
+    let index = Path::new(cfg.index_path.as_str());
+    if let SourceConfig::Sparse { url } = &cfg.source {
+        if find_crate_file(index, krate).is_none() {
+            // The sparse index requires the exact path, try the most common
+            // spellings
+            let spellings = [
+                krate.to_owned(),
+                krate.replace('_', "-"),
+                krate.replace('-', "_"),
+            ];
+
+            for spelling in spellings.iter() {
+                if sparse::download(&cfg.http, url, index, spelling).await? {
+                    break;
+                }
+            }
+        }
+    }
+
+    let path = match find_crate_file(index, krate) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    match Crate::read_last(&path).await {
+        Ok(krate) => Ok(Some(krate)),
+        Err(err) => {
+            warn!("couldn't read crate file {:?}: {}", path, err);
+            Ok(None)
+        }
     }
 }
 
@@ -1,12 +1,28 @@
 use fntools::value::ValueExt;
 use serde::Deserialize;
-use std::{collections::HashSet, error::Error, fs::File, io::Read, time::Duration};
+use std::{
+    collections::{BTreeMap, HashSet},
+    error::Error,
+    fs::File,
+    io::Read,
+    time::Duration,
+};
+
+use crate::{filter::ChannelRule, template::Locales};
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
-    /// Channel to post **ALL** updates
+    /// Channel to post **ALL** updates (a shorthand for a channel without
+    /// rules in `channels`)
     #[serde(default)]
     pub channel: Option<i64>,
+    /// Channels to post updates matching their rules
+    #[serde(default)]
+    pub channels: Vec<ChannelConfig>,
+    /// Updates for the channel are gathered for this long and then posted as
+    /// a single message (or a few, if they don't fit into one)
+    #[serde(default = "defaults::channel_window")]
+    pub channel_window: Duration,
     /// Delay between index fetches
     #[serde(default = "defaults::pull_delay")]
     pub pull_delay: Duration,
@@ -16,15 +32,20 @@ pub struct Config {
     /// Url of crates.io index (git repo)
     #[serde(default = "defaults::index_url")]
     pub index_url: String,
-    /// The path to the local crates.io index git repository
+    /// The path to the local crates.io index git repository (or to the mirror
+    /// of the sparse index)
     #[serde(default = "defaults::index_path")]
     pub index_path: String,
-    /// Delay after which bot will retry telegram-request
+    /// Source of index updates
+    #[serde(default)]
+    pub source: SourceConfig,
+    /// Delay after which bot will retry telegram-request (doubled after every
+    /// failed attempt to deliver a notification)
     #[serde(default)]
     pub retry_delay: RetryDelay,
-    /// Delay between broadcast send messages
+    /// Limits of sending messages
     #[serde(default)]
-    pub broadcast_delay_millis: BroadcastDelay,
+    pub rate_limit: RateLimitConfig,
     /// Delay between notifying about updates
     #[serde(default)]
     pub update_delay_millis: UpdateDelay,
@@ -35,13 +56,89 @@ pub struct Config {
     /// Ban configuration
     #[serde(default)]
     pub ban: BanConfig,
+    /// Paths to files with templates of messages by language, override the
+    /// built-in templates
+    #[serde(default, rename = "templates")]
+    pub templates_paths: BTreeMap<String, String>,
+    /// Templates of messages of all languages, loaded from `templates_paths`
+    #[serde(skip)]
+    pub locales: Locales,
+    /// HTTP client shared by the sparse index source and commands which
+    /// download crate files
+    #[serde(skip)]
+    pub http: reqwest::Client,
 }
 
 impl Config {
     pub fn read() -> Result<Self, Box<dyn Error>> {
         let mut str = String::new();
         File::open("./config.toml")?.read_to_string(&mut str)?;
-        Ok(toml::from_str(&str)?)
+
+        let mut config: Self = toml::from_str(&str)?;
+        if let Some(id) = config.channel {
+            config.channels.push(ChannelConfig {
+                id,
+                language: None,
+                rule: ChannelRule::default(),
+            });
+        }
+
+        config.locales = Locales::load(&config.templates_paths)?;
+
+        if let Some(language) = config
+            .channels
+            .iter()
+            .filter_map(|channel| channel.language.as_deref())
+            .find(|language| config.locales.resolve(language) != Some(*language))
+        {
+            return Err(format!("unknown language of a channel: {:?}", language).into());
+        }
+
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChannelConfig {
+    /// Chat id of the channel
+    pub id: i64,
+    /// Language of posts (the default one if not set)
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Which updates are posted to the channel
+    #[serde(flatten)]
+    pub rule: ChannelRule,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SourceConfig {
+    /// Walk commits of the git index (`index_url`)
+    Git,
+    /// Poll files of subscribed crates in the sparse index
+    Sparse {
+        /// Url of the sparse index
+        #[serde(default = "defaults::sparse_index_url")]
+        url: String,
+    },
+    /// Replay updates recorded in a file (one JSON object per line)
+    Replay {
+        /// Path to the file with updates
+        path: String,
+    },
+}
+
+impl SourceConfig {
+    /// Whether the source maintains the reverse-dependency index (see
+    /// `/subscribe_dependents`).
+    pub fn tracks_dependencies(&self) -> bool {
+        matches!(self, Self::Git)
+    }
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        Self::Git
     }
 }
 
@@ -67,21 +164,28 @@ pub struct BanConfig {
     pub crates: HashSet<String>,
 }
 
+/// Limits of sending messages, see
+/// <https://core.telegram.org/bots/faq#my-bot-is-hitting-limits-how-do-i-avoid-this>
 #[derive(Clone, Copy, Debug, Deserialize)]
-#[serde(transparent)]
-pub struct BroadcastDelay {
-    pub millis: u64,
+pub struct RateLimitConfig {
+    /// Maximum number of messages per second (to all chats)
+    #[serde(default = "defaults::global_per_second")]
+    pub global_per_second: u32,
+    /// Maximum number of messages per second to a single private chat
+    #[serde(default = "defaults::private_per_second")]
+    pub private_per_second: u32,
+    /// Maximum number of messages per minute to a single group or channel
+    #[serde(default = "defaults::group_per_minute")]
+    pub group_per_minute: u32,
 }
 
-impl Default for BroadcastDelay {
+impl Default for RateLimitConfig {
     fn default() -> Self {
-        Self { millis: 250 } // quoter of a sec
-    }
-}
-
-impl From<BroadcastDelay> for Duration {
-    fn from(bd: BroadcastDelay) -> Self {
-        Duration::from_millis(bd.millis)
+        Self {
+            global_per_second: defaults::global_per_second(),
+            private_per_second: defaults::private_per_second(),
+            group_per_minute: defaults::group_per_minute(),
+        }
     }
 }
 
@@ -120,6 +224,10 @@ mod defaults {
         Duration::from_secs(60 * 5) // 5 min
     }
 
+    pub(super) const fn channel_window() -> Duration {
+        Duration::from_secs(30)
+    }
+
     pub(super) const fn loglevel() -> log::LevelFilter {
         log::LevelFilter::Info
     }
@@ -131,4 +239,20 @@ mod defaults {
     pub(super) fn index_path() -> String {
         String::from("./index")
     }
+
+    pub(super) fn sparse_index_url() -> String {
+        String::from("https://index.crates.io")
+    }
+
+    pub(super) const fn global_per_second() -> u32 {
+        30
+    }
+
+    pub(super) const fn private_per_second() -> u32 {
+        1
+    }
+
+    pub(super) const fn group_per_minute() -> u32 {
+        20
+    }
 }
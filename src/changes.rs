@@ -0,0 +1,267 @@
+//! Changes between two versions of a crate, computed from their index
+//! entries.
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    krate::{Crate, DependencyKind},
+    template::{Args, Key, Templates},
+};
+
+/// At most this many changes are listed, so notifications fit into telegram
+/// limits.
+const MAX_LISTED: usize = 20;
+
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// `(name, req)` of added dependencies
+    pub deps_added: Vec<(String, String)>,
+    /// `(name, req)` of removed dependencies
+    pub deps_removed: Vec<(String, String)>,
+    /// `(name, old req, new req)` of dependencies with changed requirements
+    pub deps_changed: Vec<(String, String, String)>,
+    pub features_added: Vec<String>,
+    pub features_removed: Vec<String>,
+    /// `(old, new)` minimal supported rust version, if it was changed
+    pub rust_version: Option<(Option<String>, Option<String>)>,
+}
+
+impl Changes {
+    pub fn between(old: &Crate, new: &Crate) -> Self {
+        let (old_deps, new_deps) = (deps(old), deps(new));
+        let (old_features, new_features) = (features(old), features(new));
+
+        let mut res = Self::default();
+
+        for (name, new_req) in &new_deps {
+            match old_deps.get(name) {
+                None => res.deps_added.push((name.clone(), new_req.clone())),
+                Some(old_req) if old_req != new_req => {
+                    res.deps_changed
+                        .push((name.clone(), old_req.clone(), new_req.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+
+        for (name, old_req) in &old_deps {
+            if !new_deps.contains_key(name) {
+                res.deps_removed.push((name.clone(), old_req.clone()));
+            }
+        }
+
+        res.features_added = new_features.difference(&old_features).cloned().collect();
+        res.features_removed = old_features.difference(&new_features).cloned().collect();
+
+        if old.rust_version != new.rust_version {
+            res.rust_version = Some((old.rust_version.clone(), new.rust_version.clone()));
+        }
+
+        res
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deps_added.is_empty()
+            && self.deps_removed.is_empty()
+            && self.deps_changed.is_empty()
+            && self.features_added.is_empty()
+            && self.features_removed.is_empty()
+            && self.rust_version.is_none()
+    }
+
+    /// Render the changes with the `changes` template, returns an empty string
+    /// if there are no changes.
+    pub fn render(&self, templates: &Templates, previous_version: &str) -> String {
+        if self.is_empty() {
+            return String::new();
+        }
+
+        let mut list = Vec::new();
+        match &self.rust_version {
+            None | Some((None, None)) => {}
+            Some((None, Some(new))) => {
+                list.push(templates.render(Key::MsrvSet, &Args::new().text("new", new)))
+            }
+            Some((Some(old), None)) => {
+                list.push(templates.render(Key::MsrvRemoved, &Args::new().text("old", old)))
+            }
+            Some((Some(old), Some(new))) => {
+                let args = Args::new().text("old", old).text("new", new);
+                list.push(templates.render(Key::MsrvChanged, &args));
+            }
+        }
+        for (name, req) in &self.deps_added {
+            let args = Args::new().text("name", name).text("req", req);
+            list.push(templates.render(Key::DepAdded, &args));
+        }
+        for (name, req) in &self.deps_removed {
+            let args = Args::new().text("name", name).text("req", req);
+            list.push(templates.render(Key::DepRemoved, &args));
+        }
+        for (name, old_req, new_req) in &self.deps_changed {
+            let args = Args::new()
+                .text("name", name)
+                .text("old_req", old_req)
+                .text("new_req", new_req);
+            list.push(templates.render(Key::DepChanged, &args));
+        }
+        for name in &self.features_added {
+            list.push(templates.render(Key::FeatureAdded, &Args::new().text("name", name)));
+        }
+        for name in &self.features_removed {
+            list.push(templates.render(Key::FeatureRemoved, &Args::new().text("name", name)));
+        }
+
+        if list.len() > MAX_LISTED {
+            let args = Args::new().text("count", list.len() - MAX_LISTED);
+            list.truncate(MAX_LISTED);
+            list.push(templates.render(Key::MoreChanges, &args));
+        }
+
+        let args = Args::new()
+            .text("previous_version", previous_version)
+            .markup("list", list.join("\n"));
+        templates.render(Key::Changes, &args)
+    }
+}
+
+/// Dependencies of a version: name (with the kind, if it's not a normal
+/// dependency, and the target, if any) => requirement.
+fn deps(krate: &Crate) -> BTreeMap<String, String> {
+    krate
+        .deps
+        .iter()
+        .map(|dep| {
+            let mut name = dep.name.clone();
+            match dep.kind() {
+                DependencyKind::Normal => {}
+                DependencyKind::Build => name.push_str(" (build)"),
+                DependencyKind::Dev => name.push_str(" (dev)"),
+                DependencyKind::Unknown => {
+                    name.push_str(&format!(" ({})", dep.kind.as_deref().unwrap_or_default()))
+                }
+            }
+            if let Some(target) = &dep.target {
+                name.push_str(&format!(" ({})", target));
+            }
+
+            (name, dep.req.clone())
+        })
+        .collect()
+}
+
+fn features(krate: &Crate) -> BTreeSet<String> {
+    krate
+        .features
+        .keys()
+        .chain(krate.features2.iter().flat_map(|features| features.keys()))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Changes, MAX_LISTED};
+    use crate::{krate::Crate, template::Locales};
+
+    fn krate(json: &str) -> Crate {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn pair(a: &str, b: &str) -> (String, String) {
+        (a.to_owned(), b.to_owned())
+    }
+
+    #[test]
+    fn changes_between_versions() {
+        let old = krate(
+            r#"{
+                "name": "foo", "vers": "1.0.0", "yanked": false,
+                "deps": [
+                    {"name": "serde", "req": "^1.0"},
+                    {"name": "log", "req": "^0.4"},
+                    {"name": "cc", "req": "^1.0", "kind": "build"},
+                    {"name": "winapi", "req": "^0.3", "target": "cfg(windows)"}
+                ],
+                "features": {"default": ["std"], "std": []}
+            }"#,
+        );
+        let new = krate(
+            r#"{
+                "name": "foo", "vers": "1.1.0", "yanked": false,
+                "deps": [
+                    {"name": "serde", "req": "^1.0.100"},
+                    {"name": "cc", "req": "^1.0", "kind": "build"},
+                    {"name": "tokio", "req": "^1.0", "kind": "dev"},
+                    {"name": "winapi", "req": "^0.3", "target": "cfg(windows)"}
+                ],
+                "features": {"default": ["std"], "std": []},
+                "features2": {"async": ["dep:tokio"]},
+                "rust_version": "1.56"
+            }"#,
+        );
+
+        let changes = Changes::between(&old, &new);
+        assert_eq!(changes.deps_added, [pair("tokio (dev)", "^1.0")]);
+        assert_eq!(changes.deps_removed, [pair("log", "^0.4")]);
+        assert_eq!(
+            changes.deps_changed,
+            [("serde".to_owned(), "^1.0".to_owned(), "^1.0.100".to_owned())]
+        );
+        assert_eq!(changes.features_added, ["async"]);
+        assert!(changes.features_removed.is_empty());
+        assert_eq!(changes.rust_version, Some((None, Some("1.56".to_owned()))));
+
+        let reverse = Changes::between(&new, &old);
+        assert_eq!(reverse.deps_added, [pair("log", "^0.4")]);
+        assert_eq!(reverse.deps_removed, [pair("tokio (dev)", "^1.0")]);
+        assert_eq!(reverse.features_removed, ["async"]);
+        assert_eq!(reverse.rust_version, Some((Some("1.56".to_owned()), None)));
+
+        let locales = Locales::builtin();
+        let rendered = changes.render(locales.get(Some("en")), "1.0.0");
+        assert!(rendered.contains("Changes since <code>1.0.0</code>"));
+        assert!(rendered.contains("MSRV: <code>1.56</code>"));
+        assert!(rendered.contains("+ <code>tokio (dev) ^1.0</code>"));
+        assert!(rendered.contains("− <code>log ^0.4</code>"));
+        assert!(
+            rendered.contains("~ <code>serde</code>: <code>^1.0</code> → <code>^1.0.100</code>")
+        );
+        assert!(rendered.contains("+ feature <code>async</code>"));
+    }
+
+    #[test]
+    fn same_version_has_no_changes() {
+        let old = krate(
+            r#"{"name": "foo", "vers": "1.0.0", "yanked": false,
+                "deps": [{"name": "serde", "req": "^1.0"}]}"#,
+        );
+        let new = krate(
+            r#"{"name": "foo", "vers": "1.0.1", "yanked": false,
+                "deps": [{"name": "serde", "req": "^1.0"}]}"#,
+        );
+
+        let changes = Changes::between(&old, &new);
+        assert!(changes.is_empty());
+        assert_eq!(changes.render(Locales::builtin().get(None), "1.0.0"), "");
+    }
+
+    #[test]
+    fn long_lists_are_truncated() {
+        let deps = (0..MAX_LISTED + 5)
+            .map(|i| format!(r#"{{"name": "dep{}", "req": "^1.0"}}"#, i))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let old = krate(r#"{"name": "foo", "vers": "1.0.0", "yanked": false}"#);
+        let new = krate(&format!(
+            r#"{{"name": "foo", "vers": "2.0.0", "yanked": false, "deps": [{}]}}"#,
+            deps
+        ));
+
+        let changes = Changes::between(&old, &new);
+        assert_eq!(changes.deps_added.len(), MAX_LISTED + 5);
+
+        let rendered = changes.render(Locales::builtin().get(Some("en")), "1.0.0");
+        assert_eq!(rendered.matches("+ <code>dep").count(), MAX_LISTED);
+        assert!(rendered.ends_with("… and 5 more"));
+    }
+}
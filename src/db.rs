@@ -1,9 +1,13 @@
 use futures::Future;
 use tokio_postgres::{
-    tls::MakeTlsConnect, types::Type, Client, Config, Connection, Error, Socket, Statement,
+    tls::MakeTlsConnect, types::Type, Client, Config, Connection, Error, Row, Socket, Statement,
 };
 
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 #[derive(Clone)]
 pub struct Database {
@@ -31,10 +35,14 @@ impl Database {
         Ok((this, connection))
     }
 
-    pub async fn subscribe(&self, chat_id: i64, krate: &str) -> Result<(), Error> {
+    /// Subscribe to a crate (or change the filter of an existing
+    /// subscription).
+    pub async fn subscribe(&self, chat_id: i64, krate: &str, filter: &str) -> Result<(), Error> {
         let stmt = &self.prepared.subscribe;
 
-        self.inner.execute(stmt, &[&chat_id, &krate]).await?;
+        self.inner
+            .execute(stmt, &[&chat_id, &krate, &filter])
+            .await?;
 
         Ok(())
     }
@@ -47,15 +55,53 @@ impl Database {
         Ok(())
     }
 
-    pub async fn list_subscribers(&self, krate: &str) -> Result<impl Iterator<Item = i64>, Error> {
+    /// Remove all subscriptions, watches, pins, settings and pending digest
+    /// items of a chat (in a single statement).
+    pub async fn forget_chat(&self, chat_id: i64) -> Result<(), Error> {
+        let stmt = &self.prepared.forget_chat;
+
+        self.inner.execute(stmt, &[&chat_id]).await?;
+
+        Ok(())
+    }
+
+    /// Set kinds of updates (bitmask) a subscriber wants to be notified
+    /// about.
+    ///
+    /// Returns `false` if the chat isn't subscribed to the crate.
+    pub async fn set_events(&self, chat_id: i64, krate: &str, events: i32) -> Result<bool, Error> {
+        let stmt = &self.prepared.set_events;
+
+        let row = self
+            .inner
+            .query_one(stmt, &[&chat_id, &krate, &events])
+            .await?;
+
+        Ok(row.get(0))
+    }
+
+    /// Returns subscribers of a crate that want to be notified about any of
+    /// `events` (bitmask).
+    pub async fn list_subscribers(
+        &self,
+        krate: &str,
+        events: i32,
+    ) -> Result<impl Iterator<Item = Subscriber>, Error> {
         let stmt = &self.prepared.list_subscribers;
 
         let res = self
             .inner
-            .query(stmt, &[&krate])
+            .query(stmt, &[&krate, &events])
             .await?
             .into_iter()
-            .map(|row| row.get(0));
+            .map(|row| Subscriber {
+                chat_id: row.get(0),
+                filter: row.get(1),
+                events: row.get(2),
+                digest: row.get(3),
+                language: row.get(4),
+                toolchain: row.get(5),
+            });
 
         Ok(res)
     }
@@ -63,7 +109,7 @@ impl Database {
     pub async fn list_subscriptions(
         &self,
         chat_id: i64,
-    ) -> Result<impl Iterator<Item = String>, Error> {
+    ) -> Result<impl Iterator<Item = Subscription>, Error> {
         let stmt = &self.prepared.list_subscriptions;
 
         let res = self
@@ -71,49 +117,1417 @@ impl Database {
             .query(stmt, &[&chat_id])
             .await?
             .into_iter()
+            .map(|row| Subscription {
+                crate_name: row.get(0),
+                filter: row.get(1),
+                events: row.get(2),
+                manifest: row.get(3),
+            });
+
+        Ok(res)
+    }
+
+    /// Synchronize subscriptions that came from a manifest (`Cargo.lock` or
+    /// `Cargo.toml`) with the given list of crates.
+    ///
+    /// Returns the number of added and removed subscriptions.
+    pub async fn sync_manifest(
+        &self,
+        chat_id: i64,
+        manifest: &str,
+        crates: &[&str],
+    ) -> Result<(i64, i64), Error> {
+        let stmt = &self.prepared.sync_manifest;
+
+        let row = self
+            .inner
+            .query_one(stmt, &[&chat_id, &manifest, &crates])
+            .await?;
+
+        Ok((row.get(0), row.get(1)))
+    }
+
+    /// Replace exact versions of crates pinned in a project's lockfile.
+    ///
+    /// `crates` and `versions` must have the same length.
+    pub async fn sync_pins(
+        &self,
+        chat_id: i64,
+        project: &str,
+        crates: &[&str],
+        versions: &[&str],
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.sync_pins;
+
+        self.inner
+            .execute(stmt, &[&chat_id, &project, &crates, &versions])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns chats (and their projects) which pinned the given version of a
+    /// crate.
+    pub async fn list_pinned(
+        &self,
+        krate: &str,
+        vers: &str,
+    ) -> Result<impl Iterator<Item = (i64, String)>, Error> {
+        let stmt = &self.prepared.list_pinned;
+
+        let res = self
+            .inner
+            .query(stmt, &[&krate, &vers])
+            .await?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)));
+
+        Ok(res)
+    }
+
+    /// Change spelling of a crate name (e.g. to the canonical one).
+    pub async fn rename_crate(&self, old: &str, new: &str) -> Result<(), Error> {
+        let stmt = &self.prepared.rename_crate;
+
+        self.inner.execute(stmt, &[&old, &new]).await?;
+
+        Ok(())
+    }
+
+    /// Returns names of all crates that have at least one subscriber.
+    pub async fn list_subscribed_crates(&self) -> Result<impl Iterator<Item = String>, Error> {
+        let stmt = &self.prepared.list_subscribed_crates;
+
+        let res = self
+            .inner
+            .query(stmt, &[])
+            .await?
+            .into_iter()
             .map(|row| row.get(0));
 
         Ok(res)
     }
-}
 
-struct Prepared {
-    subscribe: Statement,
-    unsubscribe: Statement,
-    list_subscribers: Statement,
-    list_subscriptions: Statement,
-}
+    /// Record a commit of the index which was skipped because it couldn't be
+    /// parsed.
+    pub async fn add_skipped_commit(
+        &self,
+        commit: &str,
+        author: Option<&str>,
+        message: Option<&str>,
+        reason: &str,
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.add_skipped_commit;
 
-impl Prepared {
-    async fn prepare(
-        client: &Client,
-        connection: &mut (impl Future<Output = Result<(), Error>> + Unpin),
-    ) -> Result<Self, Error> {
-        let prepare = async {
-            let subscribe = client
-                .prepare_typed("CALL subscribe($1, $2)", &[Type::INT8, Type::VARCHAR])
-                .await?;
+        self.inner
+            .execute(stmt, &[&commit, &author, &message, &reason])
+            .await?;
 
-            let unsubscribe = client
-                .prepare_typed("CALL unsubscribe($1, $2)", &[Type::INT8, Type::VARCHAR])
-                .await?;
+        Ok(())
+    }
 
-            let list_subscribers = client
-                .prepare_typed("SELECT user_id from list_subscribers($1)", &[Type::VARCHAR])
-                .await?;
+    /// Returns the last processed commit of the index (if any).
+    pub async fn index_cursor(&self) -> Result<Option<IndexCursor>, Error> {
+        let stmt = &self.prepared.get_index_cursor;
 
-            let list_subscriptions = client
-                .prepare_typed(
-                    "SELECT crate_name from list_subscriptions($1)",
-                    &[Type::INT8],
-                )
+        let res = self
+            .inner
+            .query_opt(stmt, &[])
+            .await?
+            .map(|row| IndexCursor {
+                commit: row.get(0),
+                processed_at: row.get(1),
+            });
+
+        Ok(res)
+    }
+
+    pub async fn set_index_cursor(&self, commit: &str) -> Result<(), Error> {
+        let stmt = &self.prepared.set_index_cursor;
+
+        self.inner.execute(stmt, &[&commit]).await?;
+
+        Ok(())
+    }
+
+    /// Returns saved states of files of the sparse index (lowercase crate
+    /// name => state).
+    pub async fn list_sparse_files(
+        &self,
+    ) -> Result<impl Iterator<Item = (String, SparseFile)>, Error> {
+        let stmt = &self.prepared.list_sparse_files;
+
+        let res = self.inner.query(stmt, &[]).await?.into_iter().map(|row| {
+            let versions: Vec<String> = row.get(3);
+            let yanked: Vec<bool> = row.get(4);
+            let file = SparseFile {
+                etag: row.get(1),
+                last_modified: row.get(2),
+                versions: versions.into_iter().zip(yanked).collect(),
+            };
+
+            (row.get(0), file)
+        });
+
+        Ok(res)
+    }
+
+    /// Save state of a file of the sparse index.
+    pub async fn set_sparse_file(&self, krate: &str, file: &SparseFile) -> Result<(), Error> {
+        let stmt = &self.prepared.set_sparse_file;
+
+        let (versions, yanked): (Vec<_>, Vec<_>) = file
+            .versions
+            .iter()
+            .map(|(vers, yanked)| (vers.as_str(), *yanked))
+            .unzip();
+
+        self.inner
+            .execute(
+                stmt,
+                &[&krate, &file.etag, &file.last_modified, &versions, &yanked],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Forget state of a file of the sparse index (e.g. if the crate was
+    /// deleted).
+    pub async fn delete_sparse_file(&self, krate: &str) -> Result<(), Error> {
+        let stmt = &self.prepared.delete_sparse_file;
+
+        self.inner.execute(stmt, &[&krate]).await?;
+
+        Ok(())
+    }
+
+    /// Add messages to the outbox, skipping ones with already known dedup
+    /// keys.
+    ///
+    /// All messages are added in a single statement, so either all or none of
+    /// them are added.
+    pub async fn enqueue_messages(&self, messages: &[OutgoingMessage]) -> Result<(), Error> {
+        let stmt = &self.prepared.enqueue_messages;
+
+        let m = OutboxColumns::new(messages);
+        self.inner
+            .execute(
+                stmt,
+                &[
+                    &m.chat_ids,
+                    &m.payloads,
+                    &m.silent,
+                    &m.dedup_keys,
+                    &m.coalesce,
+                    &m.next_attempts_at,
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Record everything the notifier writes about an update of `krate`:
+    /// add it to digests of `digest` chats (`(chat, dedup key)`), add
+    /// `messages` to the outbox and record `migrations` of the crate
+    /// (`(dependency, old range, new range)`).
+    ///
+    /// Everything is written in a single statement, so either all or none of
+    /// it is written. Already known dedup keys and migrations are skipped, so
+    /// retrying is safe.
+    pub async fn record_update(
+        &self,
+        krate: &str,
+        vers: &str,
+        action: &str,
+        digest: &[(i64, String)],
+        messages: &[OutgoingMessage],
+        migrations: &[(&str, String, String)],
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.record_update;
+
+        let (digest_chats, digest_keys): (Vec<_>, Vec<_>) = digest
+            .iter()
+            .map(|(chat_id, dedup_key)| (*chat_id, dedup_key.as_str()))
+            .unzip();
+        let m = OutboxColumns::new(messages);
+        let dependencies: Vec<_> = migrations.iter().map(|(dep, _, _)| *dep).collect();
+        let old_ranges: Vec<_> = migrations.iter().map(|(_, old, _)| old.as_str()).collect();
+        let new_ranges: Vec<_> = migrations.iter().map(|(_, _, new)| new.as_str()).collect();
+
+        self.inner
+            .execute(
+                stmt,
+                &[
+                    &krate,
+                    &vers,
+                    &action,
+                    &digest_chats,
+                    &digest_keys,
+                    &m.chat_ids,
+                    &m.payloads,
+                    &m.silent,
+                    &m.dedup_keys,
+                    &m.coalesce,
+                    &m.next_attempts_at,
+                    &dependencies,
+                    &old_ranges,
+                    &new_ranges,
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns at most `limit` undelivered messages which are due, oldest
+    /// first.
+    pub async fn next_messages(
+        &self,
+        limit: i64,
+        max_attempts: i32,
+    ) -> Result<impl Iterator<Item = QueuedMessage>, Error> {
+        let stmt = &self.prepared.next_messages;
+
+        let res = self
+            .inner
+            .query(stmt, &[&limit, &max_attempts])
+            .await?
+            .into_iter()
+            .map(QueuedMessage::from_row);
+
+        Ok(res)
+    }
+
+    /// Returns at most `limit` undelivered messages to a chat which may be
+    /// combined (including ones which are not due yet), oldest first.
+    pub async fn list_coalesced(
+        &self,
+        chat_id: i64,
+        limit: i64,
+        max_attempts: i32,
+    ) -> Result<impl Iterator<Item = QueuedMessage>, Error> {
+        let stmt = &self.prepared.list_coalesced;
+
+        let res = self
+            .inner
+            .query(stmt, &[&chat_id, &limit, &max_attempts])
+            .await?
+            .into_iter()
+            .map(QueuedMessage::from_row);
+
+        Ok(res)
+    }
+
+    pub async fn message_delivered(&self, id: i64) -> Result<(), Error> {
+        let stmt = &self.prepared.message_delivered;
+
+        self.inner.execute(stmt, &[&id]).await?;
+
+        Ok(())
+    }
+
+    /// Record a failed delivery attempt and schedule the next one.
+    pub async fn message_failed(
+        &self,
+        id: i64,
+        error: &str,
+        next_attempt_at: SystemTime,
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.message_failed;
+
+        self.inner
+            .execute(stmt, &[&id, &error, &next_attempt_at])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Move the next delivery attempt of a message without counting a failed
+    /// attempt (e.g. because of rate limits).
+    pub async fn postpone_message(
+        &self,
+        id: i64,
+        next_attempt_at: SystemTime,
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.postpone_message;
+
+        self.inner.execute(stmt, &[&id, &next_attempt_at]).await?;
+
+        Ok(())
+    }
+
+    /// Set how notifications are delivered to a chat (see
+    /// [`crate::digest::Delivery`]).
+    pub async fn set_delivery(
+        &self,
+        chat_id: i64,
+        delivery: &str,
+        digest_minute: i32,
+        utc_offset_minutes: i32,
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.set_delivery;
+
+        self.inner
+            .execute(
+                stmt,
+                &[&chat_id, &delivery, &digest_minute, &utc_offset_minutes],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns delivery mode, local time of daily digests and UTC offset of a
+    /// chat (`None` if the chat has default settings).
+    pub async fn get_delivery(&self, chat_id: i64) -> Result<Option<(String, i32, i32)>, Error> {
+        let stmt = &self.prepared.get_delivery;
+
+        let res = self
+            .inner
+            .query_opt(stmt, &[&chat_id])
+            .await?
+            .map(|row| (row.get(0), row.get(1), row.get(2)));
+
+        Ok(res)
+    }
+
+    pub async fn set_language(&self, chat_id: i64, language: &str) -> Result<(), Error> {
+        let stmt = &self.prepared.set_language;
+
+        self.inner.execute(stmt, &[&chat_id, &language]).await?;
+
+        Ok(())
+    }
+
+    /// Set language of a chat, unless it's already set.
+    pub async fn init_language(&self, chat_id: i64, language: &str) -> Result<(), Error> {
+        let stmt = &self.prepared.init_language;
+
+        self.inner.execute(stmt, &[&chat_id, &language]).await?;
+
+        Ok(())
+    }
+
+    /// Returns language of a chat (`None` for the default).
+    pub async fn get_language(&self, chat_id: i64) -> Result<Option<String>, Error> {
+        let stmt = &self.prepared.get_language;
+
+        let res = self
+            .inner
+            .query_opt(stmt, &[&chat_id])
+            .await?
+            .and_then(|row| row.get(0));
+
+        Ok(res)
+    }
+
+    /// Set rust toolchain version of a chat (`None` to unset).
+    pub async fn set_toolchain(&self, chat_id: i64, toolchain: Option<&str>) -> Result<(), Error> {
+        let stmt = &self.prepared.set_toolchain;
+
+        self.inner.execute(stmt, &[&chat_id, &toolchain]).await?;
+
+        Ok(())
+    }
+
+    /// Returns rust toolchain version of a chat (`None` if it's not set).
+    pub async fn get_toolchain(&self, chat_id: i64) -> Result<Option<String>, Error> {
+        let stmt = &self.prepared.get_toolchain;
+
+        let res = self
+            .inner
+            .query_opt(stmt, &[&chat_id])
+            .await?
+            .and_then(|row| row.get(0));
+
+        Ok(res)
+    }
+
+    /// Enable or disable typosquatting alerts for a chat.
+    pub async fn set_typosquat_alerts(&self, chat_id: i64, enabled: bool) -> Result<(), Error> {
+        let stmt = &self.prepared.set_typosquat_alerts;
+
+        self.inner.execute(stmt, &[&chat_id, &enabled]).await?;
+
+        Ok(())
+    }
+
+    /// Returns whether typosquatting alerts are enabled for a chat.
+    pub async fn get_typosquat_alerts(&self, chat_id: i64) -> Result<bool, Error> {
+        let stmt = &self.prepared.get_typosquat_alerts;
+
+        let res = self
+            .inner
+            .query_opt(stmt, &[&chat_id])
+            .await?
+            .map_or(false, |row| row.get(0));
+
+        Ok(res)
+    }
+
+    /// Returns `(chat, crate, language)` for all subscriptions of chats with
+    /// typosquatting alerts enabled, ordered by crate.
+    pub async fn list_typosquat_watches(
+        &self,
+    ) -> Result<impl Iterator<Item = (i64, String, Option<String>)>, Error> {
+        let stmt = &self.prepared.list_typosquat_watches;
+
+        let res = self
+            .inner
+            .query(stmt, &[])
+            .await?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)));
+
+        Ok(res)
+    }
+
+    /// Replace dependencies of `crates` in the reverse-dependency index with
+    /// `(dependent, dependency)` pairs.
+    ///
+    /// `dependents` and `dependencies` must have the same length.
+    pub async fn set_dependencies(
+        &self,
+        crates: &[&str],
+        dependents: &[&str],
+        dependencies: &[&str],
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.set_dependencies;
+
+        self.inner
+            .execute(stmt, &[&crates, &dependents, &dependencies])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Like [`Database::set_dependencies`], but also marks the
+    /// reverse-dependency index as built (in the same statement).
+    pub async fn finish_reverse_deps(
+        &self,
+        crates: &[&str],
+        dependents: &[&str],
+        dependencies: &[&str],
+    ) -> Result<(), Error> {
+        let stmt = &self.prepared.finish_reverse_deps;
+
+        self.inner
+            .execute(stmt, &[&crates, &dependents, &dependencies])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns `false` if building of the reverse-dependency index wasn't
+    /// finished yet.
+    pub async fn has_reverse_deps(&self) -> Result<bool, Error> {
+        let stmt = &self.prepared.has_reverse_deps;
+
+        let row = self.inner.query_one(stmt, &[]).await?;
+
+        Ok(row.get(0))
+    }
+
+    /// Returns number of crates which depend on `krate`.
+    pub async fn count_dependents(&self, krate: &str) -> Result<i64, Error> {
+        let stmt = &self.prepared.count_dependents;
+
+        let row = self.inner.query_one(stmt, &[&krate]).await?;
+
+        Ok(row.get(0))
+    }
+
+    /// Subscribe to new versions of crates which depend on `krate`.
+    pub async fn subscribe_dependents(&self, chat_id: i64, krate: &str) -> Result<(), Error> {
+        let stmt = &self.prepared.subscribe_dependents;
+
+        self.inner.execute(stmt, &[&chat_id, &krate]).await?;
+
+        Ok(())
+    }
+
+    pub async fn unsubscribe_dependents(&self, chat_id: i64, krate: &str) -> Result<(), Error> {
+        let stmt = &self.prepared.unsubscribe_dependents;
+
+        self.inner.execute(stmt, &[&chat_id, &krate]).await?;
+
+        Ok(())
+    }
+
+    /// Returns crates whose dependents the chat is subscribed to, along with
+    /// the number of dependents.
+    pub async fn list_dependents_subscriptions(
+        &self,
+        chat_id: i64,
+    ) -> Result<impl Iterator<Item = (String, i64)>, Error> {
+        let stmt = &self.prepared.list_dependents_subscriptions;
+
+        let res = self
+            .inner
+            .query(stmt, &[&chat_id])
+            .await?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)));
+
+        Ok(res)
+    }
+
+    /// Returns chats subscribed to dependents of any crate `krate` depends
+    /// on: chat id, these crates (comma-separated), whether the chat receives
+    /// digests and language of the chat.
+    pub async fn list_dependents_subscribers(
+        &self,
+        krate: &str,
+    ) -> Result<impl Iterator<Item = (i64, String, bool, Option<String>)>, Error> {
+        let stmt = &self.prepared.list_dependents_subscribers;
+
+        let res = self
+            .inner
+            .query(stmt, &[&krate])
+            .await?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2), row.get(3)));
+
+        Ok(res)
+    }
+
+    /// Returns the number of crates which migrated to each of `new_ranges` of
+    /// `dependencies`, counting `dependent` as migrated (migrations are
+    /// recorded by [`Database::record_update`]).
+    ///
+    /// `dependencies` and `new_ranges` must have the same length.
+    pub async fn count_new_migrations(
+        &self,
+        dependent: &str,
+        dependencies: &[&str],
+        new_ranges: &[&str],
+    ) -> Result<Vec<i64>, Error> {
+        let stmt = &self.prepared.count_new_migrations;
+
+        let res = self
+            .inner
+            .query(stmt, &[&dependent, &dependencies, &new_ranges])
+            .await?
+            .into_iter()
+            .map(|row| row.get(0))
+            .collect();
+
+        Ok(res)
+    }
+
+    /// Returns compatibility ranges of `krate` its dependents migrated to,
+    /// along with the number of migrated crates (the most recent first).
+    pub async fn count_migrations(
+        &self,
+        krate: &str,
+    ) -> Result<impl Iterator<Item = (String, i64)>, Error> {
+        let stmt = &self.prepared.count_migrations;
+
+        let res = self
+            .inner
+            .query(stmt, &[&krate])
+            .await?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)));
+
+        Ok(res)
+    }
+
+    /// Watch migrations of dependents of `krate` to its new versions.
+    pub async fn watch_migrations(&self, chat_id: i64, krate: &str) -> Result<(), Error> {
+        let stmt = &self.prepared.watch_migrations;
+
+        self.inner.execute(stmt, &[&chat_id, &krate]).await?;
+
+        Ok(())
+    }
+
+    pub async fn unwatch_migrations(&self, chat_id: i64, krate: &str) -> Result<(), Error> {
+        let stmt = &self.prepared.unwatch_migrations;
+
+        self.inner.execute(stmt, &[&chat_id, &krate]).await?;
+
+        Ok(())
+    }
+
+    /// Returns crates whose migrations the chat watches.
+    pub async fn list_migration_watches(
+        &self,
+        chat_id: i64,
+    ) -> Result<impl Iterator<Item = String>, Error> {
+        let stmt = &self.prepared.list_migration_watches;
+
+        let res = self
+            .inner
+            .query(stmt, &[&chat_id])
+            .await?
+            .into_iter()
+            .map(|row| row.get(0));
+
+        Ok(res)
+    }
+
+    /// Returns chats watching migrations of `krate` along with their
+    /// languages.
+    pub async fn list_migration_watchers(
+        &self,
+        krate: &str,
+    ) -> Result<impl Iterator<Item = (i64, Option<String>)>, Error> {
+        let stmt = &self.prepared.list_migration_watchers;
+
+        let res = self
+            .inner
+            .query(stmt, &[&krate])
+            .await?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)));
+
+        Ok(res)
+    }
+
+    /// Watch new crates with names matching a pattern (see
+    /// [`crate::pattern::Pattern`]).
+    pub async fn watch_new(&self, chat_id: i64, pattern: &str) -> Result<(), Error> {
+        let stmt = &self.prepared.watch_new;
+
+        self.inner.execute(stmt, &[&chat_id, &pattern]).await?;
+
+        Ok(())
+    }
+
+    /// Returns `false` if the chat didn't watch the pattern.
+    pub async fn unwatch_new(&self, chat_id: i64, pattern: &str) -> Result<bool, Error> {
+        let stmt = &self.prepared.unwatch_new;
+
+        let row = self.inner.query_one(stmt, &[&chat_id, &pattern]).await?;
+
+        Ok(row.get(0))
+    }
+
+    /// Returns patterns of new crates the chat watches.
+    pub async fn list_new_watches(
+        &self,
+        chat_id: i64,
+    ) -> Result<impl Iterator<Item = String>, Error> {
+        let stmt = &self.prepared.list_new_watches;
+
+        let res = self
+            .inner
+            .query(stmt, &[&chat_id])
+            .await?
+            .into_iter()
+            .map(|row| row.get(0));
+
+        Ok(res)
+    }
+
+    /// Returns all watched patterns of new crates: chat id, pattern and
+    /// language of the chat (ordered by pattern).
+    pub async fn list_new_crate_watchers(
+        &self,
+    ) -> Result<impl Iterator<Item = (i64, String, Option<String>)>, Error> {
+        let stmt = &self.prepared.list_new_crate_watchers;
+
+        let res = self
+            .inner
+            .query(stmt, &[])
+            .await?
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)));
+
+        Ok(res)
+    }
+
+    /// Returns chats which have pending digest items and whose digest is due.
+    pub async fn due_digests(&self) -> Result<impl Iterator<Item = i64>, Error> {
+        let stmt = &self.prepared.due_digests;
+
+        let res = self
+            .inner
+            .query(stmt, &[])
+            .await?
+            .into_iter()
+            .map(|row| row.get(0));
+
+        Ok(res)
+    }
+
+    /// Returns pending digest items of a chat, oldest first.
+    pub async fn list_digest_items(
+        &self,
+        chat_id: i64,
+    ) -> Result<impl Iterator<Item = DigestItem>, Error> {
+        let stmt = &self.prepared.list_digest_items;
+
+        let res = self
+            .inner
+            .query(stmt, &[&chat_id])
+            .await?
+            .into_iter()
+            .map(|row| DigestItem {
+                id: row.get(0),
+                crate_name: row.get(1),
+                vers: row.get(2),
+                action: row.get(3),
+            });
+
+        Ok(res)
+    }
+
+    /// Remove digest items up to `last_item_id` (inclusive) after the digest
+    /// was sent.
+    pub async fn finish_digest(&self, chat_id: i64, last_item_id: i64) -> Result<(), Error> {
+        let stmt = &self.prepared.finish_digest;
+
+        self.inner.execute(stmt, &[&chat_id, &last_item_id]).await?;
+
+        Ok(())
+    }
+
+    /// Forget messages delivered before `before`.
+    pub async fn delete_delivered_messages(&self, before: SystemTime) -> Result<(), Error> {
+        let stmt = &self.prepared.delete_delivered_messages;
+
+        self.inner.execute(stmt, &[&before]).await?;
+
+        Ok(())
+    }
+}
+
+/// A message to be added to the outbox.
+#[derive(Debug)]
+pub struct OutgoingMessage {
+    pub chat_id: i64,
+    /// Text of the message (formatted according to templates)
+    pub payload: String,
+    /// Send the message without sound
+    pub silent: bool,
+    /// Identifies the message, the same message is never added twice
+    pub dedup_key: String,
+    /// If set, the message is delayed for this long and then sent combined
+    /// with other such messages to the same chat
+    pub coalesce_window: Option<Duration>,
+}
+
+/// Columns of messages added to the outbox, as taken by `enqueue_messages`.
+struct OutboxColumns<'a> {
+    chat_ids: Vec<i64>,
+    payloads: Vec<&'a str>,
+    silent: Vec<bool>,
+    dedup_keys: Vec<&'a str>,
+    coalesce: Vec<bool>,
+    next_attempts_at: Vec<SystemTime>,
+}
+
+impl<'a> OutboxColumns<'a> {
+    fn new(messages: &'a [OutgoingMessage]) -> Self {
+        let now = SystemTime::now();
+
+        Self {
+            chat_ids: messages.iter().map(|m| m.chat_id).collect(),
+            payloads: messages.iter().map(|m| m.payload.as_str()).collect(),
+            silent: messages.iter().map(|m| m.silent).collect(),
+            dedup_keys: messages.iter().map(|m| m.dedup_key.as_str()).collect(),
+            coalesce: messages
+                .iter()
+                .map(|m| m.coalesce_window.is_some())
+                .collect(),
+            next_attempts_at: messages
+                .iter()
+                .map(|m| now + m.coalesce_window.unwrap_or_default())
+                .collect(),
+        }
+    }
+}
+
+/// An update waiting to be sent as a part of a digest.
+#[derive(Debug)]
+pub struct DigestItem {
+    pub id: i64,
+    pub crate_name: String,
+    pub vers: String,
+    /// Kind of the update (see [`crate::krate::ActionKind::as_str`])
+    pub action: String,
+}
+
+/// An undelivered message from the outbox.
+#[derive(Debug)]
+pub struct QueuedMessage {
+    pub id: i64,
+    pub chat_id: i64,
+    /// Text of the message (formatted according to templates)
+    pub payload: String,
+    /// Send the message without sound
+    pub silent: bool,
+    /// Number of failed delivery attempts so far
+    pub attempts: i32,
+    /// The message may be combined with others (see
+    /// [`OutgoingMessage::coalesce_window`])
+    pub coalesce: bool,
+}
+
+impl QueuedMessage {
+    fn from_row(row: Row) -> Self {
+        Self {
+            id: row.get(0),
+            chat_id: row.get(1),
+            payload: row.get(2),
+            silent: row.get(3),
+            attempts: row.get(4),
+            coalesce: row.get(5),
+        }
+    }
+}
+
+/// A subscription of a chat to a crate.
+#[derive(Debug)]
+pub struct Subscription {
+    pub crate_name: String,
+    /// Filter of versions (see [`crate::filter::VersionFilter`])
+    pub filter: String,
+    /// Bitmask of kinds of updates (see [`crate::filter::EventMask`])
+    pub events: i32,
+    /// Name of the manifest the subscription came from (`None` for manual
+    /// subscriptions)
+    pub manifest: Option<String>,
+}
+
+/// A chat subscribed to a crate, see [`Database::list_subscribers`].
+#[derive(Debug)]
+pub struct Subscriber {
+    pub chat_id: i64,
+    /// Filter of versions (see [`crate::filter::VersionFilter`])
+    pub filter: String,
+    /// Bitmask of kinds of updates (see [`crate::filter::EventMask`])
+    pub events: i32,
+    /// Whether the chat receives digests instead of immediate notifications
+    pub digest: bool,
+    /// Language of the chat (`None` for the default)
+    pub language: Option<String>,
+    /// Rust toolchain version of the chat (see [`crate::msrv::RustVersion`])
+    pub toolchain: Option<String>,
+}
+
+/// The last processed commit of the crates.io index.
+#[derive(Debug)]
+pub struct IndexCursor {
+    /// Id of the commit (hex)
+    pub commit: String,
+    /// Time at which the commit was processed
+    pub processed_at: SystemTime,
+}
+
+/// State of a crate file of the sparse index as of the last poll, see
+/// [`crate::source::SparseSource`].
+#[derive(Debug, Clone, Default)]
+pub struct SparseFile {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// `vers` -> `yanked` for every version in the file
+    pub versions: HashMap<String, bool>,
+}
+
+struct Prepared {
+    subscribe: Statement,
+    unsubscribe: Statement,
+    forget_chat: Statement,
+    set_events: Statement,
+    list_subscribers: Statement,
+    list_subscriptions: Statement,
+    list_subscribed_crates: Statement,
+    sync_manifest: Statement,
+    sync_pins: Statement,
+    list_pinned: Statement,
+    rename_crate: Statement,
+    get_index_cursor: Statement,
+    set_index_cursor: Statement,
+    list_sparse_files: Statement,
+    set_sparse_file: Statement,
+    delete_sparse_file: Statement,
+    add_skipped_commit: Statement,
+    enqueue_messages: Statement,
+    next_messages: Statement,
+    list_coalesced: Statement,
+    message_delivered: Statement,
+    message_failed: Statement,
+    postpone_message: Statement,
+    delete_delivered_messages: Statement,
+    set_delivery: Statement,
+    get_delivery: Statement,
+    set_language: Statement,
+    init_language: Statement,
+    get_language: Statement,
+    set_toolchain: Statement,
+    get_toolchain: Statement,
+    set_typosquat_alerts: Statement,
+    get_typosquat_alerts: Statement,
+    list_typosquat_watches: Statement,
+    set_dependencies: Statement,
+    finish_reverse_deps: Statement,
+    has_reverse_deps: Statement,
+    count_dependents: Statement,
+    subscribe_dependents: Statement,
+    unsubscribe_dependents: Statement,
+    list_dependents_subscriptions: Statement,
+    list_dependents_subscribers: Statement,
+    record_update: Statement,
+    count_new_migrations: Statement,
+    count_migrations: Statement,
+    watch_migrations: Statement,
+    unwatch_migrations: Statement,
+    list_migration_watches: Statement,
+    list_migration_watchers: Statement,
+    watch_new: Statement,
+    unwatch_new: Statement,
+    list_new_watches: Statement,
+    list_new_crate_watchers: Statement,
+    due_digests: Statement,
+    list_digest_items: Statement,
+    finish_digest: Statement,
+}
+
+impl Prepared {
+    async fn prepare(
+        client: &Client,
+        connection: &mut (impl Future<Output = Result<(), Error>> + Unpin),
+    ) -> Result<Self, Error> {
+        let prepare = async {
+            let subscribe = client
+                .prepare_typed(
+                    "CALL subscribe($1, $2, $3)",
+                    &[Type::INT8, Type::VARCHAR, Type::VARCHAR],
+                )
+                .await?;
+
+            let unsubscribe = client
+                .prepare_typed("CALL unsubscribe($1, $2)", &[Type::INT8, Type::VARCHAR])
+                .await?;
+
+            let forget_chat = client
+                .prepare_typed("CALL forget_chat($1)", &[Type::INT8])
+                .await?;
+
+            let set_events = client
+                .prepare_typed(
+                    "SELECT set_events($1, $2, $3)",
+                    &[Type::INT8, Type::VARCHAR, Type::INT4],
+                )
+                .await?;
+
+            let list_subscribers = client
+                .prepare_typed(
+                    "SELECT user_id, filter, events, digest, language, toolchain \
+                     from list_subscribers($1, $2)",
+                    &[Type::VARCHAR, Type::INT4],
+                )
+                .await?;
+
+            let list_subscriptions = client
+                .prepare_typed(
+                    "SELECT crate_name, filter, events, manifest from list_subscriptions($1)",
+                    &[Type::INT8],
+                )
+                .await?;
+
+            let list_subscribed_crates = client
+                .prepare_typed("SELECT crate_name from list_subscribed_crates()", &[])
+                .await?;
+
+            let sync_manifest = client
+                .prepare_typed(
+                    "SELECT added, removed from sync_manifest($1, $2, $3)",
+                    &[Type::INT8, Type::VARCHAR, Type::VARCHAR_ARRAY],
+                )
+                .await?;
+
+            let sync_pins = client
+                .prepare_typed(
+                    "CALL sync_pins($1, $2, $3, $4)",
+                    &[
+                        Type::INT8,
+                        Type::VARCHAR,
+                        Type::VARCHAR_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                    ],
+                )
+                .await?;
+
+            let list_pinned = client
+                .prepare_typed(
+                    "SELECT user_id, project from list_pinned($1, $2)",
+                    &[Type::VARCHAR, Type::VARCHAR],
+                )
+                .await?;
+
+            let rename_crate = client
+                .prepare_typed("CALL rename_crate($1, $2)", &[Type::VARCHAR, Type::VARCHAR])
+                .await?;
+
+            let get_index_cursor = client
+                .prepare_typed(
+                    "SELECT commit_oid, processed_at from get_index_cursor()",
+                    &[],
+                )
+                .await?;
+
+            let set_index_cursor = client
+                .prepare_typed("CALL set_index_cursor($1)", &[Type::VARCHAR])
+                .await?;
+
+            let list_sparse_files = client
+                .prepare_typed(
+                    "SELECT crate_name, etag, last_modified, versions, yanked from list_sparse_files()",
+                    &[],
+                )
+                .await?;
+
+            let set_sparse_file = client
+                .prepare_typed(
+                    "CALL set_sparse_file($1, $2, $3, $4, $5)",
+                    &[
+                        Type::VARCHAR,
+                        Type::VARCHAR,
+                        Type::VARCHAR,
+                        Type::VARCHAR_ARRAY,
+                        Type::BOOL_ARRAY,
+                    ],
+                )
+                .await?;
+
+            let delete_sparse_file = client
+                .prepare_typed("CALL delete_sparse_file($1)", &[Type::VARCHAR])
+                .await?;
+
+            let add_skipped_commit = client
+                .prepare_typed(
+                    "CALL add_skipped_commit($1, $2, $3, $4)",
+                    &[Type::VARCHAR, Type::VARCHAR, Type::TEXT, Type::TEXT],
+                )
+                .await?;
+
+            let enqueue_messages = client
+                .prepare_typed(
+                    "CALL enqueue_messages($1, $2, $3, $4, $5, $6)",
+                    &[
+                        Type::INT8_ARRAY,
+                        Type::TEXT_ARRAY,
+                        Type::BOOL_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                        Type::BOOL_ARRAY,
+                        Type::TIMESTAMPTZ_ARRAY,
+                    ],
+                )
+                .await?;
+
+            let next_messages = client
+                .prepare_typed(
+                    "SELECT id, chat_id, payload, silent, attempts, coalesce from \
+                     next_messages($1, $2)",
+                    &[Type::INT8, Type::INT4],
+                )
+                .await?;
+
+            let list_coalesced = client
+                .prepare_typed(
+                    "SELECT id, chat_id, payload, silent, attempts, coalesce from \
+                     list_coalesced($1, $2, $3)",
+                    &[Type::INT8, Type::INT8, Type::INT4],
+                )
+                .await?;
+
+            let message_delivered = client
+                .prepare_typed("CALL message_delivered($1)", &[Type::INT8])
+                .await?;
+
+            let message_failed = client
+                .prepare_typed(
+                    "CALL message_failed($1, $2, $3)",
+                    &[Type::INT8, Type::TEXT, Type::TIMESTAMPTZ],
+                )
+                .await?;
+
+            let postpone_message = client
+                .prepare_typed(
+                    "CALL postpone_message($1, $2)",
+                    &[Type::INT8, Type::TIMESTAMPTZ],
+                )
+                .await?;
+
+            let delete_delivered_messages = client
+                .prepare_typed("CALL delete_delivered_messages($1)", &[Type::TIMESTAMPTZ])
+                .await?;
+
+            let set_delivery = client
+                .prepare_typed(
+                    "CALL set_delivery($1, $2, $3, $4)",
+                    &[Type::INT8, Type::VARCHAR, Type::INT4, Type::INT4],
+                )
+                .await?;
+
+            let get_delivery = client
+                .prepare_typed(
+                    "SELECT delivery, digest_minute, utc_offset_minutes from get_delivery($1)",
+                    &[Type::INT8],
+                )
+                .await?;
+
+            let set_language = client
+                .prepare_typed("CALL set_language($1, $2)", &[Type::INT8, Type::VARCHAR])
+                .await?;
+
+            let init_language = client
+                .prepare_typed("CALL init_language($1, $2)", &[Type::INT8, Type::VARCHAR])
+                .await?;
+
+            let get_language = client
+                .prepare_typed("SELECT language from get_language($1)", &[Type::INT8])
+                .await?;
+
+            let set_toolchain = client
+                .prepare_typed("CALL set_toolchain($1, $2)", &[Type::INT8, Type::VARCHAR])
+                .await?;
+
+            let get_toolchain = client
+                .prepare_typed("SELECT toolchain from get_toolchain($1)", &[Type::INT8])
+                .await?;
+
+            let set_typosquat_alerts = client
+                .prepare_typed(
+                    "CALL set_typosquat_alerts($1, $2)",
+                    &[Type::INT8, Type::BOOL],
+                )
+                .await?;
+
+            let get_typosquat_alerts = client
+                .prepare_typed(
+                    "SELECT typosquat_alerts from get_typosquat_alerts($1)",
+                    &[Type::INT8],
+                )
+                .await?;
+
+            let list_typosquat_watches = client
+                .prepare_typed(
+                    "SELECT user_id, crate_name, language from list_typosquat_watches()",
+                    &[],
+                )
+                .await?;
+
+            let set_dependencies = client
+                .prepare_typed(
+                    "CALL set_dependencies($1, $2, $3)",
+                    &[
+                        Type::VARCHAR_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                    ],
+                )
+                .await?;
+
+            let finish_reverse_deps = client
+                .prepare_typed(
+                    "CALL finish_reverse_deps($1, $2, $3)",
+                    &[
+                        Type::VARCHAR_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                    ],
+                )
+                .await?;
+
+            let has_reverse_deps = client
+                .prepare_typed("SELECT has_reverse_deps()", &[])
+                .await?;
+
+            let count_dependents = client
+                .prepare_typed("SELECT count_dependents($1)", &[Type::VARCHAR])
+                .await?;
+
+            let subscribe_dependents = client
+                .prepare_typed(
+                    "CALL subscribe_dependents($1, $2)",
+                    &[Type::INT8, Type::VARCHAR],
+                )
+                .await?;
+
+            let unsubscribe_dependents = client
+                .prepare_typed(
+                    "CALL unsubscribe_dependents($1, $2)",
+                    &[Type::INT8, Type::VARCHAR],
+                )
+                .await?;
+
+            let list_dependents_subscriptions = client
+                .prepare_typed(
+                    "SELECT crate_name, dependents from list_dependents_subscriptions($1)",
+                    &[Type::INT8],
+                )
+                .await?;
+
+            let list_dependents_subscribers = client
+                .prepare_typed(
+                    "SELECT user_id, dependencies, digest, language \
+                     from list_dependents_subscribers($1)",
+                    &[Type::VARCHAR],
+                )
+                .await?;
+
+            let record_update = client
+                .prepare_typed(
+                    "CALL record_update($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)",
+                    &[
+                        Type::VARCHAR,
+                        Type::VARCHAR,
+                        Type::VARCHAR,
+                        Type::INT8_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                        Type::INT8_ARRAY,
+                        Type::TEXT_ARRAY,
+                        Type::BOOL_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                        Type::BOOL_ARRAY,
+                        Type::TIMESTAMPTZ_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                        Type::VARCHAR_ARRAY,
+                    ],
+                )
+                .await?;
+
+            let count_new_migrations = client
+                .prepare_typed(
+                    "SELECT crates from count_new_migrations($1, $2, $3)",
+                    &[Type::VARCHAR, Type::VARCHAR_ARRAY, Type::VARCHAR_ARRAY],
+                )
+                .await?;
+
+            let count_migrations = client
+                .prepare_typed(
+                    "SELECT new_range, crates from count_migrations($1)",
+                    &[Type::VARCHAR],
+                )
+                .await?;
+
+            let watch_migrations = client
+                .prepare_typed(
+                    "CALL watch_migrations($1, $2)",
+                    &[Type::INT8, Type::VARCHAR],
+                )
+                .await?;
+
+            let unwatch_migrations = client
+                .prepare_typed(
+                    "CALL unwatch_migrations($1, $2)",
+                    &[Type::INT8, Type::VARCHAR],
+                )
+                .await?;
+
+            let list_migration_watches = client
+                .prepare_typed(
+                    "SELECT crate_name from list_migration_watches($1)",
+                    &[Type::INT8],
+                )
+                .await?;
+
+            let list_migration_watchers = client
+                .prepare_typed(
+                    "SELECT user_id, language from list_migration_watchers($1)",
+                    &[Type::VARCHAR],
+                )
+                .await?;
+
+            let watch_new = client
+                .prepare_typed("CALL watch_new($1, $2)", &[Type::INT8, Type::VARCHAR])
+                .await?;
+
+            let unwatch_new = client
+                .prepare_typed("SELECT unwatch_new($1, $2)", &[Type::INT8, Type::VARCHAR])
+                .await?;
+
+            let list_new_watches = client
+                .prepare_typed("SELECT pattern from list_new_watches($1)", &[Type::INT8])
+                .await?;
+
+            let list_new_crate_watchers = client
+                .prepare_typed(
+                    "SELECT user_id, pattern, language from list_new_crate_watchers()",
+                    &[],
+                )
+                .await?;
+
+            let due_digests = client
+                .prepare_typed("SELECT user_id from due_digests()", &[])
+                .await?;
+
+            let list_digest_items = client
+                .prepare_typed(
+                    "SELECT id, crate_name, vers, action from list_digest_items($1)",
+                    &[Type::INT8],
+                )
+                .await?;
+
+            let finish_digest = client
+                .prepare_typed("CALL finish_digest($1, $2)", &[Type::INT8, Type::INT8])
                 .await?;
 
             Ok(Self {
                 subscribe,
                 unsubscribe,
+                forget_chat,
+                set_events,
                 list_subscribers,
                 list_subscriptions,
+                list_subscribed_crates,
+                sync_manifest,
+                sync_pins,
+                list_pinned,
+                rename_crate,
+                get_index_cursor,
+                set_index_cursor,
+                list_sparse_files,
+                set_sparse_file,
+                delete_sparse_file,
+                add_skipped_commit,
+                enqueue_messages,
+                next_messages,
+                list_coalesced,
+                message_delivered,
+                message_failed,
+                postpone_message,
+                delete_delivered_messages,
+                set_delivery,
+                get_delivery,
+                set_language,
+                init_language,
+                get_language,
+                set_toolchain,
+                get_toolchain,
+                set_typosquat_alerts,
+                get_typosquat_alerts,
+                list_typosquat_watches,
+                set_dependencies,
+                finish_reverse_deps,
+                has_reverse_deps,
+                count_dependents,
+                subscribe_dependents,
+                unsubscribe_dependents,
+                list_dependents_subscriptions,
+                list_dependents_subscribers,
+                record_update,
+                count_new_migrations,
+                count_migrations,
+                watch_migrations,
+                unwatch_migrations,
+                list_migration_watches,
+                list_migration_watchers,
+                watch_new,
+                unwatch_new,
+                list_new_watches,
+                list_new_crate_watchers,
+                due_digests,
+                list_digest_items,
+                finish_digest,
             })
         };
 
@@ -0,0 +1,448 @@
+//! Digests: periodic summaries of updates instead of separate notifications.
+//!
+//! Updates for chats with [`Delivery::Hourly`] or [`Delivery::Daily`] are
+//! stored in the `digest_items` table (see [`Database::record_update`]).
+//! [`run`] periodically checks which digests are due, adds them to the outbox
+//! and only then removes the items, so digests survive restarts.
+use std::{fmt, str::FromStr, sync::Arc, time::Duration};
+
+use log::error;
+
+use crate::{
+    cfg::Config,
+    db::{Database, DigestItem, OutgoingMessage},
+    krate::ActionKind,
+    outbox::MAX_MESSAGE_LEN,
+    source::{wait, Stop},
+    template::{Args, Key, Templates},
+};
+
+/// Delay between checks of due digests.
+const CHECK_DELAY: Duration = Duration::from_secs(60);
+
+/// Default local time of daily digests (minutes after midnight).
+const DEFAULT_DIGEST_MINUTE: u16 = 9 * 60;
+
+/// How notifications are delivered to a chat.
+///
+/// Stored in the database as `delivery`, `digest_minute` and
+/// `utc_offset_minutes` columns (see [`Delivery::to_db`]).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    /// Every update is sent right away (`immediate`)
+    Immediate,
+    /// Updates are sent as a digest once an hour (`hourly`)
+    Hourly,
+    /// Updates are sent as a digest once a day at the given local time (e.g.
+    /// `daily 09:00 +03:00`)
+    Daily {
+        /// Minutes after midnight
+        minute: u16,
+        /// Offset of the local time from UTC in minutes
+        utc_offset: i16,
+    },
+}
+
+impl Delivery {
+    pub fn from_db(delivery: &str, digest_minute: i32, utc_offset_minutes: i32) -> Self {
+        match delivery {
+            "hourly" => Self::Hourly,
+            "daily" => Self::Daily {
+                minute: digest_minute as u16,
+                utc_offset: utc_offset_minutes as i16,
+            },
+            _ => Self::Immediate,
+        }
+    }
+
+    /// Returns `(delivery, digest_minute, utc_offset_minutes)`.
+    pub fn to_db(self) -> (&'static str, i32, i32) {
+        match self {
+            Self::Immediate => ("immediate", DEFAULT_DIGEST_MINUTE.into(), 0),
+            Self::Hourly => ("hourly", DEFAULT_DIGEST_MINUTE.into(), 0),
+            Self::Daily { minute, utc_offset } => ("daily", minute.into(), utc_offset.into()),
+        }
+    }
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        Self::Immediate
+    }
+}
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Immediate => f.write_str("immediate"),
+            Self::Hourly => f.write_str("hourly"),
+            Self::Daily { minute, utc_offset } => {
+                let sign = if utc_offset < 0 { '-' } else { '+' };
+                let offset = utc_offset.unsigned_abs();
+                write!(
+                    f,
+                    "daily {:02}:{:02} {}{:02}:{:02}",
+                    minute / 60,
+                    minute % 60,
+                    sign,
+                    offset / 60,
+                    offset % 60
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, derive_more::Display)]
+pub enum ParseDeliveryError {
+    #[display(fmt = "unknown delivery mode: {:?}", _0)]
+    UnknownMode(String),
+    #[display(fmt = "invalid time: {:?}", _0)]
+    InvalidTime(String),
+    #[display(fmt = "invalid UTC offset: {:?}", _0)]
+    InvalidOffset(String),
+    #[display(fmt = "too many arguments")]
+    TooManyArguments,
+}
+
+impl FromStr for Delivery {
+    type Err = ParseDeliveryError;
+
+    /// Parse `immediate`, `hourly` or `daily [HH:MM] [+HH:MM]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let res = match words.next().unwrap_or_default() {
+            "immediate" => Self::Immediate,
+            "hourly" => Self::Hourly,
+            "daily" => {
+                let minute = match words.next() {
+                    Some(time) => parse_time(time)
+                        .ok_or_else(|| ParseDeliveryError::InvalidTime(time.to_owned()))?,
+                    None => DEFAULT_DIGEST_MINUTE,
+                };
+                let utc_offset = match words.next() {
+                    Some(offset) => parse_offset(offset)
+                        .ok_or_else(|| ParseDeliveryError::InvalidOffset(offset.to_owned()))?,
+                    None => 0,
+                };
+
+                Self::Daily { minute, utc_offset }
+            }
+            mode => return Err(ParseDeliveryError::UnknownMode(mode.to_owned())),
+        };
+
+        match words.next() {
+            Some(_) => Err(ParseDeliveryError::TooManyArguments),
+            None => Ok(res),
+        }
+    }
+}
+
+/// Parse `HH:MM` into minutes after midnight.
+fn parse_time(s: &str) -> Option<u16> {
+    let (hours, minutes) = s.split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u16>().ok()?, minutes.parse::<u16>().ok()?);
+
+    if hours < 24 && minutes < 60 {
+        Some(hours * 60 + minutes)
+    } else {
+        None
+    }
+}
+
+/// Parse UTC offset like `+3`, `-05:30` or `UTC+2` into minutes.
+fn parse_offset(s: &str) -> Option<i16> {
+    let s = s.trim_start_matches("UTC").trim_start_matches("utc");
+    let (sign, s) = match s.strip_prefix('-') {
+        Some(s) => (-1, s),
+        None => (1, s.strip_prefix('+').unwrap_or(s)),
+    };
+
+    let (hours, minutes) = s.split_once(':').unwrap_or((s, "0"));
+    let (hours, minutes) = (hours.parse::<u16>().ok()?, minutes.parse::<u16>().ok()?);
+    if hours > 14 || minutes >= 60 {
+        return None;
+    }
+
+    // Real offsets are in -12:00..=+14:00
+    let offset = sign * (hours * 60 + minutes) as i16;
+    (-12 * 60..=14 * 60).contains(&offset).then(|| offset)
+}
+
+/// Add due digests to the outbox until `stop` is aborted.
+pub async fn run(db: Database, cfg: Arc<Config>, stop: Stop) {
+    while !stop.is_aborted() {
+        match db.due_digests().await {
+            Ok(chats) => {
+                for chat_id in chats {
+                    if let Err(err) = send_digest(&db, &cfg, chat_id).await {
+                        error!("db error while sending digest to {}: {}", chat_id, err);
+                    }
+                }
+            }
+            Err(err) => error!("db error while getting due digests: {}", err),
+        }
+
+        if !wait(CHECK_DELAY, &stop).await {
+            break;
+        }
+    }
+}
+
+async fn send_digest(
+    db: &Database,
+    cfg: &Config,
+    chat_id: i64,
+) -> Result<(), tokio_postgres::Error> {
+    let items: Vec<_> = db.list_digest_items(chat_id).await?.collect();
+    let last_item_id = match items.last() {
+        Some(item) => item.id,
+        None => return Ok(()),
+    };
+
+    // Dedup keys make it safe to enqueue the digest again if the bot is
+    // stopped before the items are removed
+    let language = db.get_language(chat_id).await?;
+    let templates = cfg.locales.get(language.as_deref());
+
+    let header = templates.render(Key::DigestHeader, &Args::new());
+    let messages: Vec<_> = split(&header, &format_items(&items, templates))
+        .into_iter()
+        .enumerate()
+        .map(|(i, payload)| OutgoingMessage {
+            chat_id,
+            payload,
+            silent: false,
+            dedup_key: format!("digest:{}:{}:{}", chat_id, last_item_id, i),
+            coalesce_window: None,
+        })
+        .collect();
+
+    db.enqueue_messages(&messages).await?;
+    db.finish_digest(chat_id, last_item_id).await
+}
+
+/// Format digest items grouped by crate, e.g.:
+///
+/// ```text
+/// tokio: 1.4.0 → 1.6.1 (3 releases), yanked 1.5.0
+/// ```
+fn format_items(items: &[DigestItem], templates: &Templates) -> Vec<String> {
+    // Keep crates in order of their first update
+    let mut crates: Vec<(&str, Vec<&DigestItem>)> = Vec::new();
+    for item in items {
+        match crates.iter_mut().find(|(name, _)| *name == item.crate_name) {
+            Some((_, crate_items)) => crate_items.push(item),
+            None => crates.push((&item.crate_name, vec![item])),
+        }
+    }
+
+    crates
+        .into_iter()
+        .map(|(name, items)| {
+            let versions = |action: ActionKind| -> Vec<&str> {
+                items
+                    .iter()
+                    .filter(|item| item.action.parse::<ActionKind>().ok() == Some(action))
+                    .map(|item| item.vers.as_str())
+                    .collect()
+            };
+
+            let mut parts = Vec::new();
+
+            match versions(ActionKind::NewVersion).as_slice() {
+                [] => {}
+                [vers] => parts.push(
+                    templates.render(Key::DigestReleased, &Args::new().text("version", vers)),
+                ),
+                all @ [first, .., last] => parts.push(
+                    templates.render(
+                        Key::DigestReleases,
+                        &Args::new()
+                            .text("first", first)
+                            .text("last", last)
+                            .text("count", all.len()),
+                    ),
+                ),
+            }
+
+            for (action, key) in [
+                (ActionKind::Yanked, Key::DigestYanked),
+                (ActionKind::Unyanked, Key::DigestUnyanked),
+                (ActionKind::Deleted, Key::DigestDeleted),
+            ]
+            .iter()
+            {
+                let versions = versions(*action);
+                if !versions.is_empty() {
+                    let args = Args::new().text("versions", versions.join(", "));
+                    parts.push(templates.render(*key, &args));
+                }
+            }
+
+            if !versions(ActionKind::CrateDeleted).is_empty() {
+                parts.push(templates.render(Key::DigestCrateDeleted, &Args::new()));
+            }
+
+            templates.render(
+                Key::DigestItem,
+                &Args::new()
+                    .text("crate", name)
+                    .markup("summary", parts.join(", ")),
+            )
+        })
+        .collect()
+}
+
+/// Join lines into messages that fit into telegram limits.
+fn split(header: &str, lines: &[String]) -> Vec<String> {
+    let header = format!("{}\n", header);
+
+    let mut messages = Vec::new();
+    let mut current = header.clone();
+    for line in lines {
+        if current.len() + line.len() + 1 > MAX_MESSAGE_LEN && current.len() > header.len() {
+            messages.push(current);
+            current = header.clone();
+        }
+
+        current.push('\n');
+        current.push_str(line);
+    }
+    messages.push(current);
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_items, parse_offset, split, Delivery};
+    use crate::{db::DigestItem, outbox::MAX_MESSAGE_LEN, template::Locales};
+
+    fn daily(minute: u16, utc_offset: i16) -> Delivery {
+        Delivery::Daily { minute, utc_offset }
+    }
+
+    #[test]
+    fn parse_delivery() {
+        assert_eq!(
+            "immediate".parse::<Delivery>().unwrap(),
+            Delivery::Immediate
+        );
+        assert_eq!("hourly".parse::<Delivery>().unwrap(), Delivery::Hourly);
+        assert_eq!("daily".parse::<Delivery>().unwrap(), daily(9 * 60, 0));
+        assert_eq!(
+            "daily 21:30".parse::<Delivery>().unwrap(),
+            daily(21 * 60 + 30, 0)
+        );
+        assert_eq!(
+            " daily  09:00 +03:00 ".parse::<Delivery>().unwrap(),
+            daily(9 * 60, 3 * 60)
+        );
+
+        assert!("weekly".parse::<Delivery>().is_err());
+        assert!("".parse::<Delivery>().is_err());
+        assert!("daily 24:00".parse::<Delivery>().is_err());
+        assert!("daily 9".parse::<Delivery>().is_err());
+        assert!("daily 09:00 +03:00 now".parse::<Delivery>().is_err());
+        assert!("hourly 09:00".parse::<Delivery>().is_err());
+    }
+
+    #[test]
+    fn delivery_roundtrip() {
+        for &delivery in [
+            Delivery::Immediate,
+            Delivery::Hourly,
+            daily(9 * 60, 0),
+            daily(23 * 60 + 59, -(5 * 60 + 30)),
+        ]
+        .iter()
+        {
+            assert_eq!(delivery.to_string().parse::<Delivery>().unwrap(), delivery);
+
+            let (mode, minute, offset) = delivery.to_db();
+            assert_eq!(Delivery::from_db(mode, minute, offset), delivery);
+        }
+
+        assert_eq!(
+            daily(23 * 60 + 59, -(5 * 60 + 30)).to_string(),
+            "daily 23:59 -05:30"
+        );
+    }
+
+    #[test]
+    fn utc_offsets() {
+        assert_eq!(parse_offset("+3"), Some(3 * 60));
+        assert_eq!(parse_offset("3"), Some(3 * 60));
+        assert_eq!(parse_offset("UTC+2"), Some(2 * 60));
+        assert_eq!(parse_offset("-05:30"), Some(-(5 * 60 + 30)));
+        assert_eq!(parse_offset("+14:00"), Some(14 * 60));
+        assert_eq!(parse_offset("-12"), Some(-12 * 60));
+
+        assert_eq!(parse_offset("-12:30"), None);
+        assert_eq!(parse_offset("-14"), None);
+        assert_eq!(parse_offset("+15"), None);
+        assert_eq!(parse_offset("+14:30"), None);
+        assert_eq!(parse_offset("+03:60"), None);
+        assert_eq!(parse_offset("Moscow"), None);
+    }
+
+    fn item(id: i64, crate_name: &str, vers: &str, action: &str) -> DigestItem {
+        DigestItem {
+            id,
+            crate_name: crate_name.to_owned(),
+            vers: vers.to_owned(),
+            action: action.to_owned(),
+        }
+    }
+
+    #[test]
+    fn items_are_grouped_by_crate() {
+        let locales = Locales::builtin();
+        let items = [
+            item(1, "tokio", "1.4.0", "new_version"),
+            item(2, "serde", "1.0.1", "new_version"),
+            item(3, "tokio", "1.5.0", "new_version"),
+            item(4, "tokio", "1.4.0", "yanked"),
+            item(5, "tokio", "1.6.1", "new_version"),
+            item(6, "old", "0.1.0", "crate_deleted"),
+        ];
+
+        assert_eq!(
+            format_items(&items, locales.get(Some("en"))),
+            [
+                "— <code>tokio</code>: 1.4.0 → 1.6.1 (3 releases), yanked 1.4.0",
+                "— <code>serde</code>: released 1.0.1",
+                "— <code>old</code>: the crate was deleted",
+            ]
+        );
+    }
+
+    #[test]
+    fn split_at_message_limit() {
+        // "h\n" + 2 * ("\n" + line) is exactly the limit
+        let line = "x".repeat((MAX_MESSAGE_LEN - 2) / 2 - 1);
+
+        let messages = split("h", &[line.clone(), line.clone()]);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].len(), MAX_MESSAGE_LEN);
+
+        let messages = split("h", &[line.clone(), line.clone(), line.clone()]);
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m.starts_with("h\n")));
+
+        // One byte more doesn't fit
+        let longer = format!("{}x", line);
+        let messages = split("h", &[line, longer]);
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m.len() <= MAX_MESSAGE_LEN));
+    }
+
+    #[test]
+    fn split_keeps_overlong_lines() {
+        let line = "x".repeat(MAX_MESSAGE_LEN);
+
+        let messages = split("h", &[line.clone()]);
+        assert_eq!(messages, [format!("h\n\n{}", line)]);
+    }
+}
@@ -0,0 +1,363 @@
+//! Filters of subscriptions.
+use std::{fmt, ops::BitOr, str::FromStr};
+
+use semver::{Version, VersionReq};
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{
+    krate::{ActionKind, Crate},
+    pattern::Pattern,
+    util::normalize_crate_name,
+};
+
+/// Filter of new versions a subscriber wants to be notified about (yanks,
+/// unyanks and deletions are never filtered).
+///
+/// Stored in the database in its textual form (see [`fmt::Display`] and
+/// [`FromStr`] impls).
+#[derive(Debug, Clone, PartialEq)]
+pub enum VersionFilter {
+    /// All versions (`all`)
+    All,
+    /// Only semver-incompatible versions (`major`), that is versions that
+    /// start a new compatibility range, e.g. `2.0.0`, `0.4.0` or `0.0.7`
+    Major,
+    /// All versions except pre-releases (`stable`)
+    Stable,
+    /// Versions matching a semver requirement (e.g. `>=1.0, <2`)
+    Req(VersionReq),
+}
+
+impl VersionFilter {
+    /// Returns `true` if the subscriber should be notified about `vers`.
+    pub fn matches(&self, vers: &str) -> bool {
+        let vers = match (self, Version::parse(vers)) {
+            (Self::All, _) => return true,
+            (_, Ok(vers)) => vers,
+            // Better to notify about something weird than to silently skip it
+            (_, Err(_)) => return true,
+        };
+
+        match self {
+            Self::All => true,
+            Self::Major => is_breaking(&vers),
+            Self::Stable => vers.pre.is_empty(),
+            Self::Req(req) => req.matches(&vers),
+        }
+    }
+}
+
+impl Default for VersionFilter {
+    fn default() -> Self {
+        Self::All
+    }
+}
+
+impl fmt::Display for VersionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::All => f.write_str("all"),
+            Self::Major => f.write_str("major"),
+            Self::Stable => f.write_str("stable"),
+            Self::Req(req) => fmt::Display::fmt(req, f),
+        }
+    }
+}
+
+impl FromStr for VersionFilter {
+    type Err = semver::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "all" => Ok(Self::All),
+            "major" => Ok(Self::Major),
+            "stable" => Ok(Self::Stable),
+            req => VersionReq::parse(req).map(Self::Req),
+        }
+    }
+}
+
+/// Set of kinds of updates a subscriber wants to be notified about.
+///
+/// Stored in the database as a bitmask (see [`EventMask::bits`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventMask(i32);
+
+impl EventMask {
+    /// New versions (`releases`)
+    pub const RELEASES: Self = Self(1);
+    /// Yanked versions (`yanks`)
+    pub const YANKS: Self = Self(1 << 1);
+    /// Unyanked versions (`unyanks`)
+    pub const UNYANKS: Self = Self(1 << 2);
+    /// Deleted versions and crates (`deletions`)
+    pub const DELETIONS: Self = Self(1 << 3);
+    /// All of the above (`all`)
+    pub const ALL: Self = Self(0b1111);
+    /// Only new versions that raise MSRV (`msrv`), see
+    /// [`crate::msrv::MsrvAlert`]. Implied by [`EventMask::RELEASES`].
+    pub const MSRV: Self = Self(1 << 4);
+
+    const NAMES: [(Self, &'static str); 5] = [
+        (Self::RELEASES, "releases"),
+        (Self::YANKS, "yanks"),
+        (Self::UNYANKS, "unyanks"),
+        (Self::DELETIONS, "deletions"),
+        (Self::MSRV, "msrv"),
+    ];
+
+    /// Mask of a single kind of updates.
+    pub fn of(action: ActionKind) -> Self {
+        match action {
+            ActionKind::NewVersion => Self::RELEASES,
+            ActionKind::Yanked => Self::YANKS,
+            ActionKind::Unyanked => Self::UNYANKS,
+            ActionKind::Deleted | ActionKind::CrateDeleted => Self::DELETIONS,
+        }
+    }
+
+    pub fn from_bits(bits: i32) -> Self {
+        Self(bits & (Self::ALL | Self::MSRV).0)
+    }
+
+    pub fn bits(self) -> i32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Default for EventMask {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl BitOr for EventMask {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl fmt::Display for EventMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Self::ALL {
+            return f.write_str("all");
+        }
+
+        let names: Vec<_> = Self::NAMES
+            .iter()
+            .filter(|(mask, _)| self.contains(*mask))
+            .map(|(_, name)| *name)
+            .collect();
+
+        f.write_str(&names.join(", "))
+    }
+}
+
+impl<'de> Deserialize<'de> for EventMask {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, derive_more::Display)]
+#[display(fmt = "unknown kind of updates: {:?}", _0)]
+pub struct UnknownEvent(String);
+
+impl FromStr for EventMask {
+    type Err = UnknownEvent;
+
+    /// Parse comma or whitespace separated list of kinds (e.g.
+    /// `yanks, unyanks`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|name| !name.is_empty())
+            .try_fold(Self(0), |mask, name| {
+                let kind = match name {
+                    "all" => Self::ALL,
+                    name => Self::NAMES
+                        .iter()
+                        .find(|(_, n)| *n == name)
+                        .map(|(mask, _)| *mask)
+                        .ok_or_else(|| UnknownEvent(name.to_owned()))?,
+                };
+
+                Ok(mask | kind)
+            })
+            .and_then(|mask| match mask {
+                Self(0) => Err(UnknownEvent(s.to_owned())),
+                // Releases raising MSRV are releases too
+                mask if mask.contains(Self::RELEASES) => Ok(Self(mask.0 & !Self::MSRV.0)),
+                mask => Ok(mask),
+            })
+    }
+}
+
+/// Rule of a broadcast channel, deciding which updates are posted to it.
+///
+/// All conditions must hold, the default rule allows everything.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChannelRule {
+    /// Only crates with names matching any of these patterns
+    #[serde(default)]
+    pub names: Vec<Pattern>,
+    /// Only crates which depend on any of these crates (dev-dependencies are
+    /// not counted)
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Kinds of updates
+    #[serde(default)]
+    pub events: EventMask,
+    /// Whether to post pre-releases
+    #[serde(default = "default_prereleases")]
+    pub prereleases: bool,
+}
+
+impl ChannelRule {
+    pub fn matches(&self, krate: &Crate, action: ActionKind) -> bool {
+        let name_matches =
+            self.names.is_empty() || self.names.iter().any(|p| p.matches(&krate.id.name));
+
+        let deps_match = self.depends_on.is_empty() || {
+            let deps = krate.dependency_names();
+            self.depends_on
+                .iter()
+                .any(|name| deps.contains(&normalize_crate_name(name)))
+        };
+
+        let prerelease_matches = self.prereleases || VersionFilter::Stable.matches(&krate.id.vers);
+
+        name_matches
+            && deps_match
+            && self.events.contains(EventMask::of(action))
+            && prerelease_matches
+    }
+}
+
+impl Default for ChannelRule {
+    fn default() -> Self {
+        Self {
+            names: Vec::new(),
+            depends_on: Vec::new(),
+            events: EventMask::ALL,
+            prereleases: default_prereleases(),
+        }
+    }
+}
+
+const fn default_prereleases() -> bool {
+    true
+}
+
+/// Returns `true` if `vers` is the first version of its semver-compatibility
+/// range (note that for `0.x` versions minor bump is breaking and for `0.0.x`
+/// versions every bump is breaking).
+fn is_breaking(vers: &Version) -> bool {
+    matches!(
+        vers,
+        Version {
+            major: 0,
+            minor: 0,
+            ..
+        } | Version {
+            major: 0,
+            patch: 0,
+            ..
+        } | Version {
+            minor: 0,
+            patch: 0,
+            ..
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventMask, VersionFilter};
+
+    fn matches(filter: &str, vers: &str) -> bool {
+        filter.parse::<VersionFilter>().unwrap().matches(vers)
+    }
+
+    #[test]
+    fn parse_version_filter() {
+        assert_eq!("all".parse::<VersionFilter>().unwrap(), VersionFilter::All);
+        assert_eq!(
+            " major ".parse::<VersionFilter>().unwrap(),
+            VersionFilter::Major
+        );
+        assert_eq!(
+            "stable".parse::<VersionFilter>().unwrap(),
+            VersionFilter::Stable
+        );
+        assert!(matches!(
+            ">=1.0, <2".parse::<VersionFilter>().unwrap(),
+            VersionFilter::Req(_)
+        ));
+        assert!("latest".parse::<VersionFilter>().is_err());
+    }
+
+    #[test]
+    fn major_releases() {
+        assert!(matches("major", "2.0.0"));
+        assert!(!matches("major", "2.1.0"));
+        assert!(!matches("major", "2.0.1"));
+
+        // For 0.x minor bumps are breaking
+        assert!(matches("major", "0.4.0"));
+        assert!(!matches("major", "0.4.1"));
+
+        // For 0.0.x every bump is breaking
+        assert!(matches("major", "0.0.7"));
+    }
+
+    #[test]
+    fn prereleases() {
+        assert!(!matches("stable", "1.0.0-alpha.1"));
+        assert!(matches("stable", "1.0.0"));
+        assert!(matches("all", "1.0.0-alpha.1"));
+
+        // Pre-releases match requirements only if they ask for pre-releases
+        assert!(!matches(">=1.0", "1.1.0-rc.1"));
+        assert!(matches(">=1.1.0-rc.0", "1.1.0-rc.1"));
+    }
+
+    #[test]
+    fn unparseable_versions_match() {
+        assert!(matches("major", "not-a-version"));
+        assert!(matches(">=1.0", "not-a-version"));
+    }
+
+    #[test]
+    fn parse_event_mask() {
+        let mask = "yanks, unyanks".parse::<EventMask>().unwrap();
+        assert_eq!(mask, EventMask::YANKS | EventMask::UNYANKS);
+        assert_eq!(mask.to_string(), "yanks, unyanks");
+
+        assert_eq!("all".parse::<EventMask>().unwrap(), EventMask::ALL);
+        assert_eq!(EventMask::ALL.to_string(), "all");
+
+        // Releases imply releases raising MSRV
+        assert_eq!(
+            "releases msrv".parse::<EventMask>().unwrap(),
+            EventMask::RELEASES
+        );
+        assert_eq!("msrv".parse::<EventMask>().unwrap(), EventMask::MSRV);
+
+        assert!("".parse::<EventMask>().is_err());
+        assert!("yanks, forks".parse::<EventMask>().is_err());
+    }
+
+    #[test]
+    fn event_mask_bits() {
+        assert_eq!(EventMask::from_bits(EventMask::ALL.bits()), EventMask::ALL);
+        // Unknown bits are ignored
+        assert_eq!(EventMask::from_bits((1 << 10) | 1), EventMask::RELEASES);
+    }
+}
@@ -1,18 +1,114 @@
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use serde_json::{Map, Value};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    str::FromStr,
+};
 use tokio::{
     fs::File,
     io,
     io::{AsyncBufReadExt, BufReader},
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::util::normalize_crate_name;
+
+/// An entry (line) of the crates.io index.
+///
+/// See <https://doc.rust-lang.org/cargo/reference/registries.html#index-format>
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Crate {
-    // TODO: stole from crates.io repo?
     #[serde(flatten)]
     pub id: CrateId,
+    /// Direct dependencies of the version
+    #[serde(default)]
+    pub deps: Vec<Dependency>,
+    /// SHA256 checksum of the `.crate` file
+    #[serde(default)]
+    pub cksum: String,
+    /// Features of the version
+    #[serde(default)]
+    pub features: BTreeMap<String, Vec<String>>,
+    /// Features with new syntax (`dep:` and `?`), kept separate for older
+    /// cargo versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub features2: Option<BTreeMap<String, Vec<String>>>,
     pub yanked: bool,
-    // ignore all unrelated stuff :D
+    /// Value of the `links` field of the manifest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub links: Option<String>,
+    /// Minimal supported rust version (MSRV)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rust_version: Option<String>,
+    /// Version of the schema of this entry (`None` means 1)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<u32>,
+    /// Fields unknown to the bot, preserved to not lose information when
+    /// re-serializing
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+/// A dependency of a crate version.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dependency {
+    /// Name of the dependency, may be a rename (see [`Dependency::package`])
+    pub name: String,
+    /// Version requirement
+    pub req: String,
+    /// Features enabled for the dependency
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub optional: bool,
+    #[serde(default = "default_features")]
+    pub default_features: bool,
+    /// Target platform (e.g. `cfg(windows)`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// `normal`, `build` or `dev` (`None` means normal dependency), kept as a
+    /// string so kinds unknown to the bot are preserved (see
+    /// [`Dependency::kind`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    /// Registry index url, `None` means crates.io
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+    /// The real name of the dependency, if it was renamed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+    /// Fields unknown to the bot
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DependencyKind {
+    Normal,
+    Build,
+    Dev,
+    /// A kind added to the index after the bot was written
+    Unknown,
+}
+
+impl Dependency {
+    /// The real name of the dependency (accounting for renames).
+    pub fn crate_name(&self) -> &str {
+        self.package.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn kind(&self) -> DependencyKind {
+        match self.kind.as_deref() {
+            None | Some("normal") => DependencyKind::Normal,
+            Some("build") => DependencyKind::Build,
+            Some("dev") => DependencyKind::Dev,
+            Some(_) => DependencyKind::Unknown,
+        }
+    }
+}
+
+const fn default_features() -> bool {
+    true
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Serialize, Deserialize)]
@@ -21,6 +117,49 @@ pub struct CrateId {
     pub vers: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionKind {
+    NewVersion,
+    Yanked,
+    Unyanked,
+    /// A single version was deleted from the index
+    Deleted,
+    /// The whole crate was deleted from the index
+    CrateDeleted,
+}
+
+impl ActionKind {
+    /// Name of the action as used in (de)serialization, e.g. `new_version`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NewVersion => "new_version",
+            Self::Yanked => "yanked",
+            Self::Unyanked => "unyanked",
+            Self::Deleted => "deleted",
+            Self::CrateDeleted => "crate_deleted",
+        }
+    }
+}
+
+impl FromStr for ActionKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Self::NewVersion,
+            Self::Yanked,
+            Self::Unyanked,
+            Self::Deleted,
+            Self::CrateDeleted,
+        ]
+        .iter()
+        .copied()
+        .find(|action| action.as_str() == s)
+        .ok_or(())
+    }
+}
+
 impl Crate {
     // TODO: struct: Display
 
@@ -46,24 +185,144 @@ impl Crate {
         )
     }
 
-    pub fn html_links(&self) -> String {
-        format!(
-            "<a href='{docs}'>[docs.rs]</a> <a href='{crates}'>[crates.io]</a> <a \
-             href='{lib}'>[lib.rs]</a>",
-            docs = self.docsrs(),
-            crates = self.cratesio(),
-            lib = self.librs(),
-        )
+    /// Normalized names of crates this version depends on (dev-dependencies
+    /// are not counted).
+    pub fn dependency_names(&self) -> BTreeSet<String> {
+        self.deps
+            .iter()
+            .filter(|dep| dep.kind() != DependencyKind::Dev)
+            .map(|dep| normalize_crate_name(dep.crate_name()))
+            .collect()
     }
 
+    /// Read the last line of a crate file, that is the latest published
+    /// version.
+    ///
+    /// Returns an error if the file is empty.
     pub async fn read_last(path: &Path) -> io::Result<Self> {
         let file = File::open(path).await?;
         let mut lines = BufReader::new(file).lines();
         let mut last = None;
-        while let next @ Some(_) = lines.next_line().await? {
-            last = next
+        while let Some(line) = lines.next_line().await? {
+            if !line.is_empty() {
+                last = Some(line);
+            }
         }
-        serde_json::from_str(&last.unwrap())
+
+        let last = last.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "empty crate file")
+        })?;
+        serde_json::from_str(&last)
             .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
     }
+
+    /// Read the version published right before `vers`: the previous line of
+    /// the file or, if the file doesn't have `vers` yet (e.g. the local git
+    /// index is fast-forwarded only after the commit is processed), the last
+    /// line.
+    ///
+    /// Returns `None` if `vers` is the first version.
+    pub async fn read_previous(path: &Path, vers: &str) -> io::Result<Option<Self>> {
+        let file = File::open(path).await?;
+        let mut lines = BufReader::new(file).lines();
+        let mut previous = None;
+        while let Some(line) = lines.next_line().await? {
+            let krate: Self = serde_json::from_str(&line)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+            if krate.id.vers == vers {
+                break;
+            }
+            previous = Some(krate);
+        }
+
+        Ok(previous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::Value;
+
+    use super::{Crate, DependencyKind};
+
+    #[test]
+    fn roundtrip() {
+        let line = r#"{
+            "name": "foo",
+            "vers": "1.0.0",
+            "deps": [
+                {
+                    "name": "bar",
+                    "req": "^1",
+                    "features": ["std"],
+                    "optional": true,
+                    "default_features": false,
+                    "target": "cfg(windows)",
+                    "kind": "build",
+                    "package": "bar-rs",
+                    "public": true
+                },
+                {
+                    "name": "baz",
+                    "req": "^0.2",
+                    "features": [],
+                    "optional": false,
+                    "default_features": true,
+                    "kind": "artifact",
+                    "artifact": ["bin"]
+                }
+            ],
+            "cksum": "abcd",
+            "features": {"default": ["bar"]},
+            "features2": {"async": ["dep:baz"]},
+            "yanked": false,
+            "links": "foo-sys",
+            "rust_version": "1.60",
+            "v": 2,
+            "pubtime": "2024-01-01T00:00:00Z"
+        }"#;
+
+        let krate: Crate = serde_json::from_str(line).unwrap();
+        assert_eq!(krate.deps[0].kind(), DependencyKind::Build);
+        assert_eq!(krate.deps[0].crate_name(), "bar-rs");
+        assert_eq!(krate.deps[1].kind(), DependencyKind::Unknown);
+        assert_eq!(krate.other["pubtime"], "2024-01-01T00:00:00Z");
+
+        let json = serde_json::to_value(&krate).unwrap();
+        assert_eq!(json, serde_json::from_str::<Value>(line).unwrap());
+    }
+
+    #[test]
+    fn roundtrip_without_optional_fields() {
+        let line = r#"{"name":"foo","vers":"0.1.0","deps":[{"name":"bar","req":"*","features":[],"optional":false,"default_features":true}],"cksum":"","features":{},"yanked":true}"#;
+
+        let krate: Crate = serde_json::from_str(line).unwrap();
+        assert_eq!(krate.deps[0].kind(), DependencyKind::Normal);
+
+        // No `null`s are added
+        assert_eq!(serde_json::to_string(&krate).unwrap(), line);
+    }
+
+    #[tokio::test]
+    async fn read_last_line() {
+        let dir = std::env::temp_dir().join(format!("crate_upd_bot-last-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("foo");
+        fs::write(
+            &path,
+            "{\"name\":\"foo\",\"vers\":\"0.1.0\",\"yanked\":false}\n\
+             {\"name\":\"foo\",\"vers\":\"0.2.0\",\"yanked\":false}\n\n",
+        )
+        .unwrap();
+        assert_eq!(Crate::read_last(&path).await.unwrap().id.vers, "0.2.0");
+
+        let empty = dir.join("empty");
+        fs::write(&empty, "").unwrap();
+        assert!(Crate::read_last(&empty).await.is_err());
+
+        assert!(Crate::read_last(&dir.join("missing")).await.is_err());
+    }
 }
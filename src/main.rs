@@ -1,34 +1,51 @@
-// TODO: somehow better handle rate-limits (https://core.telegram.org/bots/faq#broadcasting-to-users)
-//       maybe concat many messages into one (in channel) + queues to properly
-//       handle limits
-
-// When index colapses, use `git reset --hard origin/master`
-use std::{convert::Infallible, iter, sync::Arc, time::Duration};
-
-use arraylib::Slice;
-use either::Either::{Left, Right};
-use fntools::{self, value::ValueExt};
-use futures::future::{self, pending};
-use git2::{Commit, Delta, Diff, DiffOptions, Repository, Sort};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+    sync::Arc,
+};
+
+use futures::{
+    future::{self, pending},
+    StreamExt,
+};
 use log::{error, info, warn};
-use std::str;
 use teloxide::{
     adaptors::{AutoSend, DefaultParseMode},
     prelude::*,
-    types::ParseMode,
 };
-use tokio::sync::{
-    mpsc::{self, Sender},
-    oneshot,
+use tokio_postgres::{Error as DbError, NoTls};
+
+use crate::{
+    cfg::SourceConfig,
+    changes::Changes,
+    db::{Database, OutgoingMessage, Subscriber},
+    filter::{EventMask, VersionFilter},
+    krate::{ActionKind, Crate},
+    migration::Migration,
+    msrv::{MsrvAlert, RustVersion},
+    pattern::PatternCache,
+    source::{GitSource, ReplaySource, SparseSource, Update, UpdateSource},
+    template::{Args, Key, Templates},
+    typosquat::Resemblance,
+    util::{find_crate_file, tryn},
 };
-use tokio_postgres::NoTls;
-
-use crate::{db::Database, krate::Crate, util::tryn};
 
 mod bot;
 mod cfg;
+mod changes;
 mod db;
+mod digest;
+mod filter;
 mod krate;
+mod manifest;
+mod migration;
+mod msrv;
+mod outbox;
+mod pattern;
+mod scheduler;
+mod source;
+mod template;
+mod typosquat;
 mod util;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -70,296 +87,767 @@ async fn main() {
         d
     };
 
-    let index_url = &config.index_url; // Closures still borrow full struct :|
-    let index_path = &config.index_path;
-    let repo = Repository::open(index_path).unwrap_or_else(move |_| {
-        info!("start cloning");
-        Repository::clone(index_url, index_path)
-            .unwrap()
-            .also(|_| info!("cloning finished"))
-    });
+    canonicalize_crate_names(&db, &config).await;
+
+    let source: Box<dyn UpdateSource> = match &config.source {
+        SourceConfig::Git => Box::new(GitSource::open(
+            &config.index_url,
+            &config.index_path,
+            db.clone(),
+            config.pull_delay,
+        )),
+        SourceConfig::Sparse { url } => Box::new(SparseSource::new(
+            url,
+            Path::new(&config.index_path),
+            config.http.clone(),
+            db.clone(),
+            config.pull_delay,
+        )),
+        SourceConfig::Replay { path } => Box::new(ReplaySource::new(path)),
+    };
 
     let (abortable, abort_handle) = future::abortable(pending::<()>());
-
-    let (tx, mut rx) = mpsc::channel(2);
-    let git2_th = {
-        let pull_delay = config.pull_delay;
-        std::thread::spawn(move || {
-            'outer: loop {
-                info!("start pulling updates");
-
-                if let Err(err) = pull(&repo, tx.clone()) {
-                    error!("couldn't pull new crate version from the index: {}", err);
-                }
-
-                info!("pulling updates finished");
-
-                // delay for `config.pull_delay` (default 5 min)
-                let mut pd = pull_delay;
-                const STEP: Duration = Duration::from_secs(5);
-
-                while pd > Duration::ZERO {
-                    if abortable.is_aborted() {
-                        break 'outer;
-                    }
-
-                    pd = pd.saturating_sub(STEP);
-                    std::thread::sleep(STEP);
-                }
-            }
-        })
-    };
+    let mut updates = source.updates(abortable);
 
     let bot = teloxide::Bot::new(&config.bot_token)
-        .parse_mode(ParseMode::Html)
+        .parse_mode(config.locales.format().parse_mode())
         .auto_send();
 
+    let (outbox_stop, outbox_abort_handle) = future::abortable(pending::<()>());
+    let outbox_loop = outbox::run(bot.clone(), db.clone(), Arc::clone(&config), outbox_stop);
+
+    let (digest_stop, digest_abort_handle) = future::abortable(pending::<()>());
+    let digest_loop = digest::run(db.clone(), Arc::clone(&config), digest_stop);
+
     let notify_loop = async {
-        while let Some((krate, action, _unblock)) = rx.recv().await {
-            notify(krate, action, &bot, &db, &config).await;
+        let new_crate_patterns = PatternCache::default();
+
+        while let Some(update) = updates.next().await {
+            // The update is acknowledged only after notifications are in the
+            // outbox, so they are not lost if the bot is stopped
+            let res = tryn(5, config.retry_delay.0, || {
+                notify(&update, &db, &config, &new_crate_patterns)
+            })
+            .await;
+            if let Err(err) = res {
+                error!(
+                    "db error while adding notifications about {:?} to the outbox, they are lost: {}",
+                    update.krate.id, err
+                );
+            }
 
-            // implicitly unblock git2 thread by dropping `_unblock`
+            // implicitly unblock the source by dropping `update`
         }
 
-        // `recv()` returned `None` => `tx` was dropped => `git2_th` was stopped
+        // `next()` returned `None` => the source was exhausted or stopped
         // => `abort_handle.abort()` was probably called
     };
 
     let tg_loop = async {
         bot::run(bot.clone(), db.clone(), Arc::clone(&config)).await;
 
-        // When bot stopped executing (e.g. because of ^C) stop pull loop
+        // When bot stopped executing (e.g. because of ^C) stop pull loop and
+        // delivery of notifications
         abort_handle.abort();
+        outbox_abort_handle.abort();
+        digest_abort_handle.abort();
     };
 
-    tokio::join!(notify_loop, tg_loop);
+    tokio::join!(notify_loop, outbox_loop, digest_loop, tg_loop);
+}
 
-    git2_th.join().unwrap();
+/// Fix spelling of subscribed crates' names that were stored as typed by
+/// users (e.g. `Serde_JSON` instead of `serde_json`).
+async fn canonicalize_crate_names(db: &Database, cfg: &cfg::Config) {
+    let names = match db.list_subscribed_crates().await {
+        Ok(names) => names,
+        Err(err) => {
+            error!("db error while getting subscribed crates: {}", err);
+            return;
+        }
+    };
+
+    for name in names {
+        let path = match find_crate_file(Path::new(&cfg.index_path), &name) {
+            Some(path) => path,
+            None => continue,
+        };
+
+        let canonical = match Crate::read_last(&path).await {
+            Ok(krate) => krate.id.name,
+            Err(err) => {
+                warn!("couldn't read crate file {:?}: {}", path, err);
+                continue;
+            }
+        };
+
+        if canonical != name {
+            info!("renaming crate {:?} to {:?}", name, canonical);
+            if let Err(err) = db.rename_crate(&name, &canonical).await {
+                error!("db error while renaming crate {:?}: {}", name, err);
+            }
+        }
+    }
 }
 
-/// Fast-Forward (FF) to a given commit.
+/// Add notifications about an update to the outbox.
 ///
-/// Implementation is taken from <https://stackoverflow.com/a/58778350>.
-fn fast_forward(repo: &Repository, commit: &git2::Commit) -> Result<(), git2::Error> {
-    let fetch_commit = repo.find_annotated_commit(commit.id())?;
-    let analysis = repo.merge_analysis(&[&fetch_commit])?;
-
-    if analysis.0.is_up_to_date() {
-        Ok(())
-    } else if analysis.0.is_fast_forward() {
-        let mut reference = repo.find_reference("refs/heads/master")?;
-        reference.set_target(fetch_commit.id(), "Fast-Forward")?;
-        repo.set_head(reference.name().unwrap())?;
-        repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))
-    } else {
-        Err(git2::Error::from_str("Fast-forward only!"))
+/// Messages are delivered later by [`outbox::run`].
+async fn notify(
+    update: &Update,
+    db: &Database,
+    cfg: &cfg::Config,
+    new_crate_patterns: &PatternCache,
+) -> Result<(), DbError> {
+    let Update {
+        krate,
+        action,
+        origin,
+        new_crate,
+        ..
+    } = update;
+    let action = *action;
+
+    let previous = match (action, &update.previous) {
+        (ActionKind::NewVersion, Some(previous)) => Some(previous.clone()),
+        // The source doesn't know the previous version, look it up in the
+        // local index
+        (ActionKind::NewVersion, None) => previous_version(krate, cfg).await,
+        _ => None,
+    };
+
+    let events = match action {
+        // Whether the release raises MSRV depends on the chat's toolchain, so
+        // these subscribers are checked by `notifications`
+        ActionKind::NewVersion => EventMask::RELEASES | EventMask::MSRV,
+        action => EventMask::of(action),
+    };
+    let subscribers = db.list_subscribers(&krate.id.name, events.bits()).await?;
+    let dependents: Vec<_> = match action {
+        ActionKind::NewVersion => db
+            .list_dependents_subscribers(&krate.id.name)
+            .await?
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let Notifications {
+        mut messages,
+        digest,
+    } = notifications(update, previous.as_ref(), subscribers, dependents, cfg);
+
+    // Alerts are important enough to ignore digest mode
+    if let ActionKind::Yanked = action {
+        messages.extend(pinned_alerts(krate, origin, db, cfg).await?);
     }
+    let migrations = previous
+        .as_ref()
+        .map(|previous| Migration::between(previous, krate))
+        .unwrap_or_default();
+    messages.extend(migration_alerts(krate, &migrations, origin, db, cfg).await?);
+    if *new_crate {
+        messages.extend(new_crate_alerts(krate, origin, db, cfg, new_crate_patterns).await?);
+        messages.extend(typosquat_alerts(krate, origin, db, cfg).await?);
+    }
+
+    // Everything is written at once, so a retry after an error doesn't see
+    // a half-written update
+    let migrations: Vec<_> = migrations
+        .iter()
+        .map(|m| {
+            (
+                m.dependency.as_str(),
+                m.old_range.to_string(),
+                m.new_range.to_string(),
+            )
+        })
+        .collect();
+    db.record_update(
+        &krate.id.name,
+        &krate.id.vers,
+        action.as_str(),
+        &digest,
+        &messages,
+        &migrations,
+    )
+    .await
 }
 
-fn pull(
-    repo: &Repository,
-    ch: Sender<(Crate, ActionKind, oneshot::Sender<Infallible>)>,
-) -> Result<(), git2::Error> {
-    // fetch changes from remote index
-    repo.find_remote("origin")?.fetch(&["master"], None, None)?;
-
-    // Collect all commits in the range `HEAD~1..FETCH_HEAD` (i.e. one before
-    // currently checked out to the last fetched)
-    let mut walk = repo.revwalk()?;
-    walk.push_range("HEAD~1..FETCH_HEAD")?;
-    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
-    let commits: Result<Vec<_>, _> = walk.map(|oid| repo.find_commit(oid?)).collect();
-
-    let mut opts = DiffOptions::default();
-    let opts = opts.context_lines(0).minimal(true);
-
-    for [prev, next] in Slice::array_windows::<[_; 2]>(&commits?[..]) {
-        // Commits from humans tend to be formatted differently, compared to
-        // machine-generated ones. This basically makes them unanalyzable.
-        if next.author().name() != Some("bors") {
-            warn!(
-                "Skip commit#{} from non-bors user @{}: {}",
-                next.id(),
-                next.author().name().unwrap_or("<invalid utf-8>"),
-                next.message()
-                    .unwrap_or("<invalid utf-8>")
-                    .trim_end_matches('\n'),
-            );
+/// Notifications about an update, see [`notifications`].
+#[derive(Debug, Default)]
+struct Notifications {
+    messages: Vec<OutgoingMessage>,
+    /// `(chat, dedup key)` of chats which receive the update in a digest
+    digest: Vec<(i64, String)>,
+}
+
+/// Build notifications about an update for channels, `subscribers` of the
+/// crate and `dependents` subscribers (see
+/// [`Database::list_dependents_subscribers`]).
+///
+/// `previous` is the version published before the updated one (known only
+/// for new versions).
+fn notifications(
+    update: &Update,
+    previous: Option<&Crate>,
+    subscribers: impl IntoIterator<Item = Subscriber>,
+    dependents: impl IntoIterator<Item = (i64, String, bool, Option<String>)>,
+    cfg: &cfg::Config,
+) -> Notifications {
+    let Update {
+        krate,
+        action,
+        origin,
+        ..
+    } = update;
+    let action = *action;
+
+    let changes = previous.map(|previous| Changes::between(previous, krate));
+
+    let msrv_alert = |toolchain: Option<RustVersion>| match action {
+        ActionKind::NewVersion => MsrvAlert::check(krate, previous, toolchain),
+        _ => None,
+    };
+
+    // The message is rendered once per language & MSRV alert
+    let mut rendered = HashMap::new();
+    let mut message = |language: Option<&str>, msrv: Option<MsrvAlert>| -> String {
+        rendered
+            .entry((language.map(str::to_owned), msrv.clone()))
+            .or_insert_with(|| {
+                let templates = cfg.locales.get(language);
+                render_update(
+                    krate,
+                    action,
+                    previous.zip(changes.as_ref()),
+                    msrv.as_ref(),
+                    templates,
+                )
+            })
+            .clone()
+    };
+
+    let dedup_key = |chat_id: i64| {
+        format!(
+            "{}:{:?}:{}#{}:{}",
+            origin, action, krate.id.name, krate.id.vers, chat_id
+        )
+    };
+
+    let mut res = Notifications::default();
+
+    if !cfg.ban.crates.contains(krate.id.name.as_str()) {
+        for channel in cfg
+            .channels
+            .iter()
+            .filter(|ch| ch.rule.matches(krate, action))
+        {
+            res.messages.push(OutgoingMessage {
+                chat_id: channel.id,
+                payload: message(channel.language.as_deref(), msrv_alert(None)),
+                silent: true,
+                dedup_key: dedup_key(channel.id),
+                coalesce_window: Some(cfg.channel_window),
+            });
+        }
+    }
 
+    let mut notified = HashSet::new();
+
+    for user in subscribers {
+        let Subscriber {
+            chat_id,
+            filter,
+            events,
+            digest,
+            language,
+            toolchain,
+        } = user;
+
+        notified.insert(chat_id);
+
+        let filter = filter.parse::<VersionFilter>().unwrap_or_else(|err| {
+            warn!("invalid filter {:?} of {}: {}", filter, chat_id, err);
+            VersionFilter::All
+        });
+
+        // Yanks, unyanks & deletions are about versions the chat may already
+        // depend on, so they aren't filtered
+        if action == ActionKind::NewVersion && !filter.matches(&krate.id.vers) {
             continue;
         }
 
-        let diff = repo.diff_tree_to_tree(Some(&prev.tree()?), Some(&next.tree()?), Some(opts))?;
-        let (krate, action) = diff_one(diff, (prev, next))?;
+        let toolchain = toolchain.and_then(|toolchain| {
+            toolchain
+                .parse::<RustVersion>()
+                .map_err(|err| warn!("invalid toolchain of {}: {}", chat_id, err))
+                .ok()
+        });
+        let msrv = msrv_alert(toolchain);
 
-        // Send crates.io update to notifier
-        let (tx, mut rx) = oneshot::channel();
-        ch.blocking_send((krate, action, tx)).ok().unwrap();
+        // The chat wants only releases which raise MSRV
+        if !EventMask::from_bits(events).contains(EventMask::of(action)) && msrv.is_none() {
+            continue;
+        }
 
-        // Wait untill the crate is processed before moving on
-        while let Err(oneshot::error::TryRecvError::Empty) = rx.try_recv() {
-            // Yeild/sleep to not spend all resources
-            std::thread::sleep(Duration::from_secs(1));
+        if digest {
+            res.digest.push((chat_id, dedup_key(chat_id)));
+            continue;
         }
 
-        // 'Move' to the next commit
-        fast_forward(repo, next)?;
+        res.messages.push(OutgoingMessage {
+            chat_id,
+            payload: message(language.as_deref(), msrv),
+            silent: false,
+            dedup_key: dedup_key(chat_id),
+            coalesce_window: None,
+        });
+    }
+
+    // Dependents are notified only about new versions, chats subscribed to
+    // the crate itself were handled above
+    let dependents = dependents
+        .into_iter()
+        .filter(|_| action == ActionKind::NewVersion);
+    for (chat_id, dependencies, digest, language) in dependents {
+        if notified.contains(&chat_id) {
+            continue;
+        }
+
+        let dedup_key = format!(
+            "{}:dependent:{}#{}:{}",
+            origin, krate.id.name, krate.id.vers, chat_id
+        );
+
+        if digest {
+            res.digest.push((chat_id, dedup_key));
+            continue;
+        }
+
+        let templates = cfg.locales.get(language.as_deref());
+        res.messages.push(OutgoingMessage {
+            chat_id,
+            payload: render_dependent_update(
+                krate,
+                &dependencies,
+                previous.zip(changes.as_ref()),
+                templates,
+            ),
+            silent: false,
+            dedup_key,
+            coalesce_window: None,
+        });
     }
 
-    Ok(())
+    res
 }
 
-enum ActionKind {
-    NewVersion,
-    Yanked,
-    Unyanked,
+/// Render a notification about an update.
+///
+/// `previous` is the previous version and changes since it (known only for
+/// new versions), `msrv` is an alert about MSRV of a new version.
+fn render_update(
+    krate: &Crate,
+    action: ActionKind,
+    previous: Option<(&Crate, &Changes)>,
+    msrv: Option<&MsrvAlert>,
+    templates: &Templates,
+) -> String {
+    let (key, action_name) = match action {
+        ActionKind::NewVersion => (Key::NewVersion, "updated"),
+        ActionKind::Yanked => (Key::Yanked, "yanked"),
+        ActionKind::Unyanked => (Key::Unyanked, "unyanked"),
+        ActionKind::Deleted => (Key::Deleted, "deleted"),
+        ActionKind::CrateDeleted => (Key::CrateDeleted, "deleted"),
+    };
+
+    let mut args = Args::new()
+        .text("crate", &krate.id.name)
+        .text("action", action_name);
+
+    // There is nothing to link to if the whole crate was deleted
+    if action != ActionKind::CrateDeleted {
+        args = args
+            .text("version", &krate.id.vers)
+            .markup("links", templates.format().crate_links(krate));
+    }
+
+    if let Some((previous, changes)) = previous {
+        args = args
+            .text("previous_version", &previous.id.vers)
+            .markup("changes", changes.render(templates, &previous.id.vers));
+    }
+
+    if let Some(msrv) = msrv {
+        args = args.markup("msrv", msrv.render(templates));
+    }
+
+    templates.render(key, &args)
 }
 
-/// Get a `crates.io` update from a diff of 2 consecutive commits from a
-/// `crates.io-index` repository.
-fn diff_one(diff: Diff, commits: (&Commit, &Commit)) -> Result<(Crate, ActionKind), git2::Error> {
-    let mut prev = None;
-    let mut next = None;
-
-    diff.foreach(
-        &mut |_, _| true,
-        None,
-        None,
-        Some(&mut |delta, _hunk, line| {
-            match delta.status() {
-                // New version of a crate or (un)yanked old version
-                Delta::Modified | Delta::Added => {
-                    assert!(delta.nfiles() == 2 || delta.nfiles() == 1);
-                    match line.origin() {
-                        '-' => {
-                            assert!(
-                                prev.is_none(),
-                                "Expected number of deletions <= 1 per commit ({} -> {})",
-                                commits.0.id(),
-                                commits.1.id(),
-                            );
-                            let krate = str::from_utf8(line.content()).expect("non-utf8 diff");
-                            let krate = serde_json::from_str::<Crate>(krate)
-                                .expect("cound't deserialize crate");
-
-                            prev = Some(krate);
-                        }
-                        '+' => {
-                            assert!(
-                                next.is_none(),
-                                "Expected number of additions = 1 per commit ({} -> {})",
-                                commits.0.id(),
-                                commits.1.id(),
-                            );
-                            let krate = str::from_utf8(line.content()).expect("non-utf8 diff");
-                            let krate = serde_json::from_str::<Crate>(krate)
-                                .expect("cound't deserialize crate");
-
-                            next = Some(krate);
-                        }
-                        _ => { /* don't care */ }
-                    }
-                }
-                delta => {
-                    warn!("Unexpected delta: {:?}", delta);
-                }
-            }
+/// Render a notification about a new version of a crate which depends on
+/// `dependencies` (comma-separated), for chats subscribed to their dependents.
+fn render_dependent_update(
+    krate: &Crate,
+    dependencies: &str,
+    previous: Option<(&Crate, &Changes)>,
+    templates: &Templates,
+) -> String {
+    let mut args = Args::new()
+        .text("crate", &krate.id.name)
+        .text("version", &krate.id.vers)
+        .markup("links", templates.format().crate_links(krate))
+        .text("dependencies", dependencies);
+
+    if let Some((previous, changes)) = previous {
+        args = args
+            .text("previous_version", &previous.id.vers)
+            .markup("changes", changes.render(templates, &previous.id.vers));
+    }
 
-            true
-        }),
-    )?;
+    templates.render(Key::DependentUpdated, &args)
+}
 
-    assert!(
-        next.is_some(),
-        "Expected number of additions = 1 per commit ({} -> {})",
-        commits.0.id(),
-        commits.1.id(),
-    );
-    let next = next.expect("Expected number of additions = 1 per commit");
-    match (prev.as_ref().map(|c| c.yanked), next.yanked) {
-        /* was yanked?, is yanked? */
-        (None, false) => {
-            // There were no deleted line & crate is not yanked.
-            // New version.
-            Ok((next, ActionKind::NewVersion))
-        }
-        (Some(false), true) => {
-            // The crate was not yanked and now is yanked.
-            // Crate was yanked.
-            Ok((next, ActionKind::Yanked))
-        }
-        (Some(true), false) => {
-            // The crate was yanked and now is not yanked.
-            // Crate was unyanked.
-            Ok((next, ActionKind::Unyanked))
-        }
-        _unexpected => {
-            // Something unexpected happened
-            warn!("Unexpected diff_one input: {:?}, {:?}", next, prev);
-            Err(git2::Error::from_str("Unexpected diff"))
-        }
+/// Alerts for chats which have the yanked version pinned in a lockfile of one
+/// of their projects.
+///
+/// These are sent regardless of subscription filters since a yanked
+/// dependency most likely requires actions from the user.
+async fn pinned_alerts(
+    krate: &Crate,
+    origin: &str,
+    db: &Database,
+    cfg: &cfg::Config,
+) -> Result<Vec<OutgoingMessage>, DbError> {
+    let mut projects = BTreeMap::<_, Vec<_>>::new();
+    for (chat_id, project) in db.list_pinned(&krate.id.name, &krate.id.vers).await? {
+        projects.entry(chat_id).or_default().push(project);
     }
+
+    let mut alerts = Vec::new();
+    for (chat_id, projects) in projects {
+        let language = db.get_language(chat_id).await?;
+        let templates = cfg.locales.get(language.as_deref());
+
+        alerts.push(OutgoingMessage {
+            chat_id,
+            payload: templates.render(
+                Key::PinnedYanked,
+                &Args::new()
+                    .text("crate", &krate.id.name)
+                    .text("version", &krate.id.vers)
+                    .markup("links", templates.format().crate_links(krate))
+                    .text("projects", projects.join(", ")),
+            ),
+            silent: false,
+            dedup_key: format!(
+                "{}:pinned:{}#{}:{}",
+                origin, krate.id.name, krate.id.vers, chat_id
+            ),
+            coalesce_window: None,
+        });
+    }
+
+    Ok(alerts)
 }
 
-async fn notify(krate: Crate, action: ActionKind, bot: &Bot, db: &Database, cfg: &cfg::Config) {
-    let message = format!(
-        "Crate was {action}: <code>{krate}#{version}</code> {links}",
-        krate = krate.id.name,
-        version = krate.id.vers,
-        links = krate.html_links(),
-        action = match action {
-            ActionKind::NewVersion => "updated",
-            ActionKind::Yanked => "yanked",
-            ActionKind::Unyanked => "unyanked",
-        }
-    );
+/// Alert chats watching migrations of dependencies of a new version, which
+/// moved to new semver-incompatible versions (`migrations`).
+async fn migration_alerts(
+    krate: &Crate,
+    migrations: &[Migration],
+    origin: &str,
+    db: &Database,
+    cfg: &cfg::Config,
+) -> Result<Vec<OutgoingMessage>, DbError> {
+    if migrations.is_empty() {
+        return Ok(Vec::new());
+    }
 
-    let channel_fut = async {
-        if let Some(chat_id) = cfg.channel {
-            if !cfg.ban.crates.contains(krate.id.name.as_str()) {
-                notify_inner(bot, chat_id, &message, cfg, &krate, true).await;
-            }
+    let dependencies: Vec<_> = migrations.iter().map(|m| m.dependency.as_str()).collect();
+    let new_ranges: Vec<_> = migrations.iter().map(|m| m.new_range.to_string()).collect();
+    let counts = db
+        .count_new_migrations(
+            &krate.id.name,
+            &dependencies,
+            &new_ranges.iter().map(String::as_str).collect::<Vec<_>>(),
+        )
+        .await?;
+
+    let mut alerts = Vec::new();
+    for (migration, count) in migrations.iter().zip(counts) {
+        for (chat_id, language) in db.list_migration_watchers(&migration.dependency).await? {
+            let templates = cfg.locales.get(language.as_deref());
+
+            alerts.push(OutgoingMessage {
+                chat_id,
+                payload: templates.render(
+                    Key::Migration,
+                    &Args::new()
+                        .text("crate", &krate.id.name)
+                        .text("version", &krate.id.vers)
+                        .markup("links", templates.format().crate_links(krate))
+                        .text("dependency", &migration.dependency)
+                        .text("old_req", &migration.old_req)
+                        .text("new_req", &migration.new_req)
+                        .text("old_range", migration.old_range)
+                        .text("new_range", migration.new_range)
+                        .text("count", count),
+                ),
+                silent: false,
+                dedup_key: format!(
+                    "{}:migration:{}#{}:{}:{}",
+                    origin, krate.id.name, krate.id.vers, migration.dependency, chat_id
+                ),
+                coalesce_window: None,
+            });
         }
-    };
+    }
 
-    let users_fut = async {
-        let users = db
-            .list_subscribers(&krate.id.name)
-            .await
-            .map(Left)
-            .map_err(|err| error!("db error while getting subscribers: {}", err))
-            .unwrap_or_else(|_| Right(iter::empty()));
+    Ok(alerts)
+}
 
-        for chat_id in users {
-            notify_inner(bot, chat_id, &message, cfg, &krate, false).await;
-            tokio::time::sleep(cfg.broadcast_delay_millis.into()).await;
+/// Alerts for chats watching new crates with names matching the name of a
+/// new crate.
+async fn new_crate_alerts(
+    krate: &Crate,
+    origin: &str,
+    db: &Database,
+    cfg: &cfg::Config,
+    patterns: &PatternCache,
+) -> Result<Vec<OutgoingMessage>, DbError> {
+    let watchers: Vec<_> = db.list_new_crate_watchers().await?.collect();
+
+    // Watchers are ordered by pattern
+    let mut sources: Vec<_> = watchers.iter().map(|(_, p, _)| p.clone()).collect();
+    sources.dedup();
+
+    let matched: HashSet<_> = match patterns.matches(&sources, &krate.id.name) {
+        Ok(matched) => matched.into_iter().map(|i| sources[i].as_str()).collect(),
+        Err(err) => {
+            error!("couldn't compile patterns of new crates: {}", err);
+            return Ok(Vec::new());
         }
     };
 
-    tokio::join!(channel_fut, users_fut);
+    // chat => (language, patterns)
+    let mut chats = BTreeMap::<_, (_, Vec<_>)>::new();
+    for (chat_id, pattern, language) in &watchers {
+        if matched.contains(pattern.as_str()) {
+            chats
+                .entry(*chat_id)
+                .or_insert_with(|| (language.as_deref(), Vec::new()))
+                .1
+                .push(pattern.as_str());
+        }
+    }
+
+    let mut alerts = Vec::new();
+    for (chat_id, (language, patterns)) in chats {
+        let templates = cfg.locales.get(language);
+
+        alerts.push(OutgoingMessage {
+            chat_id,
+            payload: templates.render(
+                Key::NewCrate,
+                &Args::new()
+                    .text("crate", &krate.id.name)
+                    .text("version", &krate.id.vers)
+                    .markup("links", templates.format().crate_links(krate))
+                    .text("patterns", patterns.join(", ")),
+            ),
+            silent: false,
+            dedup_key: format!("{}:new:{}:{}", origin, krate.id.name, chat_id),
+            coalesce_window: None,
+        });
+    }
+
+    Ok(alerts)
 }
 
-async fn notify_inner(
-    bot: &Bot,
-    chat_id: i64,
-    msg: &str,
-    cfg: &cfg::Config,
+/// Alerts for chats with typosquatting alerts enabled, which are subscribed to
+/// crates with names similar to the name of a new crate.
+async fn typosquat_alerts(
     krate: &Crate,
-    quiet: bool,
-) {
-    tryn(5, cfg.retry_delay.0, || {
-        bot.send_message(chat_id, msg)
-            .disable_web_page_preview(true)
-            .disable_notification(quiet)
-    })
-    .await
-    .map(drop)
-    .unwrap_or_else(|err| {
-        error!(
-            "error while trying to send notification about {krate:?} to {chat_id}: {err}",
-            krate = krate,
-            chat_id = chat_id,
-            err = err
+    origin: &str,
+    db: &Database,
+    cfg: &cfg::Config,
+) -> Result<Vec<OutgoingMessage>, DbError> {
+    let watches: Vec<_> = db.list_typosquat_watches().await?.collect();
+
+    // Watches are ordered by crate, so each name is checked once
+    let mut checked: Option<(&str, Option<Resemblance>)> = None;
+    // chat => (language, similar crates)
+    let mut chats = BTreeMap::<_, (_, Vec<_>)>::new();
+    for (chat_id, name, language) in &watches {
+        let resemblance = match checked {
+            Some((checked_name, resemblance)) if checked_name == name.as_str() => resemblance,
+            _ => {
+                let resemblance = Resemblance::check(&krate.id.name, name);
+                checked = Some((name.as_str(), resemblance));
+                resemblance
+            }
+        };
+
+        if let Some(resemblance) = resemblance {
+            chats
+                .entry(*chat_id)
+                .or_insert_with(|| (language.as_deref(), Vec::new()))
+                .1
+                .push((name.as_str(), resemblance));
+        }
+    }
+
+    let mut alerts = Vec::new();
+    for (chat_id, (language, similar)) in chats {
+        let templates = cfg.locales.get(language);
+        let similar: Vec<_> = similar
+            .iter()
+            .map(|(name, resemblance)| resemblance.render(name, templates))
+            .collect();
+
+        alerts.push(OutgoingMessage {
+            chat_id,
+            payload: templates.render(
+                Key::Typosquat,
+                &Args::new()
+                    .text("crate", &krate.id.name)
+                    .text("version", &krate.id.vers)
+                    .markup("links", templates.format().crate_links(krate))
+                    .markup("similar", similar.join("\n")),
+            ),
+            silent: false,
+            dedup_key: format!("{}:typosquat:{}:{}", origin, krate.id.name, chat_id),
+            coalesce_window: None,
+        });
+    }
+
+    Ok(alerts)
+}
+
+/// The version published before `krate`, according to the local index.
+async fn previous_version(krate: &Crate, cfg: &cfg::Config) -> Option<Crate> {
+    let path = find_crate_file(Path::new(&cfg.index_path), &krate.id.name)?;
+    Crate::read_previous(&path, &krate.id.vers)
+        .await
+        .unwrap_or_else(|err| {
+            warn!("couldn't read crate file {:?}: {}", path, err);
+            None
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{
+        future::{self, pending},
+        StreamExt,
+    };
+
+    use super::{notifications, Notifications};
+    use crate::{
+        cfg::Config,
+        db::Subscriber,
+        filter::EventMask,
+        source::{ReplaySource, UpdateSource},
+    };
+
+    const CONFIG: &str = r#"
+bot_token = ""
+
+[db]
+host = ""
+user = ""
+dbname = ""
+
+[[channels]]
+id = -1
+
+[[channels]]
+id = -2
+names = ["tokio-*"]
+"#;
+
+    const REPLAY: &str = r#"
+{"action": "new_version", "crate": {"name": "serde", "vers": "1.0.1", "yanked": false}}
+{"action": "yanked", "crate": {"name": "serde", "vers": "1.0.0", "yanked": true}}
+"#;
+
+    fn subscriber(chat_id: i64, filter: &str, events: i32, digest: bool) -> Subscriber {
+        Subscriber {
+            chat_id,
+            filter: filter.to_owned(),
+            events,
+            digest,
+            language: None,
+            toolchain: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn replayed_updates_produce_notifications() {
+        let cfg: Config = toml::from_str(CONFIG).unwrap();
+
+        let path =
+            std::env::temp_dir().join(format!("crate_upd_bot-replay-{}", std::process::id()));
+        std::fs::write(&path, REPLAY).unwrap();
+
+        let (stop, _abort_handle) = future::abortable(pending::<()>());
+        let mut updates = Box::new(ReplaySource::new(&path)).updates(stop);
+
+        let mut results = Vec::new();
+        while let Some(update) = updates.next().await {
+            let subscribers = vec![
+                // All updates
+                subscriber(1, "all", EventMask::ALL.bits(), false),
+                // All updates, in a digest
+                subscriber(2, "all", EventMask::ALL.bits(), true),
+                // Only breaking releases
+                subscriber(3, "major", EventMask::ALL.bits(), false),
+            ];
+            let dependents = vec![
+                // Already notified as a subscriber of the crate
+                (1, "serde".to_owned(), false, None),
+                (4, "serde".to_owned(), false, None),
+            ];
+
+            let Notifications { messages, digest } =
+                notifications(&update, None, subscribers, dependents, &cfg);
+            let messages: Vec<_> = messages
+                .into_iter()
+                .map(|m| {
+                    assert!(m.payload.contains("serde"));
+                    (
+                        m.chat_id,
+                        m.silent,
+                        m.dedup_key.replace(&update.origin, "origin"),
+                    )
+                })
+                .collect();
+            results.push((messages, digest.len()));
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            results,
+            [
+                (
+                    vec![
+                        (-1, true, "origin:NewVersion:serde#1.0.1:-1".to_owned()),
+                        (1, false, "origin:NewVersion:serde#1.0.1:1".to_owned()),
+                        (4, false, "origin:dependent:serde#1.0.1:4".to_owned()),
+                    ],
+                    1
+                ),
+                (
+                    vec![
+                        (-1, true, "origin:Yanked:serde#1.0.0:-1".to_owned()),
+                        (1, false, "origin:Yanked:serde#1.0.0:1".to_owned()),
+                        // The filter applies only to new versions
+                        (3, false, "origin:Yanked:serde#1.0.0:3".to_owned()),
+                    ],
+                    1
+                ),
+            ]
         );
-    });
+    }
 }
@@ -0,0 +1,271 @@
+//! Parsing of `Cargo.lock` and `Cargo.toml` files uploaded by users.
+use std::collections::BTreeSet;
+
+use toml::{value::Table, Value};
+
+/// Source of packages from crates.io in `Cargo.lock`.
+const CRATES_IO_SOURCES: [&str; 2] = [
+    "registry+https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+
+/// Tables of a manifest that contain dependencies.
+const DEPENDENCY_TABLES: [&str; 5] = [
+    "dependencies",
+    "dev-dependencies",
+    "dev_dependencies",
+    "build-dependencies",
+    "build_dependencies",
+];
+
+#[derive(Debug, derive_more::Display, derive_more::From, derive_more::Error)]
+pub enum Error {
+    Toml(toml::de::Error),
+    #[display(fmt = "the file is neither Cargo.lock nor Cargo.toml")]
+    NotCargo,
+}
+
+/// crates.io dependencies of a project.
+#[derive(Debug, Default)]
+pub struct Dependencies {
+    /// Names of the dependencies
+    pub crates: BTreeSet<String>,
+    /// Exact versions (`name`, `vers`) of the dependencies, only available
+    /// for `Cargo.lock`
+    pub pins: BTreeSet<(String, String)>,
+}
+
+/// Parse crates.io dependencies from the content of `Cargo.lock` or
+/// `Cargo.toml`.
+///
+/// Path, git and alternative registry dependencies are skipped.
+pub fn parse(content: &str) -> Result<Dependencies, Error> {
+    let value: Value = toml::from_str(content)?;
+    let table = value.as_table().ok_or(Error::NotCargo)?;
+
+    // `Cargo.lock` has an array of packages while `Cargo.toml` has a single
+    // package table
+    match table.get("package") {
+        Some(Value::Array(packages)) => Ok(lockfile(packages)),
+        _ if is_manifest(table) => Ok(Dependencies {
+            crates: manifest(table),
+            pins: BTreeSet::new(),
+        }),
+        _ => Err(Error::NotCargo),
+    }
+}
+
+fn lockfile(packages: &[Value]) -> Dependencies {
+    let pins: BTreeSet<_> = packages
+        .iter()
+        .filter_map(Value::as_table)
+        .filter(|package| {
+            // Packages without source are workspace members
+            package
+                .get("source")
+                .and_then(Value::as_str)
+                .map_or(false, |source| CRATES_IO_SOURCES.contains(&source))
+        })
+        .filter_map(|package| {
+            let name = package.get("name")?.as_str()?;
+            let vers = package.get("version")?.as_str()?;
+            Some((name.to_owned(), vers.to_owned()))
+        })
+        .collect();
+
+    Dependencies {
+        crates: pins.iter().map(|(name, _)| name.clone()).collect(),
+        pins,
+    }
+}
+
+fn is_manifest(table: &Table) -> bool {
+    ["package", "workspace", "target"]
+        .iter()
+        .chain(DEPENDENCY_TABLES.iter())
+        .any(|key| table.contains_key(*key))
+}
+
+fn manifest(table: &Table) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+
+    dependency_tables(table, &mut names);
+
+    // [target.'cfg(windows)'.dependencies]
+    if let Some(targets) = table.get("target").and_then(Value::as_table) {
+        for target in targets.values().filter_map(Value::as_table) {
+            dependency_tables(target, &mut names);
+        }
+    }
+
+    // [workspace.dependencies]
+    if let Some(workspace) = table.get("workspace").and_then(Value::as_table) {
+        dependency_tables(workspace, &mut names);
+    }
+
+    names
+}
+
+fn dependency_tables(table: &Table, names: &mut BTreeSet<String>) {
+    let deps = DEPENDENCY_TABLES
+        .iter()
+        .filter_map(|key| table.get(*key)?.as_table())
+        .flatten();
+
+    for (key, dep) in deps {
+        let name = match dep {
+            // foo = "1.0"
+            Value::String(_) => Some(key.as_str()),
+            // foo = { version = "1.0", package = "bar" }
+            Value::Table(dep) => {
+                // Path, git and alternative registry dependencies & ones
+                // inherited from `[workspace.dependencies]`
+                let skip = ["path", "git", "registry", "workspace"]
+                    .iter()
+                    .any(|k| dep.contains_key(*k));
+
+                if skip {
+                    None
+                } else {
+                    dep.get("package")
+                        .and_then(Value::as_str)
+                        .or_else(|| Some(key.as_str()))
+                }
+            }
+            _ => None,
+        };
+
+        names.extend(name.map(String::from));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Error};
+
+    fn pair(name: &str, vers: &str) -> (String, String) {
+        (name.to_owned(), vers.to_owned())
+    }
+
+    #[test]
+    fn lockfile() {
+        let lockfile = r#"
+            version = 3
+
+            [[package]]
+            name = "my-app"
+            version = "0.1.0"
+            dependencies = ["serde", "local"]
+
+            [[package]]
+            name = "local"
+            version = "0.1.0"
+
+            [[package]]
+            name = "serde"
+            version = "1.0.130"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+            checksum = "f12d06de37cf59146fbdecab66aa99f9fe4f78722e3607577a5375d66bd0c913"
+
+            [[package]]
+            name = "tokio"
+            version = "1.12.0"
+            source = "sparse+https://index.crates.io/"
+
+            [[package]]
+            name = "forked"
+            version = "0.2.0"
+            source = "git+https://github.com/user/forked#0123456789abcdef"
+
+            [[package]]
+            name = "private"
+            version = "0.3.0"
+            source = "registry+https://example.com/index"
+        "#;
+
+        let deps = parse(lockfile).unwrap();
+        assert_eq!(
+            deps.pins.into_iter().collect::<Vec<_>>(),
+            [pair("serde", "1.0.130"), pair("tokio", "1.12.0")]
+        );
+        assert_eq!(
+            deps.crates.into_iter().collect::<Vec<_>>(),
+            ["serde", "tokio"]
+        );
+    }
+
+    #[test]
+    fn manifest() {
+        let manifest = r#"
+            [package]
+            name = "my-app"
+            version = "0.1.0"
+
+            [dependencies]
+            serde = "1.0"
+            tokio = { version = "1", features = ["full"] }
+            json = { version = "1.0", package = "serde_json" }
+            local = { path = "../local" }
+            local-published = { path = "../local-published", version = "0.1" }
+            forked = { git = "https://github.com/user/forked" }
+            private = { version = "0.3", registry = "company" }
+            inherited = { workspace = true }
+
+            [dev-dependencies]
+            proptest = "1"
+
+            [build_dependencies]
+            cc = "1.0"
+
+            [target.'cfg(windows)'.dependencies]
+            winapi = { version = "0.3", features = ["winuser"] }
+
+            [target.'cfg(unix)'.build-dependencies]
+            pkg-config = "0.3"
+        "#;
+
+        let deps = parse(manifest).unwrap();
+        assert_eq!(
+            deps.crates.into_iter().collect::<Vec<_>>(),
+            [
+                "cc",
+                "pkg-config",
+                "proptest",
+                "serde",
+                "serde_json",
+                "tokio",
+                "winapi"
+            ]
+        );
+        assert!(deps.pins.is_empty());
+    }
+
+    #[test]
+    fn workspace() {
+        let manifest = r#"
+            [workspace]
+            members = ["a", "b"]
+
+            [workspace.dependencies]
+            log = "0.4"
+            a = { path = "a" }
+            anyhow = { version = "1", default-features = false }
+        "#;
+
+        let deps = parse(manifest).unwrap();
+        assert_eq!(
+            deps.crates.into_iter().collect::<Vec<_>>(),
+            ["anyhow", "log"]
+        );
+    }
+
+    #[test]
+    fn not_cargo() {
+        assert!(matches!(
+            parse("[tool.poetry]\nname = \"x\""),
+            Err(Error::NotCargo)
+        ));
+        assert!(matches!(parse("name = \"x\""), Err(Error::NotCargo)));
+        assert!(matches!(parse("{ not toml"), Err(Error::Toml(_))));
+    }
+}
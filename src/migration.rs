@@ -0,0 +1,219 @@
+//! Migrations of dependents to new semver-incompatible versions of their
+//! dependencies (e.g. `mycrate = "1"` → `mycrate = "2"`).
+use std::{collections::BTreeMap, fmt};
+
+use semver::{Op, VersionReq};
+
+use crate::{
+    krate::{Crate, DependencyKind},
+    util::normalize_crate_name,
+};
+
+/// Semver-compatibility range, e.g. `2` (`2.x.y`), `0.4` (`0.4.x`) or `0.0.3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CompatRange {
+    major: u64,
+    minor: u64,
+    patch: u64,
+}
+
+impl CompatRange {
+    /// Range of the lowest version allowed by a requirement, `None` if the
+    /// requirement has no lower bound (e.g. `*` or `<2`).
+    fn of_req(req: &str) -> Option<Self> {
+        let req = VersionReq::parse(req).ok()?;
+
+        // All comparators must hold, so the lower bound is the greatest one
+        req.comparators
+            .iter()
+            .filter(|c| !matches!(c.op, Op::Less | Op::LessEq))
+            .map(|c| Self::of((c.major, c.minor.unwrap_or(0), c.patch.unwrap_or(0))))
+            .max()
+    }
+
+    fn of((major, minor, patch): (u64, u64, u64)) -> Self {
+        match (major, minor) {
+            (0, 0) => Self {
+                major,
+                minor,
+                patch,
+            },
+            (0, _) => Self {
+                major,
+                minor,
+                patch: 0,
+            },
+            _ => Self {
+                major,
+                minor: 0,
+                patch: 0,
+            },
+        }
+    }
+}
+
+impl fmt::Display for CompatRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.major, self.minor) {
+            (0, 0) => write!(f, "0.0.{}", self.patch),
+            (0, minor) => write!(f, "0.{}", minor),
+            (major, _) => write!(f, "{}", major),
+        }
+    }
+}
+
+/// A dependency which requirement moved to a newer compatibility range.
+#[derive(Debug)]
+pub struct Migration {
+    /// Name of the dependency (accounting for renames)
+    pub dependency: String,
+    pub old_req: String,
+    pub new_req: String,
+    pub old_range: CompatRange,
+    pub new_range: CompatRange,
+}
+
+impl Migration {
+    /// Find dependencies of `new` which requirements moved to a newer
+    /// compatibility range since `old` (dev-dependencies are not counted).
+    pub fn between(old: &Crate, new: &Crate) -> Vec<Self> {
+        let old_reqs = reqs(old);
+
+        reqs(new)
+            .into_iter()
+            .filter_map(|(name, (new_range, dependency, new_req))| {
+                let (old_range, _, old_req) = old_reqs.get(&name)?;
+                (new_range > *old_range).then(|| Self {
+                    dependency,
+                    old_req: old_req.clone(),
+                    new_req,
+                    old_range: *old_range,
+                    new_range,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Normalized name of a dependency => `(range, name, requirement)` with the
+/// newest range, if a crate is listed more than once (e.g. for different
+/// targets).
+fn reqs(krate: &Crate) -> BTreeMap<String, (CompatRange, String, String)> {
+    let mut res = BTreeMap::<_, (CompatRange, String, String)>::new();
+
+    let deps = krate
+        .deps
+        .iter()
+        .filter(|dep| dep.kind() != DependencyKind::Dev);
+    for dep in deps {
+        let range = match CompatRange::of_req(&dep.req) {
+            Some(range) => range,
+            None => continue,
+        };
+
+        let name = normalize_crate_name(dep.crate_name());
+        match res.get(&name) {
+            Some((newest, _, _)) if *newest >= range => {}
+            _ => {
+                res.insert(name, (range, dep.crate_name().to_owned(), dep.req.clone()));
+            }
+        }
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{CompatRange, Migration};
+    use crate::krate::Crate;
+
+    fn range(req: &str) -> Option<String> {
+        CompatRange::of_req(req).map(|r| r.to_string())
+    }
+
+    fn krate(deps: &[(&str, &str, Option<&str>)]) -> Crate {
+        let deps: Vec<_> = deps
+            .iter()
+            .map(|(name, req, kind)| json!({ "name": name, "req": req, "kind": kind }))
+            .collect();
+        serde_json::from_value(json!({
+            "name": "foo",
+            "vers": "1.0.0",
+            "deps": deps,
+            "yanked": false,
+        }))
+        .unwrap()
+    }
+
+    fn between(
+        old: &[(&str, &str, Option<&str>)],
+        new: &[(&str, &str, Option<&str>)],
+    ) -> Vec<(String, String, String)> {
+        Migration::between(&krate(old), &krate(new))
+            .into_iter()
+            .map(|m| {
+                (
+                    m.dependency,
+                    m.old_range.to_string(),
+                    m.new_range.to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(range("^1").as_deref(), Some("1"));
+        assert_eq!(range("1.2.3").as_deref(), Some("1"));
+        assert_eq!(range("0.3").as_deref(), Some("0.3"));
+        assert_eq!(range("~0.0.3").as_deref(), Some("0.0.3"));
+        assert_eq!(range(">=0.4").as_deref(), Some("0.4"));
+        assert_eq!(range(">=1.2, <3").as_deref(), Some("1"));
+        assert_eq!(range(">=1, >=2.1").as_deref(), Some("2"));
+        assert_eq!(range("*"), None);
+        assert_eq!(range("<2"), None);
+        assert_eq!(range("not a requirement"), None);
+    }
+
+    #[test]
+    fn migrations() {
+        let major = between(&[("bar", "^1", None)], &[("bar", "^2", None)]);
+        assert_eq!(major, [("bar".to_owned(), "1".to_owned(), "2".to_owned())]);
+
+        let minor = between(&[("bar", "0.3", None)], &[("bar", "0.4", None)]);
+        assert_eq!(
+            minor,
+            [("bar".to_owned(), "0.3".to_owned(), "0.4".to_owned())]
+        );
+
+        assert!(between(&[("bar", "1.2", None)], &[("bar", "1.3", None)]).is_empty());
+        assert!(between(&[("bar", "0.4", None)], &[("bar", "0.3", None)]).is_empty());
+    }
+
+    #[test]
+    fn unbounded_and_unparseable_requirements() {
+        assert!(between(&[("bar", "*", None)], &[("bar", "^2", None)]).is_empty());
+        assert!(between(&[("bar", "^1", None)], &[("bar", "*", None)]).is_empty());
+        assert!(between(&[("bar", "oops", None)], &[("bar", "^2", None)]).is_empty());
+
+        let multi = between(&[("bar", ">=1.5, <2", None)], &[("bar", ">=2, <4", None)]);
+        assert_eq!(multi, [("bar".to_owned(), "1".to_owned(), "2".to_owned())]);
+    }
+
+    #[test]
+    fn added_removed_and_dev_dependencies() {
+        assert!(between(&[], &[("bar", "^2", None)]).is_empty());
+        assert!(between(&[("bar", "^1", None)], &[]).is_empty());
+        assert!(between(&[("bar", "^1", None)], &[("baz", "^2", None)]).is_empty());
+        assert!(between(&[("bar", "^1", Some("dev"))], &[("bar", "^2", Some("dev"))]).is_empty());
+
+        let build = between(
+            &[("bar", "^1", Some("build"))],
+            &[("bar", "^2", Some("build"))],
+        );
+        assert_eq!(build, [("bar".to_owned(), "1".to_owned(), "2".to_owned())]);
+    }
+}
@@ -0,0 +1,184 @@
+//! Minimal supported rust versions (MSRV) of crates and alerts about them.
+use std::{fmt, str::FromStr};
+
+use crate::{
+    krate::Crate,
+    template::{Args, Key, Templates},
+};
+
+/// Version of the rust toolchain, e.g. `1.56` or `1.56.1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RustVersion {
+    major: u64,
+    minor: u64,
+    patch: u64,
+}
+
+#[derive(Debug, derive_more::Display)]
+#[display(fmt = "invalid rust version: {:?}", _0)]
+pub struct InvalidRustVersion(String);
+
+impl FromStr for RustVersion {
+    type Err = InvalidRustVersion;
+
+    /// Parse `major.minor` or `major.minor.patch`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || InvalidRustVersion(s.to_owned());
+
+        let mut parts = s.trim().split('.').map(str::parse::<u64>);
+        let mut next = || parts.next().transpose().map_err(|_| err());
+        let (major, minor, patch) = (next()?, next()?, next()?);
+        if next()?.is_some() {
+            return Err(err());
+        }
+
+        Ok(Self {
+            major: major.ok_or_else(err)?,
+            minor: minor.ok_or_else(err)?,
+            patch: patch.unwrap_or(0),
+        })
+    }
+}
+
+impl fmt::Display for RustVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.patch {
+            0 => write!(f, "{}.{}", self.major, self.minor),
+            patch => write!(f, "{}.{}.{}", self.major, self.minor, patch),
+        }
+    }
+}
+
+/// Reason to draw attention to the MSRV of a new version.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MsrvAlert {
+    /// MSRV is higher than the one of the previous version
+    Raised { old: RustVersion, new: RustVersion },
+    /// MSRV is higher than the toolchain of the chat
+    AboveToolchain {
+        new: RustVersion,
+        toolchain: RustVersion,
+    },
+}
+
+impl MsrvAlert {
+    /// Check MSRV of `krate` against the chat's `toolchain` or, if the chat
+    /// didn't set one, against MSRV of the `previous` version.
+    ///
+    /// Versions without (or with unparseable) MSRV never raise alerts.
+    pub fn check(
+        krate: &Crate,
+        previous: Option<&Crate>,
+        toolchain: Option<RustVersion>,
+    ) -> Option<Self> {
+        let new = rust_version(krate)?;
+
+        match toolchain {
+            Some(toolchain) if new > toolchain => Some(Self::AboveToolchain { new, toolchain }),
+            Some(_) => None,
+            None => {
+                let old = rust_version(previous?)?;
+                (new > old).then(|| Self::Raised { old, new })
+            }
+        }
+    }
+
+    /// Render the alert (`msrv` variable of `new_version`).
+    pub fn render(&self, templates: &Templates) -> String {
+        match self {
+            Self::Raised { old, new } => {
+                let args = Args::new().text("old", old).text("new", new);
+                templates.render(Key::MsrvRaised, &args)
+            }
+            Self::AboveToolchain { new, toolchain } => {
+                let args = Args::new().text("new", new).text("toolchain", toolchain);
+                templates.render(Key::MsrvAboveToolchain, &args)
+            }
+        }
+    }
+}
+
+fn rust_version(krate: &Crate) -> Option<RustVersion> {
+    krate.rust_version.as_deref()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MsrvAlert, RustVersion};
+    use crate::krate::Crate;
+
+    fn version(s: &str) -> RustVersion {
+        s.parse().unwrap()
+    }
+
+    fn krate(rust_version: Option<&str>) -> Crate {
+        let mut krate: Crate =
+            serde_json::from_str(r#"{"name": "foo", "vers": "1.0.0", "yanked": false}"#).unwrap();
+        krate.rust_version = rust_version.map(str::to_owned);
+        krate
+    }
+
+    #[test]
+    fn parse_rust_version() {
+        assert_eq!(version("1.56").to_string(), "1.56");
+        assert_eq!(version("1.56.1").to_string(), "1.56.1");
+        assert_eq!(version(" 1.56.0 ").to_string(), "1.56");
+        assert!(version("1.56.1") > version("1.56"));
+        assert!(version("1.60") > version("1.56.1"));
+
+        assert!("1".parse::<RustVersion>().is_err());
+        assert!("1.56.1.2".parse::<RustVersion>().is_err());
+        assert!("1.x".parse::<RustVersion>().is_err());
+        assert!("".parse::<RustVersion>().is_err());
+    }
+
+    #[test]
+    fn raised_above_previous() {
+        let (old, new) = (krate(Some("1.56")), krate(Some("1.60")));
+        assert_eq!(
+            MsrvAlert::check(&new, Some(&old), None),
+            Some(MsrvAlert::Raised {
+                old: version("1.56"),
+                new: version("1.60"),
+            })
+        );
+
+        // Not raised
+        assert_eq!(MsrvAlert::check(&old, Some(&new), None), None);
+        assert_eq!(MsrvAlert::check(&new, Some(&new), None), None);
+        // Nothing to compare with
+        assert_eq!(MsrvAlert::check(&new, None, None), None);
+        assert_eq!(MsrvAlert::check(&new, Some(&krate(None)), None), None);
+    }
+
+    #[test]
+    fn toolchain_takes_precedence_over_previous() {
+        let (old, new) = (krate(Some("1.56")), krate(Some("1.60")));
+
+        assert_eq!(
+            MsrvAlert::check(&new, Some(&old), Some(version("1.58"))),
+            Some(MsrvAlert::AboveToolchain {
+                new: version("1.60"),
+                toolchain: version("1.58"),
+            })
+        );
+        // Raised, but the toolchain is new enough
+        assert_eq!(
+            MsrvAlert::check(&new, Some(&old), Some(version("1.60"))),
+            None
+        );
+        // Not raised, but above the toolchain
+        assert!(MsrvAlert::check(&new, Some(&new), Some(version("1.50"))).is_some());
+    }
+
+    #[test]
+    fn missing_or_invalid_msrv() {
+        let old = krate(Some("1.56"));
+
+        assert_eq!(MsrvAlert::check(&krate(None), Some(&old), None), None);
+        assert_eq!(
+            MsrvAlert::check(&krate(Some("latest")), Some(&old), Some(version("1.0"))),
+            None
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! Delivery of notifications from the persistent outbox.
+//!
+//! Notifications are not sent right away, instead they are added to the
+//! `outbox` table (see [`Database::enqueue_messages`]) before the update is
+//! acknowledged. [`run`] then drains the table, retrying failed messages with
+//! exponential backoff. This way a restart in the middle of a broadcast
+//! doesn't lose the rest of it, and (thanks to dedup keys) re-processing of an
+//! update doesn't notify anyone twice.
+//!
+//! Messages are sent concurrently, at times chosen by [`Scheduler`] to stay
+//! within telegram rate limits.
+//!
+//! Messages enqueued with a coalesce window (e.g. posts to the channel) are
+//! delayed by the window, and once the first of them is due, all pending ones
+//! to the same chat are combined into as few messages as possible.
+use std::{
+    cmp,
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures::future;
+use log::{error, warn};
+use teloxide::{prelude::*, RequestError};
+
+use crate::{
+    cfg::Config,
+    db::{Database, QueuedMessage},
+    scheduler::Scheduler,
+    source::Stop,
+    Bot,
+};
+
+/// Telegram doesn't allow messages longer than this.
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Messages are dropped after this many failed delivery attempts.
+const MAX_ATTEMPTS: i32 = 10;
+
+/// Maximum number of messages fetched from the db at once.
+const BATCH_SIZE: i64 = 256;
+
+/// Messages which would have to wait for their slot longer than this are
+/// postponed in the db instead, so a single busy chat doesn't hold up others.
+const MAX_WAIT: Duration = Duration::from_secs(5);
+
+/// Maximum number of messages combined at once.
+const COALESCE_LIMIT: i64 = 256;
+
+/// Delay between checks of the outbox when it's empty.
+const POLL_DELAY: Duration = Duration::from_secs(1);
+
+/// Maximum delay between delivery attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// How long delivered messages are kept to deduplicate re-processed updates.
+const KEEP_DELIVERED: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Delay between removals of old delivered messages.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Deliver messages from the outbox until `stop` is aborted.
+pub async fn run(bot: Bot, db: Database, cfg: Arc<Config>, stop: Stop) {
+    let scheduler = Scheduler::new(&cfg.rate_limit);
+    let mut last_cleanup: Option<Instant> = None;
+
+    while !stop.is_aborted() {
+        if last_cleanup.map_or(true, |at| at.elapsed() >= CLEANUP_INTERVAL) {
+            let before = SystemTime::now() - KEEP_DELIVERED;
+            if let Err(err) = db.delete_delivered_messages(before).await {
+                error!("db error while cleaning up the outbox: {}", err);
+            }
+            last_cleanup = Some(Instant::now());
+        }
+
+        let messages: Vec<_> = match db.next_messages(BATCH_SIZE, MAX_ATTEMPTS).await {
+            Ok(messages) => messages.collect(),
+            Err(err) => {
+                error!("db error while getting messages from the outbox: {}", err);
+                Vec::new()
+            }
+        };
+
+        if messages.is_empty() {
+            tokio::time::sleep(POLL_DELAY).await;
+            continue;
+        }
+
+        let messages = combine(&db, messages).await;
+
+        // Slots are reserved eagerly, in order of messages, so messages to a
+        // single chat are sent in order
+        let sends: Vec<_> = messages
+            .into_iter()
+            .map(|message| {
+                let next_slot = scheduler.next_slot(message.chat_id);
+                let slot = if next_slot > Instant::now() + MAX_WAIT {
+                    Err(next_slot)
+                } else {
+                    Ok(scheduler.reserve(message.chat_id))
+                };
+
+                let (bot, db, cfg, scheduler, stop) = (&bot, &db, &cfg, &scheduler, &stop);
+                async move {
+                    match slot {
+                        Ok(at) => {
+                            tokio::time::sleep_until(at.into()).await;
+                            if stop.is_aborted() {
+                                return;
+                            }
+
+                            // Another message hit a flood wait in the meantime
+                            match scheduler.paused_until() {
+                                Some(until) => {
+                                    let delay = until.saturating_duration_since(Instant::now());
+                                    postpone(db, &message, delay).await
+                                }
+                                None => deliver(bot, db, cfg, scheduler, message).await,
+                            }
+                        }
+                        Err(at) => {
+                            let delay = at.saturating_duration_since(Instant::now());
+                            postpone(db, &message, delay).await
+                        }
+                    }
+                }
+            })
+            .collect();
+
+        future::join_all(sends).await;
+    }
+}
+
+/// A single telegram message: either a message from the outbox or several
+/// combined ones.
+struct Message {
+    /// Ids of messages in the outbox
+    ids: Vec<i64>,
+    chat_id: i64,
+    payload: String,
+    silent: bool,
+    attempts: i32,
+}
+
+impl From<QueuedMessage> for Message {
+    fn from(message: QueuedMessage) -> Self {
+        Self {
+            ids: vec![message.id],
+            chat_id: message.chat_id,
+            payload: message.payload,
+            silent: message.silent,
+            attempts: message.attempts,
+        }
+    }
+}
+
+/// Replace messages which may be combined with combined messages containing
+/// all pending messages to the same chat.
+async fn combine(db: &Database, messages: Vec<QueuedMessage>) -> Vec<Message> {
+    let mut res = Vec::new();
+    let mut combined_chats = HashSet::new();
+
+    for message in messages {
+        if !message.coalesce {
+            res.push(Message::from(message));
+            continue;
+        }
+
+        // All pending messages to the chat were already combined
+        if !combined_chats.insert(message.chat_id) {
+            continue;
+        }
+
+        match db
+            .list_coalesced(message.chat_id, COALESCE_LIMIT, MAX_ATTEMPTS)
+            .await
+        {
+            Ok(pending) => res.extend(coalesce(pending)),
+            Err(err) => {
+                error!("db error while getting messages to combine: {}", err);
+                res.push(Message::from(message));
+            }
+        }
+    }
+
+    res
+}
+
+/// Join messages (in order) into as few messages as fit into telegram limits.
+fn coalesce(messages: impl IntoIterator<Item = QueuedMessage>) -> Vec<Message> {
+    let mut res: Vec<Message> = Vec::new();
+
+    for message in messages {
+        match res.last_mut() {
+            Some(last) if last.payload.len() + 1 + message.payload.len() <= MAX_MESSAGE_LEN => {
+                last.ids.push(message.id);
+                last.payload.push('\n');
+                last.payload.push_str(&message.payload);
+                last.silent &= message.silent;
+                last.attempts = cmp::max(last.attempts, message.attempts);
+            }
+            _ => res.push(Message::from(message)),
+        }
+    }
+
+    res
+}
+
+/// Make a single delivery attempt and record its result.
+async fn deliver(bot: &Bot, db: &Database, cfg: &Config, scheduler: &Scheduler, message: Message) {
+    let res = bot
+        .send_message(message.chat_id, &message.payload)
+        .disable_web_page_preview(true)
+        .disable_notification(message.silent)
+        .await;
+
+    match res {
+        Ok(_) => {
+            for &id in &message.ids {
+                if let Err(err) = db.message_delivered(id).await {
+                    error!("db error while updating message#{}: {}", id, err);
+                }
+            }
+        }
+        // Not a failure of the message, just wait exactly as much as asked
+        Err(RequestError::RetryAfter(secs)) => {
+            let delay = Duration::from_secs(secs as u64);
+            scheduler.retry_after(delay);
+            postpone(db, &message, delay).await;
+        }
+        Err(err) => {
+            let attempts = message.attempts + 1;
+            let delay = backoff(cfg.retry_delay.0, attempts);
+
+            if attempts >= MAX_ATTEMPTS {
+                error!(
+                    "giving up on messages{:?} to {} after {} attempts: {}",
+                    message.ids, message.chat_id, attempts, err
+                );
+            } else {
+                warn!(
+                    "error while trying to send messages{:?} to {} (attempt {}): {}",
+                    message.ids, message.chat_id, attempts, err
+                );
+            }
+
+            let next_attempt_at = SystemTime::now() + delay;
+            for &id in &message.ids {
+                if let Err(err) = db
+                    .message_failed(id, &err.to_string(), next_attempt_at)
+                    .await
+                {
+                    error!("db error while updating message#{}: {}", id, err);
+                }
+            }
+        }
+    }
+}
+
+async fn postpone(db: &Database, message: &Message, delay: Duration) {
+    let next_attempt_at = SystemTime::now() + delay;
+    for &id in &message.ids {
+        if let Err(err) = db.postpone_message(id, next_attempt_at).await {
+            error!("db error while postponing message#{}: {}", id, err);
+        }
+    }
+}
+
+/// `base * 2^(attempts - 1)`, but not more than [`MAX_BACKOFF`].
+fn backoff(base: Duration, attempts: i32) -> Duration {
+    let factor = 1u32 << cmp::min(attempts.saturating_sub(1), 16) as u32;
+    cmp::min(base.saturating_mul(factor), MAX_BACKOFF)
+}
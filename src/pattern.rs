@@ -0,0 +1,289 @@
+//! Patterns of crate names.
+use std::{fmt, str::FromStr, sync::Mutex};
+
+use log::warn;
+use regex::{Regex, RegexSet};
+use serde::{de, Deserialize, Deserializer};
+
+use crate::util::normalize_crate_name;
+
+/// Pattern of crate names: either a glob (`tokio-*`, `?ac`) or a regex
+/// surrounded by slashes (`/^async[-_]/`) or anchored with `^`/`$`
+/// (`^bevy_`).
+///
+/// Like crate names on crates.io, matching is case-insensitive and doesn't
+/// distinguish `-` from `_`: both the pattern and the name are normalized (see
+/// [`normalize_crate_name`]).
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    /// The pattern as written by the user
+    source: String,
+    regex: Regex,
+}
+
+impl Pattern {
+    pub fn matches(&self, name: &str) -> bool {
+        self.regex.is_match(&normalize_crate_name(name))
+    }
+
+    /// The exact crate name this pattern matches, if it's a glob without
+    /// wildcards. Spelled as written by the user, which may differ from the
+    /// name the crate is published with in case and `-`/`_`.
+    pub fn literal(&self) -> Option<&str> {
+        let source = self.source.as_str();
+        let regex = source.starts_with('/') || source.starts_with('^') || source.ends_with('$');
+        let wildcard = source.contains(&['*', '?'][..]);
+
+        (!regex && !wildcard).then(|| source)
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = regex::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let source = s.trim().to_owned();
+
+        let regex = match source.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+            Some(regex) => format!("(?i){}", normalize_regex(regex)),
+            // Crate names can't contain `^` and `$`, so this can't be a glob
+            None if source.starts_with('^') || source.ends_with('$') => {
+                format!("(?i){}", normalize_regex(&source))
+            }
+            None => format!("(?i)^{}$", glob_to_regex(&normalize_crate_name(&source))),
+        };
+
+        Ok(Self {
+            regex: Regex::new(&regex)?,
+            source,
+        })
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Patterns stored elsewhere (e.g. in the database) compiled into a single
+/// [`RegexSet`], which is recompiled only when the patterns change.
+#[derive(Debug, Default)]
+pub struct PatternCache {
+    compiled: Mutex<Option<(Vec<String>, Compiled)>>,
+}
+
+#[derive(Debug)]
+enum Compiled {
+    Set(RegexSet),
+    /// Patterns which are too big to be compiled together (each of them was
+    /// compiled on its own, so only the size of the set can be the problem)
+    Each(Vec<Regex>),
+}
+
+impl PatternCache {
+    /// Returns indices of `patterns` that match `name`.
+    pub fn matches(&self, patterns: &[String], name: &str) -> Result<Vec<usize>, regex::Error> {
+        let mut compiled = self.compiled.lock().unwrap();
+
+        if compiled
+            .as_ref()
+            .map_or(true, |(cached, _)| cached != patterns)
+        {
+            let regexes = patterns
+                .iter()
+                .map(|p| p.parse::<Pattern>().map(|p| p.regex))
+                .collect::<Result<Vec<_>, _>>()?;
+            let set = match RegexSet::new(regexes.iter().map(Regex::as_str)) {
+                Ok(set) => Compiled::Set(set),
+                Err(err) => {
+                    warn!("matching {} patterns one by one: {}", regexes.len(), err);
+                    Compiled::Each(regexes)
+                }
+            };
+            *compiled = Some((patterns.to_vec(), set));
+        }
+
+        let (_, compiled) = compiled.as_ref().expect("just compiled");
+        Ok(compiled.matches(&normalize_crate_name(name)))
+    }
+}
+
+impl Compiled {
+    fn matches(&self, name: &str) -> Vec<usize> {
+        match self {
+            Self::Set(set) => set.matches(name).into_iter().collect(),
+            Self::Each(regexes) => regexes
+                .iter()
+                .enumerate()
+                .filter(|(_, regex)| regex.is_match(name))
+                .map(|(i, _)| i)
+                .collect(),
+        }
+    }
+}
+
+/// Translate a glob (`*` - any number of characters, `?` - any single
+/// character) into a regex.
+fn glob_to_regex(glob: &str) -> String {
+    let mut res = String::with_capacity(glob.len() * 2);
+    for c in glob.chars() {
+        match c {
+            '*' => res.push_str(".*"),
+            '?' => res.push('.'),
+            c => res.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+
+    res
+}
+
+/// Replace `-` with `_` in a regex, so it matches normalized names (see
+/// [`normalize_crate_name`]). Hyphens in character classes are kept since
+/// they may be ranges (e.g. `[a-z]`).
+fn normalize_regex(regex: &str) -> String {
+    let mut res = String::with_capacity(regex.len());
+    let mut class = false;
+    let mut chars = regex.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('-') if !class => res.push('_'),
+                Some(escaped) => {
+                    res.push(c);
+                    res.push(escaped);
+                }
+                None => res.push(c),
+            },
+            '[' => {
+                class = true;
+                res.push(c);
+            }
+            ']' => {
+                class = false;
+                res.push(c);
+            }
+            '-' if !class => res.push('_'),
+            c => res.push(c),
+        }
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::{Regex, RegexSet};
+
+    use super::{glob_to_regex, normalize_regex, Compiled, Pattern, PatternCache};
+
+    fn pattern(s: &str) -> Pattern {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn globs() {
+        assert_eq!(glob_to_regex("tokio_*"), "tokio_.*");
+        assert_eq!(glob_to_regex("?ac"), ".ac");
+        assert_eq!(glob_to_regex("a.b"), "a\\.b");
+
+        let p = pattern("tokio-*");
+        assert!(p.matches("tokio-util"));
+        assert!(p.matches("Tokio-Stream"));
+        assert!(p.matches("tokio-"));
+        assert!(!p.matches("tokio"));
+        assert!(p.matches("tokio_util"));
+        assert!(!p.matches("my-tokio-util"));
+
+        let p = pattern("Serde_*");
+        assert_eq!(p.regex.as_str(), "(?i)^serde_.*$");
+        assert!(p.matches("serde-json"));
+
+        let p = pattern("?ac");
+        assert!(p.matches("mac"));
+        assert!(!p.matches("ac"));
+        assert!(!p.matches("macro"));
+    }
+
+    #[test]
+    fn regexes() {
+        // Anchored patterns are regexes, not globs
+        let p = pattern("^bevy_");
+        assert_eq!(p.regex.as_str(), "(?i)^bevy_");
+        assert!(p.matches("bevy_ecs"));
+        assert!(p.matches("Bevy_Render"));
+        assert!(!p.matches("my_bevy_ecs"));
+        assert!(p.matches("bevy-ecs"));
+
+        let p = pattern("/^async-[a-z]+$/");
+        assert_eq!(p.regex.as_str(), "(?i)^async_[a-z]+$");
+        assert!(p.matches("async_std"));
+        assert!(p.matches("Async-Std"));
+        assert!(!p.matches("async-std-2"));
+
+        let p = pattern("_derive$");
+        assert!(p.matches("serde_derive"));
+        assert!(!p.matches("serde_derive_internals"));
+
+        // Regexes between slashes aren't anchored
+        let p = pattern(" /async/ ");
+        assert_eq!(p.regex.as_str(), "(?i)async");
+        assert_eq!(p.to_string(), "/async/");
+        assert!(p.matches("async-std"));
+        assert!(p.matches("tokio-ASYNC-util"));
+        assert!(!p.matches("tokio"));
+
+        assert!("/[a-/".parse::<Pattern>().is_err());
+        assert!("^(bevy".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn normalized_regexes() {
+        assert_eq!(normalize_regex("^tokio-"), "^tokio_");
+        assert_eq!(normalize_regex(r"^tokio\-"), "^tokio_");
+        assert_eq!(normalize_regex(r"[a-z\]-]-"), r"[a-z\]-]_");
+        assert_eq!(normalize_regex(r"\d+"), r"\d+");
+    }
+
+    #[test]
+    fn cache() {
+        let cache = PatternCache::default();
+        let patterns = [
+            "tokio-*".to_owned(),
+            "/async/".to_owned(),
+            "^bevy_".to_owned(),
+        ];
+
+        assert_eq!(cache.matches(&patterns, "tokio-async").unwrap(), [0, 1]);
+        assert_eq!(cache.matches(&patterns, "bevy_async").unwrap(), [1, 2]);
+        assert_eq!(cache.matches(&patterns, "Tokio_Util").unwrap(), [0]);
+        assert!(cache.matches(&patterns, "serde").unwrap().is_empty());
+
+        // Changed patterns are recompiled
+        let patterns = ["serde*".to_owned()];
+        assert_eq!(cache.matches(&patterns, "serde").unwrap(), [0]);
+
+        assert!(cache.matches(&["/(/".to_owned()], "serde").is_err());
+    }
+
+    #[test]
+    fn matching_one_by_one() {
+        let regexes: Vec<_> = ["tokio-*", "/async/", "^bevy_"]
+            .iter()
+            .map(|p| pattern(p).regex)
+            .collect();
+        let set = Compiled::Set(RegexSet::new(regexes.iter().map(Regex::as_str)).unwrap());
+        let each = Compiled::Each(regexes);
+
+        for name in &["tokio-async", "bevy_async", "serde"] {
+            assert_eq!(set.matches(name), each.matches(name));
+        }
+    }
+}
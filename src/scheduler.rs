@@ -0,0 +1,204 @@
+//! Scheduling of outgoing messages according to telegram rate limits.
+//!
+//! Telegram allows ~30 messages per second in total, 1 message per second to a
+//! private chat and 20 messages per minute to a group or channel (see
+//! [bots faq]). Sending faster results in `RetryAfter` errors.
+//!
+//! [`Scheduler`] hands out time slots: every slot is at least
+//! `1 / global_per_second` apart from any other slot and slots of a single chat
+//! are at least the per-chat interval apart. Since messages to different chats
+//! get different slots, they can be sent concurrently.
+//!
+//! `RetryAfter` is a flood wait of the whole bot, so it pauses sending to all
+//! chats (see [`Scheduler::retry_after`]).
+//!
+//! [bots faq]: https://core.telegram.org/bots/faq#my-bot-is-hitting-limits-how-do-i-avoid-this
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::cfg::RateLimitConfig;
+
+/// Chats are forgotten once the map grows over this size (only chats without
+/// reserved slots in the future are forgotten).
+const MAX_TRACKED_CHATS: usize = 1024;
+
+pub struct Scheduler {
+    /// Minimal interval between any 2 messages
+    global_interval: Duration,
+    /// Minimal interval between 2 messages to a single private chat
+    private_interval: Duration,
+    /// Minimal interval between 2 messages to a single group or channel
+    group_interval: Duration,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Origin of global ticks
+    start: Instant,
+    /// Reserved global ticks (a tick is `global_interval` long)
+    ticks: BTreeSet<u64>,
+    /// Chat id -> the earliest instant at which the next message may be sent
+    chats: HashMap<i64, Instant>,
+    /// Nothing may be sent before this instant (see
+    /// [`Scheduler::retry_after`])
+    paused_until: Option<Instant>,
+}
+
+impl Scheduler {
+    pub fn new(cfg: &RateLimitConfig) -> Self {
+        let per = |period: Duration, n: u32| period / n.max(1);
+
+        Self {
+            global_interval: per(Duration::from_secs(1), cfg.global_per_second),
+            private_interval: per(Duration::from_secs(1), cfg.private_per_second),
+            group_interval: per(Duration::from_secs(60), cfg.group_per_minute),
+            state: Mutex::new(State {
+                start: Instant::now(),
+                ticks: BTreeSet::new(),
+                chats: HashMap::new(),
+                paused_until: None,
+            }),
+        }
+    }
+
+    /// Reserve a slot for a message to `chat_id`.
+    ///
+    /// Returns the instant at which the message may be sent. Slots of a single
+    /// chat are handed out in order of reservation.
+    pub fn reserve(&self, chat_id: i64) -> Instant {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        let not_before = state.not_before(chat_id, now);
+
+        // Forget the past
+        let now_tick = ticks(now - state.start, self.global_interval);
+        state.ticks = state.ticks.split_off(&now_tick);
+
+        let mut tick = ticks(not_before - state.start, self.global_interval);
+        while state.ticks.contains(&tick) {
+            tick += 1;
+        }
+        state.ticks.insert(tick);
+
+        let at = state.start + mul(self.global_interval, tick);
+
+        if state.chats.len() >= MAX_TRACKED_CHATS {
+            state.chats.retain(|_, next| *next > now);
+        }
+        state
+            .chats
+            .insert(chat_id, at + self.chat_interval(chat_id));
+
+        at
+    }
+
+    /// Returns the instant at which a message to `chat_id` would be sent, if it
+    /// was reserved now (without reserving it).
+    pub fn next_slot(&self, chat_id: i64) -> Instant {
+        let state = self.state.lock().unwrap();
+
+        state.not_before(chat_id, Instant::now())
+    }
+
+    /// Don't send anything to any chat for `delay` (as requested by telegram
+    /// with `RetryAfter`, which limits the whole bot rather than a single
+    /// chat).
+    pub fn retry_after(&self, delay: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let until = Instant::now() + delay;
+
+        state.paused_until = Some(state.paused_until.map_or(until, |at| at.max(until)));
+    }
+
+    /// Returns the instant until which sending is paused by
+    /// [`Scheduler::retry_after`], if it's in the future.
+    ///
+    /// Slots reserved before the pause may fall into it, so this must be
+    /// checked right before sending.
+    pub fn paused_until(&self) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+
+        state.paused_until.filter(|&at| at > Instant::now())
+    }
+
+    fn chat_interval(&self, chat_id: i64) -> Duration {
+        // Ids of groups and channels are negative
+        if chat_id < 0 {
+            self.group_interval
+        } else {
+            self.private_interval
+        }
+    }
+}
+
+impl State {
+    /// The earliest instant at which a message to `chat_id` may be sent.
+    fn not_before(&self, chat_id: i64, now: Instant) -> Instant {
+        let now = self.paused_until.map_or(now, |at| at.max(now));
+        self.chats.get(&chat_id).map_or(now, |&at| at.max(now))
+    }
+}
+
+/// Number of `interval`s in `duration`, rounded up.
+fn ticks(duration: Duration, interval: Duration) -> u64 {
+    let interval = interval.as_nanos().max(1);
+    ((duration.as_nanos() + interval - 1) / interval) as u64
+}
+
+fn mul(interval: Duration, ticks: u64) -> Duration {
+    Duration::from_nanos((interval.as_nanos() * ticks as u128) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Scheduler;
+    use crate::cfg::RateLimitConfig;
+
+    #[test]
+    fn slots_respect_limits() {
+        let scheduler = Scheduler::new(&RateLimitConfig::default());
+        let start = Instant::now();
+
+        let (first, second) = (scheduler.reserve(1), scheduler.reserve(1));
+        assert!(second >= first + Duration::from_secs(1));
+
+        // Other chats aren't affected by the per-chat interval, only by the
+        // global one
+        let other = scheduler.reserve(2);
+        assert!(other < start + Duration::from_secs(1));
+        assert_ne!(other, first);
+    }
+
+    #[test]
+    fn retry_after_pauses_all_chats() {
+        let scheduler = Scheduler::new(&RateLimitConfig::default());
+        let delay = Duration::from_secs(10);
+
+        let before = Instant::now();
+        scheduler.retry_after(delay);
+        // A shorter wait doesn't shorten the pause
+        scheduler.retry_after(Duration::from_secs(1));
+
+        assert!(scheduler.paused_until().unwrap() >= before + delay);
+        for chat_id in [1, 2, -3] {
+            assert!(scheduler.next_slot(chat_id) >= before + delay);
+            assert!(scheduler.reserve(chat_id) >= before + delay);
+        }
+    }
+
+    #[test]
+    fn pause_ends() {
+        let scheduler = Scheduler::new(&RateLimitConfig::default());
+
+        scheduler.retry_after(Duration::ZERO);
+
+        assert_eq!(scheduler.paused_until(), None);
+        assert!(scheduler.reserve(1) < Instant::now() + Duration::from_secs(1));
+    }
+}
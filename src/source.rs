@@ -0,0 +1,174 @@
+//! Sources of index updates.
+//!
+//! A source produces a stream of [`Update`]s. The notifier processes updates
+//! one by one and acknowledges each of them by dropping it. Sources wait for
+//! the acknowledgement before moving on, so they can record the progress only
+//! after the update was actually processed.
+use std::{
+    convert::Infallible,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{
+    future,
+    stream::{BoxStream, StreamExt},
+};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::krate::{ActionKind, Crate};
+
+pub use self::{git::GitSource, replay::ReplaySource, sparse::SparseSource};
+
+mod git;
+mod replay;
+pub mod sparse;
+
+/// Stop signal of a source (see [`future::abortable`]).
+pub type Stop = future::Abortable<future::Pending<()>>;
+
+/// A single update of the index.
+///
+/// Dropping the update acknowledges that it was processed.
+pub struct Update {
+    pub krate: Crate,
+    pub action: ActionKind,
+    /// The version published right before `krate` (only for new versions and
+    /// only if the source knows it)
+    pub previous: Option<Crate>,
+    /// The update is the first version of a new crate (only if the source can
+    /// tell it apart from a crate it didn't see before)
+    pub new_crate: bool,
+    /// Identifier of the change that produced the update (e.g. commit of the
+    /// git index), used to deduplicate notifications when the same change is
+    /// processed twice
+    pub origin: String,
+    _ack: oneshot::Sender<Infallible>,
+}
+
+pub trait UpdateSource {
+    /// Start producing updates.
+    ///
+    /// The stream ends when the source is exhausted or after `stop` is
+    /// aborted.
+    fn updates(self: Box<Self>, stop: Stop) -> BoxStream<'static, Update>;
+}
+
+/// Sending half of an update stream, see [`channel`].
+#[derive(Clone)]
+struct Emitter {
+    tx: mpsc::Sender<Update>,
+}
+
+impl Emitter {
+    /// Send an update & wait until it's processed.
+    ///
+    /// Returns `false` if the stream was dropped.
+    async fn emit(
+        &self,
+        origin: &str,
+        krate: Crate,
+        action: ActionKind,
+        previous: Option<Crate>,
+        new_crate: bool,
+    ) -> bool {
+        let (tx, rx) = oneshot::channel();
+        let update = Update {
+            krate,
+            action,
+            previous,
+            new_crate,
+            origin: origin.to_owned(),
+            _ack: tx,
+        };
+
+        if self.tx.send(update).await.is_err() {
+            return false;
+        }
+
+        // Wait untill the crate is processed before moving on
+        let _ = rx.await;
+        true
+    }
+
+    /// Blocking version of [`Emitter::emit`], for use outside of the tokio
+    /// runtime.
+    fn blocking_emit(
+        &self,
+        origin: &str,
+        krate: Crate,
+        action: ActionKind,
+        previous: Option<Crate>,
+        new_crate: bool,
+    ) -> bool {
+        let (tx, mut rx) = oneshot::channel();
+        let update = Update {
+            krate,
+            action,
+            previous,
+            new_crate,
+            origin: origin.to_owned(),
+            _ack: tx,
+        };
+
+        if self.tx.blocking_send(update).is_err() {
+            return false;
+        }
+
+        // Wait untill the crate is processed before moving on
+        while let Err(oneshot::error::TryRecvError::Empty) = rx.try_recv() {
+            // Yeild/sleep to not spend all resources
+            std::thread::sleep(Duration::from_secs(1));
+        }
+
+        true
+    }
+}
+
+fn channel() -> (Emitter, BoxStream<'static, Update>) {
+    let (tx, rx) = mpsc::channel(2);
+    (Emitter { tx }, ReceiverStream::new(rx).boxed())
+}
+
+/// Seconds since the unix epoch.
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Wait for `delay`, checking `stop` every few seconds.
+///
+/// Returns `false` if `stop` was aborted.
+fn blocking_wait(delay: Duration, stop: &Stop) -> bool {
+    let mut delay = delay;
+    const STEP: Duration = Duration::from_secs(5);
+
+    while delay > Duration::ZERO {
+        if stop.is_aborted() {
+            return false;
+        }
+
+        delay = delay.saturating_sub(STEP);
+        std::thread::sleep(STEP);
+    }
+
+    true
+}
+
+/// Async version of [`blocking_wait`].
+pub async fn wait(delay: Duration, stop: &Stop) -> bool {
+    let mut delay = delay;
+    const STEP: Duration = Duration::from_secs(5);
+
+    while delay > Duration::ZERO {
+        if stop.is_aborted() {
+            return false;
+        }
+
+        delay = delay.saturating_sub(STEP);
+        tokio::time::sleep(STEP).await;
+    }
+
+    true
+}
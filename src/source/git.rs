@@ -0,0 +1,752 @@
+//! Source of updates that walks commits of the crates.io-index git repository.
+//!
+//! crates.io periodically squashes the history of the index. When this happens
+//! the last processed commit is no longer an ancestor of the remote head, so
+//! instead of walking commits the trees are compared directly (see
+//! [`reconcile`]).
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    iter, mem,
+    path::{Path, PathBuf},
+    str,
+    time::Duration,
+};
+
+use arraylib::Slice;
+use fntools::value::ValueExt;
+use futures::{executor::block_on, stream::BoxStream};
+use git2::{
+    Commit, Delta, Diff, DiffOptions, ObjectType, Oid, Repository, ResetType, Sort, TreeWalkMode,
+    TreeWalkResult,
+};
+use log::{error, info, warn};
+
+use crate::{
+    db::Database,
+    krate::{ActionKind, Crate},
+    source::{blocking_wait, channel, Emitter, Stop, Update, UpdateSource},
+    util::crate_path,
+};
+
+pub struct GitSource {
+    repo: Repository,
+    db: Database,
+    pull_delay: Duration,
+}
+
+impl GitSource {
+    /// Open the local index repository, cloning it from `url` if it doesn't
+    /// exist yet.
+    pub fn open(url: &str, path: &str, db: Database, pull_delay: Duration) -> Self {
+        let repo = Repository::open(path).unwrap_or_else(move |_| {
+            info!("start cloning");
+            Repository::clone(url, path)
+                .unwrap()
+                .also(|_| info!("cloning finished"))
+        });
+
+        Self {
+            repo,
+            db,
+            pull_delay,
+        }
+    }
+}
+
+impl UpdateSource for GitSource {
+    fn updates(self: Box<Self>, stop: Stop) -> BoxStream<'static, Update> {
+        let (emitter, updates) = channel();
+
+        // git2 is blocking, so all work is done on a separate (non-tokio) thread
+        std::thread::spawn(move || {
+            if let Err(err) = init_reverse_deps(&self.repo, &self.db) {
+                error!("couldn't build the reverse dependency index: {}", err);
+            }
+
+            loop {
+                info!("start pulling updates");
+
+                if let Err(err) = pull(&self.repo, &self.db, &emitter) {
+                    error!("couldn't pull new crate version from the index: {}", err);
+                }
+
+                info!("pulling updates finished");
+
+                // delay for `config.pull_delay` (default 5 min)
+                if !blocking_wait(self.pull_delay, &stop) {
+                    break;
+                }
+            }
+        });
+
+        updates
+    }
+}
+
+/// Fast-Forward (FF) to a given commit.
+///
+/// Implementation is taken from <https://stackoverflow.com/a/58778350>.
+fn fast_forward(repo: &Repository, commit: &git2::Commit) -> Result<(), git2::Error> {
+    let fetch_commit = repo.find_annotated_commit(commit.id())?;
+    let analysis = repo.merge_analysis(&[&fetch_commit])?;
+
+    if analysis.0.is_up_to_date() {
+        Ok(())
+    } else if analysis.0.is_fast_forward() {
+        let mut reference = repo.find_reference("refs/heads/master")?;
+        reference.set_target(fetch_commit.id(), "Fast-Forward")?;
+        repo.set_head(reference.name().unwrap())?;
+        repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))
+    } else {
+        Err(git2::Error::from_str("Fast-forward only!"))
+    }
+}
+
+/// Hard reset the local branch to a given commit, in case it's impossible to
+/// [`fast_forward`] (e.g. after the index was squashed).
+fn reset(repo: &Repository, commit: &Commit) -> Result<(), git2::Error> {
+    repo.reset(
+        commit.as_object(),
+        ResetType::Hard,
+        Some(git2::build::CheckoutBuilder::default().force()),
+    )
+}
+
+#[derive(Debug, derive_more::Display, derive_more::From)]
+enum PullError {
+    Git(git2::Error),
+    Db(tokio_postgres::Error),
+}
+
+fn pull(repo: &Repository, db: &Database, emitter: &Emitter) -> Result<(), PullError> {
+    // fetch changes from remote index
+    repo.find_remote("origin")?.fetch(&["master"], None, None)?;
+
+    let start = resume_point(repo, db)?;
+    let fetch_head = repo.find_reference("FETCH_HEAD")?.peel_to_commit()?;
+
+    let mut opts = DiffOptions::default();
+    let opts = opts.context_lines(0).minimal(true);
+
+    if start.id() != fetch_head.id() && !repo.graph_descendant_of(fetch_head.id(), start.id())? {
+        // The history was rewritten (squashed or force-pushed), there are no
+        // commits to walk through
+        return reconcile(repo, db, emitter, opts, (&start, &fetch_head));
+    }
+
+    // Collect all commits in the range `start..FETCH_HEAD` and prepend `start`
+    // itself (i.e. the last processed commit and all commits after it)
+    let mut walk = repo.revwalk()?;
+    walk.push(fetch_head.id())?;
+    walk.hide(start.id())?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    let commits: Result<Vec<_>, _> = iter::once(Ok(start))
+        .chain(walk.map(|oid| repo.find_commit(oid?)))
+        .collect();
+
+    for [prev, next] in Slice::array_windows::<[_; 2]>(&commits?[..]) {
+        if !process(repo, db, emitter, opts, (prev, next))? {
+            return Ok(());
+        }
+
+        // Remember that the commit was processed & 'move' to it
+        block_on(db.set_index_cursor(&next.id().to_string()))?;
+        fast_forward(repo, next)?;
+    }
+
+    Ok(())
+}
+
+/// Send all updates between 2 commits to the notifier.
+///
+/// Updates are recognized by the content of the diff (not by the author of the
+/// commit). Commits that contain changes, but no recognizable updates are
+/// skipped & recorded in the database for later review.
+///
+/// Returns `false` if the update stream was dropped.
+fn process(
+    repo: &Repository,
+    db: &Database,
+    emitter: &Emitter,
+    opts: &mut DiffOptions,
+    (prev, next): (&Commit, &Commit),
+) -> Result<bool, PullError> {
+    let diff = repo.diff_tree_to_tree(Some(&prev.tree()?), Some(&next.tree()?), Some(opts))?;
+    let DiffUpdates { updates, unparsed } = diff_one(repo, diff, (prev, next))?;
+
+    if updates.is_empty() && !unparsed.is_empty() {
+        let author = next.author();
+        let message = next.message().map(|m| m.trim_end_matches('\n'));
+
+        warn!(
+            "Skip unparseable commit#{} from @{}: {}",
+            next.id(),
+            author.name().unwrap_or("<invalid utf-8>"),
+            message.unwrap_or("<invalid utf-8>"),
+        );
+
+        let reason = unparsed.join("\n");
+        block_on(db.add_skipped_commit(&next.id().to_string(), author.name(), message, &reason))?;
+    }
+
+    // Keep the reverse-dependency index up to date, before notifying
+    // subscribers of dependents
+    let deps: BTreeMap<_, _> = updates
+        .iter()
+        .filter_map(|(krate, action, _, _)| match action {
+            ActionKind::NewVersion => Some((krate.id.name.clone(), krate.dependency_names())),
+            ActionKind::CrateDeleted => Some((krate.id.name.clone(), BTreeSet::new())),
+            _ => None,
+        })
+        .collect();
+    if !deps.is_empty() {
+        set_dependencies(db, &deps)?;
+    }
+
+    let origin = next.id().to_string();
+    for (krate, action, previous, new_crate) in updates {
+        // Send crates.io update to notifier
+        if !emitter.blocking_emit(&origin, krate, action, previous, new_crate) {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Recover from a rewrite of the index history (squash or force-push).
+///
+/// Trees of the last processed commit and of the new remote head are compared
+/// file by file, so only the real changes in between are reported. After that
+/// the local branch is reset to the new head.
+fn reconcile(
+    repo: &Repository,
+    db: &Database,
+    emitter: &Emitter,
+    opts: &mut DiffOptions,
+    (old, new): (&Commit, &Commit),
+) -> Result<(), PullError> {
+    warn!(
+        "index history was rewritten ({} is not an ancestor of {}), reconciling trees",
+        old.id(),
+        new.id(),
+    );
+
+    if !process(repo, db, emitter, opts, (old, new))? {
+        return Ok(());
+    }
+
+    // Remember that the commit was processed & 'move' to it
+    block_on(db.set_index_cursor(&new.id().to_string()))?;
+    reset(repo, new)?;
+
+    info!("reconciled index history, now at {}", new.id());
+
+    Ok(())
+}
+
+/// Build the reverse-dependency index from the checkout (`HEAD`), unless it
+/// was already built. After that it's updated by [`process`].
+///
+/// The index is marked as built together with the last batch, so if the build
+/// is interrupted, it's started over on the next start.
+///
+/// Dependencies of a crate are the ones of its last line (the most recently
+/// published version).
+fn init_reverse_deps(repo: &Repository, db: &Database) -> Result<(), PullError> {
+    const BATCH: usize = 1000;
+
+    if block_on(db.has_reverse_deps())? {
+        return Ok(());
+    }
+
+    info!("start building the reverse dependency index");
+
+    let tree = repo.head()?.peel_to_tree()?;
+    let mut batch = BTreeMap::new();
+    let mut res = Ok(());
+    let walked = tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        let path = match entry.name() {
+            Some(name) if entry.kind() == Some(ObjectType::Blob) => Path::new(root).join(name),
+            _ => return TreeWalkResult::Ok,
+        };
+
+        if !is_crate_file(&path) {
+            return TreeWalkResult::Ok;
+        }
+
+        if let Some(krate) = last_entry(repo, entry.id()) {
+            let deps = krate.dependency_names();
+            batch.insert(krate.id.name, deps);
+        }
+
+        if batch.len() < BATCH {
+            return TreeWalkResult::Ok;
+        }
+
+        match set_dependencies(db, &mem::take(&mut batch)) {
+            Ok(()) => TreeWalkResult::Ok,
+            Err(err) => {
+                res = Err(err);
+                TreeWalkResult::Abort
+            }
+        }
+    });
+    res?;
+    walked?;
+
+    let (crates, dependents, dependencies) = dependency_columns(&batch);
+    block_on(db.finish_reverse_deps(&crates, &dependents, &dependencies))?;
+
+    info!("building the reverse dependency index finished");
+
+    Ok(())
+}
+
+/// Replace dependencies of crates in the reverse-dependency index.
+fn set_dependencies(
+    db: &Database,
+    deps: &BTreeMap<String, BTreeSet<String>>,
+) -> Result<(), tokio_postgres::Error> {
+    let (crates, dependents, dependencies) = dependency_columns(deps);
+    block_on(db.set_dependencies(&crates, &dependents, &dependencies))
+}
+
+/// Split dependencies of crates into columns: names of the crates and pairs
+/// of `(dependent, dependency)`.
+fn dependency_columns(
+    deps: &BTreeMap<String, BTreeSet<String>>,
+) -> (Vec<&str>, Vec<&str>, Vec<&str>) {
+    let crates = deps.keys().map(String::as_str).collect();
+    let (dependents, dependencies) = deps
+        .iter()
+        .flat_map(|(name, deps)| deps.iter().map(move |dep| (name.as_str(), dep.as_str())))
+        .unzip();
+
+    (crates, dependents, dependencies)
+}
+
+/// Find the commit from which the walk should be resumed.
+///
+/// The last processed commit is stored in the database, local `HEAD` is only
+/// used when the database doesn't know it (e.g. on the first run) or when the
+/// stored commit is missing from the local repository.
+fn resume_point<'r>(repo: &'r Repository, db: &Database) -> Result<Commit<'r>, PullError> {
+    let head = repo.head()?.peel_to_commit()?;
+
+    let cursor = match block_on(db.index_cursor())? {
+        Some(cursor) => cursor,
+        None => {
+            info!(
+                "there is no index cursor in the db, starting from HEAD ({})",
+                head.id()
+            );
+            block_on(db.set_index_cursor(&head.id().to_string()))?;
+            return Ok(head);
+        }
+    };
+
+    match Oid::from_str(&cursor.commit).and_then(|oid| repo.find_commit(oid)) {
+        Ok(commit) => {
+            if commit.id() != head.id() {
+                warn!(
+                    "index cursor ({}) doesn't match local HEAD ({}), resuming from the cursor",
+                    commit.id(),
+                    head.id(),
+                );
+            }
+
+            Ok(commit)
+        }
+        Err(err) => {
+            error!(
+                "index cursor ({}, processed {:?} ago) is not found in the local index \
+                 repository ({}), falling back to HEAD ({})",
+                cursor.commit,
+                cursor.processed_at.elapsed().unwrap_or_default(),
+                err,
+                head.id(),
+            );
+
+            Ok(head)
+        }
+    }
+}
+
+/// Changes of a single crate file.
+#[derive(Default)]
+struct FileChange {
+    /// The file was deleted
+    deleted: bool,
+    /// The file was added (i.e. it's a new crate)
+    added_file: bool,
+    /// The last line of the file before the change
+    previous: Option<Crate>,
+    /// Deleted lines
+    removed: Vec<Crate>,
+    /// Added lines
+    added: Vec<Crate>,
+}
+
+/// Result of [`diff_one`].
+struct DiffUpdates {
+    /// Updates with the previous versions of new versions and whether they
+    /// are the first versions of new crates
+    updates: Vec<(Crate, ActionKind, Option<Crate>, bool)>,
+    /// Descriptions of changed lines that couldn't be parsed
+    unparsed: Vec<String>,
+}
+
+/// Get `crates.io` updates from a diff of 2 consecutive commits from a
+/// `crates.io-index` repository.
+///
+/// A commit may change any number of lines in any number of files (e.g. bulk
+/// yanks or index maintenance). Files that are not crate files (e.g.
+/// `config.json`) are ignored, lines that can't be parsed are logged &
+/// collected into [`DiffUpdates::unparsed`].
+fn diff_one(
+    repo: &Repository,
+    diff: Diff,
+    commits: (&Commit, &Commit),
+) -> Result<DiffUpdates, git2::Error> {
+    let mut files = BTreeMap::<PathBuf, FileChange>::new();
+    let mut unparsed = Vec::new();
+
+    diff.foreach(
+        &mut |_, _| true,
+        None,
+        None,
+        Some(&mut |delta, _hunk, line| {
+            let (path, deleted, added_file) = match delta.status() {
+                // New version of a crate, (un)yanked or deleted versions
+                Delta::Modified => (delta.new_file().path(), false, false),
+                // The first version of a new crate
+                Delta::Added => (delta.new_file().path(), false, true),
+                // The whole crate was deleted
+                Delta::Deleted => (delta.old_file().path(), true, false),
+                delta => {
+                    warn!("Unexpected delta: {:?}", delta);
+                    return true;
+                }
+            };
+
+            let path = match path {
+                Some(path) if is_crate_file(path) => path,
+                _ => return true,
+            };
+
+            let change = files.entry(path.to_owned()).or_insert_with(|| FileChange {
+                previous: match deleted {
+                    false => last_entry(repo, delta.old_file().id()),
+                    true => None,
+                },
+                ..FileChange::default()
+            });
+            change.deleted = deleted;
+            change.added_file = added_file;
+
+            let lines = match line.origin() {
+                '-' => &mut change.removed,
+                '+' => &mut change.added,
+                _ => return true, /* don't care */
+            };
+
+            let krate = str::from_utf8(line.content())
+                .map_err(|err| err.to_string())
+                .and_then(|krate| {
+                    serde_json::from_str::<Crate>(krate).map_err(|err| err.to_string())
+                });
+
+            match krate {
+                Ok(krate) => lines.push(krate),
+                Err(err) => {
+                    warn!(
+                        "Couldn't deserialize crate from {:?} ({} -> {}): {}",
+                        path,
+                        commits.0.id(),
+                        commits.1.id(),
+                        err,
+                    );
+                    unparsed.push(format!("{}: {}", path.display(), err));
+                }
+            }
+
+            true
+        }),
+    )?;
+
+    Ok(DiffUpdates {
+        updates: files.into_values().flat_map(file_updates).collect(),
+        unparsed,
+    })
+}
+
+/// Read the last line of a crate file blob, that is the latest published
+/// version.
+///
+/// Returns `None` for new files (zero `oid`) and blobs that can't be read.
+fn last_entry(repo: &Repository, oid: Oid) -> Option<Crate> {
+    if oid.is_zero() {
+        return None;
+    }
+
+    let blob = repo
+        .find_blob(oid)
+        .map_err(|err| warn!("Couldn't find blob {}: {}", oid, err))
+        .ok()?;
+    let line = str::from_utf8(blob.content())
+        .ok()?
+        .lines()
+        .rev()
+        .find(|line| !line.is_empty())?;
+
+    serde_json::from_str(line)
+        .map_err(|err| warn!("Couldn't deserialize crate from blob {}: {}", oid, err))
+        .ok()
+}
+
+/// Returns `true` if the path (relative to the root of the index) is a path of
+/// a crate file, as opposed to e.g. `config.json`.
+fn is_crate_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map_or(false, |name| crate_path(name) == path)
+}
+
+/// Get `crates.io` updates from changes of a single crate file.
+///
+/// New versions are paired with the version published right before them: the
+/// last line of the old file or the previous added line. The first line of an
+/// added file is the first version of a new crate.
+fn file_updates(change: FileChange) -> Vec<(Crate, ActionKind, Option<Crate>, bool)> {
+    let FileChange {
+        deleted,
+        added_file,
+        mut previous,
+        mut removed,
+        added,
+    } = change;
+
+    if deleted {
+        // Report deletion of the whole crate only once
+        return removed
+            .pop()
+            .map(|krate| (krate, ActionKind::CrateDeleted, None, false))
+            .into_iter()
+            .collect();
+    }
+
+    let mut updates = Vec::with_capacity(added.len());
+    for next in added {
+        let prev = removed
+            .iter()
+            .position(|prev| prev.id == next.id)
+            .map(|idx| removed.remove(idx));
+
+        match (prev.as_ref().map(|c| c.yanked), next.yanked) {
+            /* was yanked?, is yanked? */
+            (None, false) => {
+                // There were no deleted line & crate is not yanked.
+                // New version.
+                let prev = previous.replace(next.clone());
+                let new_crate = added_file && prev.is_none();
+                updates.push((next, ActionKind::NewVersion, prev, new_crate));
+            }
+            (Some(false), true) => {
+                // The crate was not yanked and now is yanked.
+                // Crate was yanked.
+                updates.push((next, ActionKind::Yanked, None, false));
+            }
+            (Some(true), false) => {
+                // The crate was yanked and now is not yanked.
+                // Crate was unyanked.
+                updates.push((next, ActionKind::Unyanked, None, false));
+            }
+            (Some(_), _) => {
+                // Yanked status didn't change, but something else did (e.g.
+                // index maintenance). Nothing to notify about.
+            }
+            (None, true) => {
+                // Something unexpected happened
+                warn!("Unexpected new version which is already yanked: {:?}", next);
+            }
+        }
+    }
+
+    // Lines that were deleted and weren't added back
+    updates.extend(
+        removed
+            .into_iter()
+            .map(|krate| (krate, ActionKind::Deleted, None, false)),
+    );
+
+    updates
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{file_updates, is_crate_file, FileChange};
+    use crate::krate::{ActionKind, Crate};
+
+    fn krate(name: &str, vers: &str, yanked: bool) -> Crate {
+        let line = format!(
+            r#"{{"name":"{}","vers":"{}","yanked":{}}}"#,
+            name, vers, yanked
+        );
+        serde_json::from_str(&line).unwrap()
+    }
+
+    fn summary(change: FileChange) -> Vec<(String, ActionKind, Option<String>, bool)> {
+        file_updates(change)
+            .into_iter()
+            .map(|(krate, action, previous, new_crate)| {
+                (
+                    krate.id.vers,
+                    action,
+                    previous.map(|p| p.id.vers),
+                    new_crate,
+                )
+            })
+            .collect()
+    }
+
+    fn update(
+        vers: &str,
+        action: ActionKind,
+        previous: Option<&str>,
+        new_crate: bool,
+    ) -> (String, ActionKind, Option<String>, bool) {
+        (
+            vers.to_owned(),
+            action,
+            previous.map(str::to_owned),
+            new_crate,
+        )
+    }
+
+    #[test]
+    fn several_new_versions() {
+        let change = FileChange {
+            previous: Some(krate("foo", "1.0.0", false)),
+            added: vec![
+                krate("foo", "1.0.1", false),
+                krate("foo", "1.1.0", false),
+                krate("foo", "2.0.0-rc.1", false),
+            ],
+            ..FileChange::default()
+        };
+
+        assert_eq!(
+            summary(change),
+            [
+                update("1.0.1", ActionKind::NewVersion, Some("1.0.0"), false),
+                update("1.1.0", ActionKind::NewVersion, Some("1.0.1"), false),
+                update("2.0.0-rc.1", ActionKind::NewVersion, Some("1.1.0"), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn new_crate() {
+        let change = FileChange {
+            added_file: true,
+            added: vec![krate("foo", "0.1.0", false), krate("foo", "0.1.1", false)],
+            ..FileChange::default()
+        };
+
+        assert_eq!(
+            summary(change),
+            [
+                update("0.1.0", ActionKind::NewVersion, None, true),
+                update("0.1.1", ActionKind::NewVersion, Some("0.1.0"), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn yank_and_unyank() {
+        let change = FileChange {
+            previous: Some(krate("foo", "1.1.0", false)),
+            removed: vec![krate("foo", "1.0.0", false), krate("foo", "1.0.1", true)],
+            added: vec![krate("foo", "1.0.0", true), krate("foo", "1.0.1", false)],
+            ..FileChange::default()
+        };
+
+        assert_eq!(
+            summary(change),
+            [
+                update("1.0.0", ActionKind::Yanked, None, false),
+                update("1.0.1", ActionKind::Unyanked, None, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn changed_line_without_yank_is_ignored() {
+        let mut old = krate("foo", "1.0.0", false);
+        old.cksum = "old".to_owned();
+        let change = FileChange {
+            removed: vec![old],
+            added: vec![krate("foo", "1.0.0", false)],
+            ..FileChange::default()
+        };
+
+        assert!(summary(change).is_empty());
+    }
+
+    #[test]
+    fn removed_line() {
+        let change = FileChange {
+            previous: Some(krate("foo", "1.0.1", false)),
+            removed: vec![krate("foo", "1.0.0", false)],
+            ..FileChange::default()
+        };
+
+        assert_eq!(
+            summary(change),
+            [update("1.0.0", ActionKind::Deleted, None, false)]
+        );
+    }
+
+    #[test]
+    fn removed_file() {
+        let change = FileChange {
+            deleted: true,
+            removed: vec![krate("foo", "0.1.0", false), krate("foo", "0.2.0", true)],
+            ..FileChange::default()
+        };
+
+        // Reported once, with the last version
+        assert_eq!(
+            summary(change),
+            [update("0.2.0", ActionKind::CrateDeleted, None, false)]
+        );
+    }
+
+    #[test]
+    fn already_yanked_new_version_is_ignored() {
+        let change = FileChange {
+            added: vec![krate("foo", "0.1.0", true)],
+            ..FileChange::default()
+        };
+
+        assert!(summary(change).is_empty());
+    }
+
+    #[test]
+    fn crate_files() {
+        assert!(is_crate_file(Path::new("se/rd/serde")));
+        assert!(is_crate_file(Path::new("se/rd/serde_json")));
+        assert!(is_crate_file(Path::new("1/a")));
+        assert!(is_crate_file(Path::new("2/cc")));
+        assert!(is_crate_file(Path::new("3/s/syn")));
+
+        assert!(!is_crate_file(Path::new("config.json")));
+        assert!(!is_crate_file(Path::new(".github/workflows/ci.yml")));
+        assert!(!is_crate_file(Path::new("se/rd/tokio")));
+        assert!(!is_crate_file(Path::new("3/a/syn")));
+        assert!(!is_crate_file(Path::new("README.md")));
+    }
+}
@@ -0,0 +1,95 @@
+//! Source of updates that replays updates recorded in a file.
+//!
+//! The file contains one update per line, e.g.:
+//!
+//! ```json
+//! {"action": "new_version", "crate": {"name": "serde", "vers": "1.0.126", "yanked": false}}
+//! ```
+//!
+//! This is mostly useful for testing the notifier without a real index.
+use std::path::PathBuf;
+
+use futures::stream::BoxStream;
+use log::{error, info, warn};
+use serde::Deserialize;
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, BufReader},
+};
+
+use crate::{
+    krate::{ActionKind, Crate},
+    source::{channel, unix_now, Stop, Update, UpdateSource},
+};
+
+#[derive(Deserialize)]
+struct Record {
+    action: ActionKind,
+    #[serde(rename = "crate")]
+    krate: Crate,
+}
+
+pub struct ReplaySource {
+    path: PathBuf,
+}
+
+impl ReplaySource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl UpdateSource for ReplaySource {
+    fn updates(self: Box<Self>, stop: Stop) -> BoxStream<'static, Update> {
+        let (emitter, updates) = channel();
+
+        tokio::spawn(async move {
+            let file = match File::open(&self.path).await {
+                Ok(file) => file,
+                Err(err) => {
+                    error!("couldn't open replay file {:?}: {}", self.path, err);
+                    return;
+                }
+            };
+
+            info!("start replaying updates from {:?}", self.path);
+
+            // Every replay is a new set of changes
+            let replay = unix_now();
+
+            let mut lines = BufReader::new(file).lines();
+            for line_number in 1.. {
+                if stop.is_aborted() {
+                    return;
+                }
+
+                let line = match lines.next_line().await {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break,
+                    Err(err) => {
+                        error!("couldn't read replay file {:?}: {}", self.path, err);
+                        break;
+                    }
+                };
+
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                match serde_json::from_str::<Record>(&line) {
+                    Ok(Record { action, krate }) => {
+                        let origin = format!("replay:{}:{}", replay, line_number);
+                        if !emitter.emit(&origin, krate, action, None, false).await {
+                            return;
+                        }
+                    }
+                    Err(err) => warn!("couldn't deserialize replayed update {:?}: {}", line, err),
+                }
+            }
+
+            info!("replaying updates finished");
+        });
+
+        updates
+    }
+}
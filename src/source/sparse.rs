@@ -0,0 +1,814 @@
+//! Source of updates that polls the crates.io [sparse index] over HTTP.
+//!
+//! The sparse index has no history, so (unlike the git index) it's impossible
+//! to see all updates. Instead only the files of crates someone is subscribed
+//! to (or watches with an exact name, see `/watch_new`) are polled. Files are
+//! re-downloaded only when they change (according to `ETag`/`Last-Modified`)
+//! and are mirrored into the `index_path`, so the rest of the bot can read them
+//! the same way it reads the git checkout.
+//!
+//! The state of a file is saved in the database as soon as updates found in it
+//! are processed, so after a restart polling resumes where it left off. A file
+//! without a saved state is compared with its mirror, if there is one (e.g. it
+//! was downloaded by `/subscribe`), otherwise it's a new crate.
+//!
+//! [sparse index]: https://rust-lang.github.io/rfcs/2789-sparse-index.html
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use futures::stream::BoxStream;
+use log::{error, info, warn};
+use reqwest::{header, Client, Response, StatusCode};
+use serde::Deserialize;
+
+use crate::{
+    db::{Database, SparseFile},
+    krate::{ActionKind, Crate},
+    pattern::Pattern,
+    source::{channel, wait, Stop, Update, UpdateSource},
+    util::crate_path,
+};
+
+#[derive(Debug, derive_more::Display, derive_more::From, derive_more::Error)]
+pub enum Error {
+    Http(reqwest::Error),
+    Io(std::io::Error),
+}
+
+/// `config.json` at the root of the index.
+#[derive(Debug, Deserialize)]
+struct IndexConfig {
+    dl: String,
+    #[serde(default)]
+    api: Option<String>,
+}
+
+pub struct SparseSource {
+    index: SparseIndex,
+    db: Database,
+    pull_delay: Duration,
+}
+
+impl SparseSource {
+    pub fn new(
+        url: &str,
+        mirror: &Path,
+        client: Client,
+        db: Database,
+        pull_delay: Duration,
+    ) -> Self {
+        Self {
+            index: SparseIndex::new(url, mirror, client),
+            db,
+            pull_delay,
+        }
+    }
+}
+
+impl UpdateSource for SparseSource {
+    fn updates(self: Box<Self>, stop: Stop) -> BoxStream<'static, Update> {
+        let (emitter, updates) = channel();
+        let Self {
+            mut index,
+            db,
+            pull_delay,
+        } = *self;
+
+        tokio::spawn(async move {
+            if let Err(err) = index.check_config().await {
+                error!("couldn't get config of the sparse index: {}", err);
+            }
+
+            match db.list_sparse_files().await {
+                Ok(files) => index.files.extend(files),
+                Err(err) => error!("db error while loading state of the sparse index: {}", err),
+            }
+            // Otherwise this is the first run, so every file is unknown
+            index.warm = !index.files.is_empty();
+
+            loop {
+                info!("start polling updates");
+
+                let names = match polled_names(&db).await {
+                    Ok(names) => names,
+                    Err(err) => {
+                        error!("db error while getting crates to poll: {}", err);
+                        Vec::new()
+                    }
+                };
+
+                for name in names {
+                    let FileUpdates { origin, updates } = match index.poll_one(&name).await {
+                        Ok(updates) => updates,
+                        Err(err) => {
+                            warn!("couldn't poll sparse index file of {}: {}", name, err);
+                            continue;
+                        }
+                    };
+
+                    for (krate, action, previous, new_crate) in updates {
+                        if !emitter
+                            .emit(&origin, krate, action, previous, new_crate)
+                            .await
+                        {
+                            return;
+                        }
+                    }
+
+                    // Saved right after the updates of the file were
+                    // processed, so they are neither lost nor repeated if the
+                    // bot is stopped in between
+                    save_changed(&db, &mut index).await;
+                }
+                index.warm = true;
+
+                info!("polling updates finished");
+
+                // delay for `config.pull_delay` (default 5 min)
+                if !wait(pull_delay, &stop).await {
+                    break;
+                }
+            }
+        });
+
+        updates
+    }
+}
+
+/// Names of crates to poll: subscribed crates and exact names watched with
+/// `/watch_new` (which may not be published yet).
+async fn polled_names(db: &Database) -> Result<Vec<String>, tokio_postgres::Error> {
+    let mut names: Vec<_> = db.list_subscribed_crates().await?.collect();
+    let mut seen: HashSet<_> = names.iter().map(|name| name.to_lowercase()).collect();
+
+    for (_, pattern, _) in db.list_new_crate_watchers().await? {
+        let name = match pattern.parse::<Pattern>() {
+            Ok(pattern) => pattern.literal().map(str::to_owned),
+            Err(_) => None,
+        };
+
+        if let Some(name) = name {
+            if seen.insert(name.to_lowercase()) {
+                names.push(name);
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+/// Updates found in a single file of the index.
+#[derive(Default)]
+struct FileUpdates {
+    /// Identifier of the state of the file the updates were found in
+    origin: String,
+    /// Updates with the previous versions of new versions and whether they
+    /// are the first versions of new crates
+    updates: Vec<(Crate, ActionKind, Option<Crate>, bool)>,
+}
+
+struct SparseIndex {
+    client: Client,
+    url: String,
+    mirror: PathBuf,
+    /// Lowercase crate name -> state of the file
+    files: HashMap<String, SparseFile>,
+    /// Lowercase names of crates which files changed since the last
+    /// [`SparseIndex::take_changed`]
+    changed: HashSet<String>,
+    /// Whether states of files were saved before, so files the index doesn't
+    /// know about are new crates (as opposed to the first run, when all files
+    /// are unknown)
+    warm: bool,
+}
+
+impl SparseIndex {
+    fn new(url: &str, mirror: &Path, client: Client) -> Self {
+        Self {
+            client,
+            url: url.trim_end_matches('/').to_owned(),
+            mirror: mirror.to_owned(),
+            files: HashMap::new(),
+            changed: HashSet::new(),
+            warm: false,
+        }
+    }
+
+    /// Returns states of files changed since the previous call (`None` for
+    /// files which were removed from the index).
+    fn take_changed(&mut self) -> Vec<(String, Option<SparseFile>)> {
+        let files = &self.files;
+        self.changed
+            .drain()
+            .map(|key| {
+                let file = files.get(&key).cloned();
+                (key, file)
+            })
+            .collect()
+    }
+
+    /// Fetch `config.json` of the index to check that `url` actually points to
+    /// an index.
+    async fn check_config(&self) -> Result<(), Error> {
+        let config = self
+            .client
+            .get(format!("{}/config.json", self.url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<IndexConfig>()
+            .await?;
+
+        info!(
+            "using sparse index {} (dl: {}, api: {})",
+            self.url,
+            config.dl,
+            config.api.as_deref().unwrap_or("<none>"),
+        );
+
+        Ok(())
+    }
+
+    /// Poll the file of a crate, returning all updates since the previous
+    /// poll.
+    ///
+    /// The first poll of a crate without a mirror only remembers its state,
+    /// unless the index is warm (then it's a new crate).
+    async fn poll_one(&mut self, name: &str) -> Result<FileUpdates, Error> {
+        let key = name.to_lowercase();
+
+        let mut request = self.client.get(file_url(&self.url, name));
+        if let Some(cached) = self.files.get(&key) {
+            if let Some(etag) = &cached.etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = request.send().await?;
+        match response.status() {
+            StatusCode::NOT_MODIFIED => return Ok(FileUpdates::default()),
+            StatusCode::NOT_FOUND => {
+                warn!("crate {} is not found in the sparse index", name);
+                if self.files.remove(&key).is_some() {
+                    self.changed.insert(key);
+                }
+                return Ok(FileUpdates::default());
+            }
+            _ => {}
+        }
+
+        let response = response.error_for_status()?;
+        let etag = header_str(&response, header::ETAG);
+        let last_modified = header_str(&response, header::LAST_MODIFIED);
+        let content = response.text().await?;
+
+        let mirrored = match tokio::fs::read_to_string(self.mirror.join(crate_path(name))).await {
+            Ok(old) => parse_lines(name, &old),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        write_mirror(&self.mirror, name, &content).await?;
+
+        let crates = parse_lines(name, &content);
+        let versions = crates
+            .iter()
+            .map(|krate| (krate.id.vers.clone(), krate.yanked))
+            .collect();
+
+        let updates = match self.files.get(&key) {
+            Some(cached) => diff_versions(name, &cached.versions, mirrored, crates, false),
+            None if !mirrored.is_empty() => {
+                let old = mirrored
+                    .iter()
+                    .map(|krate| (krate.id.vers.clone(), krate.yanked))
+                    .collect();
+                diff_versions(name, &old, mirrored, crates, false)
+            }
+            None if self.warm => diff_versions(name, &HashMap::new(), mirrored, crates, true),
+            None => Vec::new(),
+        };
+
+        // The same state of the file always produces the same updates, so
+        // notifications about them are deduplicated if the file is polled
+        // again (e.g. the bot was stopped before the state was saved)
+        let origin = match etag.as_ref().or_else(|| last_modified.as_ref()) {
+            Some(tag) => format!("sparse:{}@{}", key, tag),
+            None => format!("sparse:{}", key),
+        };
+
+        self.files.insert(
+            key.clone(),
+            SparseFile {
+                etag,
+                last_modified,
+                versions,
+            },
+        );
+        self.changed.insert(key);
+
+        Ok(FileUpdates { origin, updates })
+    }
+}
+
+/// Save states of files changed since the last save to the database.
+async fn save_changed(db: &Database, index: &mut SparseIndex) {
+    for (name, file) in index.take_changed() {
+        let res = match &file {
+            Some(file) => db.set_sparse_file(&name, file).await,
+            None => db.delete_sparse_file(&name).await,
+        };
+
+        if let Err(err) = res {
+            error!(
+                "db error while saving state of sparse index file of {}: {}",
+                name, err
+            );
+        }
+    }
+}
+
+/// Download the file of a single crate into the mirror.
+///
+/// Returns `false` if there is no such crate in the index.
+pub async fn download(
+    client: &Client,
+    url: &str,
+    mirror: &Path,
+    name: &str,
+) -> Result<bool, Error> {
+    let response = client
+        .get(file_url(url.trim_end_matches('/'), name))
+        .send()
+        .await?;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+
+    let content = response.error_for_status()?.text().await?;
+    write_mirror(mirror, name, &content).await?;
+
+    Ok(true)
+}
+
+/// Parse lines of a crate file, skipping (and logging) invalid ones.
+fn parse_lines(name: &str, content: &str) -> Vec<Crate> {
+    content
+        .lines()
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| {
+            serde_json::from_str(line)
+                .map_err(|err| warn!("couldn't deserialize crate {}: {}", name, err))
+                .ok()
+        })
+        .collect()
+}
+
+/// Turn changes between 2 states of a crate file into updates.
+///
+/// New versions are paired with the previous line of the file, that is the
+/// version published right before them. Versions missing from the new state
+/// are deleted, they are reported with their old lines (from `old_lines`), if
+/// those are known. The first line of a `new_file` is the first version of a
+/// new crate.
+fn diff_versions(
+    name: &str,
+    old: &HashMap<String, bool>,
+    old_lines: Vec<Crate>,
+    new: Vec<Crate>,
+    new_file: bool,
+) -> Vec<(Crate, ActionKind, Option<Crate>, bool)> {
+    let mut deleted: BTreeSet<_> = old.keys().map(String::as_str).collect();
+    for krate in &new {
+        deleted.remove(krate.id.vers.as_str());
+    }
+    let mut old_lines: HashMap<_, _> = old_lines
+        .into_iter()
+        .map(|krate| (krate.id.vers.clone(), krate))
+        .collect();
+    let deleted: Vec<_> = deleted
+        .into_iter()
+        .map(|vers| {
+            old_lines
+                .remove(vers)
+                .unwrap_or_else(|| stub(name, vers, old[vers]))
+        })
+        .collect();
+
+    let mut previous = None;
+    let mut updates: Vec<_> = new
+        .into_iter()
+        .filter_map(|krate| {
+            let prev = previous.replace(krate.clone());
+
+            /* was yanked?, is yanked? */
+            let action = match (old.get(&krate.id.vers), krate.yanked) {
+                (None, false) => ActionKind::NewVersion,
+                (Some(false), true) => ActionKind::Yanked,
+                (Some(true), false) => ActionKind::Unyanked,
+                (Some(_), _) => return None,
+                (None, true) => {
+                    warn!("New version is already yanked: {:?}", krate);
+                    return None;
+                }
+            };
+
+            match action {
+                ActionKind::NewVersion => {
+                    let new_crate = new_file && prev.is_none();
+                    Some((krate, action, prev, new_crate))
+                }
+                _ => Some((krate, action, None, false)),
+            }
+        })
+        .collect();
+
+    updates.extend(
+        deleted
+            .into_iter()
+            .map(|krate| (krate, ActionKind::Deleted, None, false)),
+    );
+
+    updates
+}
+
+/// Minimal index entry of a version which line is unknown.
+fn stub(name: &str, vers: &str, yanked: bool) -> Crate {
+    let entry = serde_json::json!({ "name": name, "vers": vers, "yanked": yanked });
+    serde_json::from_value(entry).expect("minimal index entry is valid")
+}
+
+/// Url of a crate file, the layout is the same as in the git index.
+fn file_url(base: &str, name: &str) -> String {
+    let path = crate_path(name)
+        .iter()
+        .map(|component| component.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    format!("{}/{}", base, path)
+}
+
+async fn write_mirror(mirror: &Path, name: &str, content: &str) -> std::io::Result<()> {
+    let path = mirror.join(crate_path(name));
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    tokio::fs::write(path, content).await
+}
+
+fn header_str(response: &Response, name: header::HeaderName) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::hash_map::DefaultHasher,
+        fs,
+        hash::{Hash, Hasher},
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+    };
+
+    use reqwest::Client;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{download, FileUpdates, SparseFile, SparseIndex};
+    use crate::krate::ActionKind;
+
+    /// Serve files of the `root` directory over HTTP on a random local port,
+    /// like the sparse index does (`ETag`s are hashes of the contents).
+    ///
+    /// Returns the url of the index and statuses of all responses so far.
+    async fn serve(root: PathBuf) -> (String, Arc<Mutex<Vec<u16>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let statuses = Arc::<Mutex<Vec<u16>>>::default();
+
+        let statuses_cloned = Arc::clone(&statuses);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (root, statuses) = (root.clone(), Arc::clone(&statuses_cloned));
+
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await.unwrap() {
+                            0 => return,
+                            n => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+
+                    let request = String::from_utf8_lossy(&request);
+                    let path = request.split_whitespace().nth(1).unwrap_or_default();
+                    let if_none_match = request.lines().find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("if-none-match")
+                            .then(|| value.trim().to_owned())
+                    });
+
+                    let (status, head, body) =
+                        match fs::read_to_string(root.join(path.trim_start_matches('/'))) {
+                            Ok(content) => {
+                                let etag = etag(&content);
+                                if if_none_match.as_ref() == Some(&etag) {
+                                    (
+                                        304,
+                                        format!("Not Modified\r\nETag: {}", etag),
+                                        String::new(),
+                                    )
+                                } else {
+                                    (200, format!("OK\r\nETag: {}", etag), content)
+                                }
+                            }
+                            Err(_) => (404, "Not Found".to_owned(), String::new()),
+                        };
+                    statuses.lock().unwrap().push(status);
+
+                    let response = format!(
+                        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        head,
+                        body.len(),
+                        body
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+
+        (url, statuses)
+    }
+
+    fn etag(content: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        format!("\"{:x}\"", hasher.finish())
+    }
+
+    /// Directories of the index and of the mirror of a test.
+    fn fixture(test: &str) -> (PathBuf, PathBuf) {
+        let root =
+            std::env::temp_dir().join(format!("crate_upd_bot-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        let index = root.join("index");
+        fs::create_dir_all(&index).unwrap();
+        fs::write(
+            index.join("config.json"),
+            r#"{"dl":"https://static.crates.io/crates","api":"https://crates.io"}"#,
+        )
+        .unwrap();
+
+        (index, root.join("mirror"))
+    }
+
+    fn line(vers: &str, yanked: bool) -> String {
+        format!(
+            r#"{{"name":"foo","vers":"{0}","deps":[],"cksum":"{0}","features":{{}},"yanked":{1}}}"#,
+            vers, yanked
+        )
+    }
+
+    /// Write the file of `foo` into the index or the mirror.
+    fn write_foo(root: &Path, lines: &[String]) {
+        fs::create_dir_all(root.join("3/f")).unwrap();
+        fs::write(root.join("3/f/foo"), lines.join("\n")).unwrap();
+    }
+
+    async fn index(test: &str) -> (SparseIndex, PathBuf, Arc<Mutex<Vec<u16>>>) {
+        let (root, mirror) = fixture(test);
+        let (url, statuses) = serve(root.clone()).await;
+        let index = SparseIndex::new(&url, &mirror, Client::new());
+
+        (index, root, statuses)
+    }
+
+    fn summary(updates: &FileUpdates) -> Vec<(&str, ActionKind, Option<&str>, bool)> {
+        updates
+            .updates
+            .iter()
+            .map(|(krate, action, previous, new_crate)| {
+                (
+                    krate.id.vers.as_str(),
+                    *action,
+                    previous.as_ref().map(|p| p.id.vers.as_str()),
+                    *new_crate,
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn first_poll_only_remembers_state() {
+        let (mut index, root, statuses) = index("first").await;
+        write_foo(&root, &[line("0.1.0", false)]);
+
+        let updates = index.poll_one("foo").await.unwrap();
+
+        assert!(updates.updates.is_empty());
+        assert_eq!(*statuses.lock().unwrap(), [200]);
+        let changed = index.take_changed();
+        assert_eq!(changed.len(), 1);
+        let (name, file) = &changed[0];
+        assert_eq!(name, "foo");
+        let file = file.as_ref().unwrap();
+        assert!(file.etag.is_some());
+        assert_eq!(file.versions.get("0.1.0"), Some(&false));
+    }
+
+    #[tokio::test]
+    async fn not_modified_when_etag_matches() {
+        let (mut index, root, statuses) = index("etag").await;
+        write_foo(&root, &[line("0.1.0", false)]);
+
+        index.poll_one("foo").await.unwrap();
+        index.take_changed();
+        let updates = index.poll_one("foo").await.unwrap();
+
+        assert!(updates.updates.is_empty());
+        assert_eq!(*statuses.lock().unwrap(), [200, 304]);
+        assert!(index.take_changed().is_empty());
+    }
+
+    #[tokio::test]
+    async fn deleted_crate_is_forgotten() {
+        let (mut index, root, statuses) = index("deleted").await;
+        write_foo(&root, &[line("0.1.0", false)]);
+
+        index.poll_one("foo").await.unwrap();
+        index.take_changed();
+        fs::remove_file(root.join("3/f/foo")).unwrap();
+        let updates = index.poll_one("foo").await.unwrap();
+
+        assert!(updates.updates.is_empty());
+        assert_eq!(*statuses.lock().unwrap(), [200, 404]);
+        assert!(index.files.is_empty());
+        let changed = index.take_changed();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0, "foo");
+        assert!(changed[0].1.is_none());
+    }
+
+    #[tokio::test]
+    async fn changed_lines_produce_updates() {
+        let (mut index, root, _) = index("diff").await;
+        write_foo(
+            &root,
+            &[
+                line("0.1.0", false),
+                line("0.2.0", false),
+                line("0.2.1", false),
+            ],
+        );
+
+        index.poll_one("foo").await.unwrap();
+        write_foo(
+            &root,
+            &[
+                line("0.1.0", true),
+                line("0.2.0", false),
+                line("0.3.0", false),
+            ],
+        );
+        let updates = index.poll_one("foo").await.unwrap();
+
+        assert_eq!(
+            summary(&updates),
+            [
+                ("0.1.0", ActionKind::Yanked, None, false),
+                ("0.3.0", ActionKind::NewVersion, Some("0.2.0"), false),
+                ("0.2.1", ActionKind::Deleted, None, false),
+            ]
+        );
+        // The deleted line is taken from the mirror
+        assert_eq!(updates.updates[2].0.cksum, "0.2.1");
+        let file = &index.files["foo"];
+        assert_eq!(file.versions.len(), 3);
+        assert!(!file.versions.contains_key("0.2.1"));
+    }
+
+    #[tokio::test]
+    async fn saved_state_resumes_polling() {
+        let (mut index, root, _) = index("resume").await;
+        write_foo(&root, &[line("0.1.0", false), line("0.2.0", false)]);
+
+        // As if 0.2.0 was published and 0.0.1 was deleted while the bot was
+        // down
+        let mut saved = SparseFile {
+            etag: Some("\"old\"".to_owned()),
+            ..Default::default()
+        };
+        saved.versions.insert("0.0.1".to_owned(), true);
+        saved.versions.insert("0.1.0".to_owned(), false);
+        index.files.insert("foo".to_owned(), saved);
+        let updates = index.poll_one("foo").await.unwrap();
+
+        assert_eq!(
+            summary(&updates),
+            [
+                ("0.2.0", ActionKind::NewVersion, Some("0.1.0"), false),
+                ("0.0.1", ActionKind::Deleted, None, false),
+            ]
+        );
+        let deleted = &updates.updates[1].0;
+        assert_eq!(deleted.id.name, "foo");
+        assert_eq!(deleted.cksum, "");
+        assert!(deleted.yanked);
+    }
+
+    #[tokio::test]
+    async fn mirror_is_the_previous_state() {
+        let (mut index, root, _) = index("mirror").await;
+        write_foo(&root, &[line("0.1.0", false), line("0.2.0", false)]);
+        // As if the crate was downloaded by `/subscribe` before 0.2.0
+        write_foo(&index.mirror, &[line("0.1.0", false)]);
+
+        let updates = index.poll_one("foo").await.unwrap();
+
+        assert_eq!(
+            summary(&updates),
+            [("0.2.0", ActionKind::NewVersion, Some("0.1.0"), false)]
+        );
+        let mirrored = fs::read_to_string(index.mirror.join("3/f/foo")).unwrap();
+        assert_eq!(mirrored.lines().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn unknown_file_of_warm_index_is_new_crate() {
+        let (mut index, root, _) = index("new").await;
+        write_foo(&root, &[line("0.1.0", false), line("0.1.1", false)]);
+        index.warm = true;
+
+        let updates = index.poll_one("foo").await.unwrap();
+
+        assert_eq!(
+            summary(&updates),
+            [
+                ("0.1.0", ActionKind::NewVersion, None, true),
+                ("0.1.1", ActionKind::NewVersion, Some("0.1.0"), false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn origin_depends_only_on_the_file() {
+        let (mut index, root, _) = index("origin").await;
+        write_foo(&root, &[line("0.1.0", false), line("0.2.0", false)]);
+        index.warm = true;
+
+        let first = index.poll_one("foo").await.unwrap();
+        // The state wasn't saved, e.g. the bot was stopped
+        index.files.clear();
+        fs::remove_dir_all(&index.mirror).unwrap();
+        let again = index.poll_one("foo").await.unwrap();
+
+        assert_eq!(first.origin, again.origin);
+        assert_eq!(summary(&first), summary(&again));
+        assert!(first.origin.starts_with("sparse:foo@"));
+
+        write_foo(&root, &[line("0.1.0", true), line("0.2.0", false)]);
+        let next = index.poll_one("foo").await.unwrap();
+        assert_ne!(next.origin, first.origin);
+    }
+
+    #[tokio::test]
+    async fn download_into_mirror() {
+        let (root, mirror) = fixture("download");
+        let (url, _) = serve(root.clone()).await;
+        write_foo(&root, &[line("0.1.0", false)]);
+
+        let client = Client::new();
+        assert!(download(&client, &url, &mirror, "foo").await.unwrap());
+        assert!(!download(&client, &url, &mirror, "bar").await.unwrap());
+
+        let mirrored = fs::read_to_string(mirror.join("3/f/foo")).unwrap();
+        assert_eq!(mirrored, line("0.1.0", false));
+        assert!(!mirror.join("3/b/bar").exists());
+    }
+
+    #[tokio::test]
+    async fn config_is_checked() {
+        let (index, _, statuses) = index("config").await;
+
+        index.check_config().await.unwrap();
+        assert_eq!(*statuses.lock().unwrap(), [200]);
+    }
+}
@@ -0,0 +1,719 @@
+//! Templates of messages sent by the bot.
+//!
+//! Templates of a language are loaded from a TOML file, one template per
+//! [`Key`], plus the `format` of the file. A template is text with `{variable}`
+//! placeholders (`{{` and `}}` are literal braces); the variables available to
+//! a template are listed in [`Key`].
+//!
+//! The bot ships templates for some languages (`templates/*.toml`), files from
+//! the config (`[templates]`) override them or add new languages. Templates
+//! missing from a file are taken from the built-in ones of the same language,
+//! if the file is in the same format.
+//!
+//! All templates are validated when they are loaded (including that every
+//! language has every template), so a typo in a template stops the bot at
+//! startup instead of breaking notifications later.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+};
+
+use serde::Deserialize;
+use teloxide::{
+    types::ParseMode,
+    utils::{html, markdown},
+};
+
+use crate::krate::Crate;
+
+/// Templates shipped with the bot: `(language, templates)`.
+const BUILTIN: &[(&str, &str)] = &[
+    ("en", include_str!("../templates/en.toml")),
+    ("ru", include_str!("../templates/ru.toml")),
+];
+
+/// Language used for chats which didn't choose any.
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+macro_rules! keys {
+    ($( $(#[$meta:meta])* $variant:ident = $name:literal [$($var:literal),*], )*) => {
+        /// Name of a template.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Key {
+            $( $(#[$meta])* $variant, )*
+        }
+
+        impl Key {
+            pub const ALL: &'static [Self] = &[$( Self::$variant, )*];
+
+            /// Name of the template in the file.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $( Self::$variant => $name, )*
+                }
+            }
+
+            /// Variables available to the template.
+            pub fn vars(self) -> &'static [&'static str] {
+                match self {
+                    $( Self::$variant => &[$( $var ),*], )*
+                }
+            }
+        }
+    };
+}
+
+keys! {
+    /// Notification about a new version
+    NewVersion = "new_version" ["crate", "version", "previous_version", "action", "links", "msrv", "changes"],
+    /// Notification about a yanked version
+    Yanked = "yanked" ["crate", "version", "action", "links"],
+    /// Notification about an unyanked version
+    Unyanked = "unyanked" ["crate", "version", "action", "links"],
+    /// Notification about a deleted version
+    Deleted = "deleted" ["crate", "version", "action", "links"],
+    /// Notification about a deleted crate
+    CrateDeleted = "crate_deleted" ["crate", "action"],
+    /// Alert about a yanked version pinned in lockfiles
+    PinnedYanked = "pinned_yanked" ["crate", "version", "links", "projects"],
+    /// Notification about a new version of a crate which depends on crates
+    /// whose dependents the chat is subscribed to
+    DependentUpdated = "dependent_updated" ["crate", "version", "previous_version", "links", "dependencies", "changes"],
+    /// Alert about a crate which moved its requirement on a watched crate to
+    /// a newer semver-incompatible version, `count` is the number of crates
+    /// which migrated to `new_range` so far
+    Migration = "migration" ["crate", "version", "links", "dependency", "old_req", "new_req", "old_range", "new_range", "count"],
+    /// Alert about a new crate with name matching watched `patterns`
+    NewCrate = "new_crate" ["crate", "version", "links", "patterns"],
+    /// Alert about a new crate with name similar to crates the chat is
+    /// subscribed to, `similar` is made of the templates below
+    Typosquat = "typosquat" ["crate", "version", "links", "similar"],
+    ResemblesSeparators = "resembles_separators" ["crate"],
+    ResemblesAffix = "resembles_affix" ["crate", "affix"],
+    ResemblesTypo = "resembles_typo" ["crate", "distance"],
+
+    /// `msrv` variable of `new_version`, when MSRV went above the previous
+    /// version's
+    MsrvRaised = "msrv_raised" ["old", "new"],
+    /// `msrv` variable of `new_version`, when MSRV is above the toolchain of
+    /// the chat
+    MsrvAboveToolchain = "msrv_above_toolchain" ["new", "toolchain"],
+
+    /// Changes since the previous version (`changes` variable of
+    /// `new_version`), `list` is made of the templates below
+    Changes = "changes" ["previous_version", "list"],
+    MsrvSet = "msrv_set" ["new"],
+    MsrvChanged = "msrv_changed" ["old", "new"],
+    MsrvRemoved = "msrv_removed" ["old"],
+    DepAdded = "dep_added" ["name", "req"],
+    DepRemoved = "dep_removed" ["name", "req"],
+    DepChanged = "dep_changed" ["name", "old_req", "new_req"],
+    FeatureAdded = "feature_added" ["name"],
+    FeatureRemoved = "feature_removed" ["name"],
+    MoreChanges = "more_changes" ["count"],
+
+    Start = "start" ["version"],
+    SubscribeUsage = "subscribe_usage" [],
+    InvalidFilter = "invalid_filter" ["error"],
+    Subscribed = "subscribed" ["crate", "version", "links", "filter"],
+    /// `filter` variable of `subscribed` (omitted for the default filter)
+    SubscribedFilter = "subscribed_filter" ["filter"],
+    NoSuchCrate = "no_such_crate" ["crate"],
+    CurrentLanguage = "current_language" ["language", "languages"],
+    LanguageSet = "language_set" ["language"],
+    UnknownLanguage = "unknown_language" ["language", "languages"],
+    CurrentToolchain = "current_toolchain" ["toolchain"],
+    NoToolchain = "no_toolchain" [],
+    ToolchainSet = "toolchain_set" ["toolchain"],
+    ToolchainUnset = "toolchain_unset" [],
+    InvalidToolchain = "invalid_toolchain" ["error"],
+    TyposquatEnabled = "typosquat_enabled" [],
+    TyposquatDisabled = "typosquat_disabled" [],
+    TyposquatUsage = "typosquat_usage" [],
+    UnsubscribeUsage = "unsubscribe_usage" [],
+    Unsubscribed = "unsubscribed" ["crate"],
+    SubscribeDependentsUsage = "subscribe_dependents_usage" [],
+    SubscribedDependents = "subscribed_dependents" ["crate", "dependents"],
+    /// Reply to `/subscribe_dependents` with the sparse source, which doesn't
+    /// maintain the reverse-dependency index
+    DependentsUnsupported = "dependents_unsupported" [],
+    /// Reply to `/subscribe_dependents` while the reverse-dependency index is
+    /// being built
+    DependentsNotReady = "dependents_not_ready" [],
+    UnsubscribeDependentsUsage = "unsubscribe_dependents_usage" [],
+    UnsubscribedDependents = "unsubscribed_dependents" ["crate"],
+    WatchMigrationsUsage = "watch_migrations_usage" [],
+    WatchingMigrations = "watching_migrations" ["crate"],
+    UnwatchMigrationsUsage = "unwatch_migrations_usage" [],
+    UnwatchedMigrations = "unwatched_migrations" ["crate"],
+    MigrationsUsage = "migrations_usage" [],
+    NoMigrations = "no_migrations" ["crate"],
+    Migrations = "migrations" ["crate", "list"],
+    /// An entry of `list` of `migrations`
+    MigrationsItem = "migrations_item" ["range", "count"],
+    WatchNewUsage = "watch_new_usage" [],
+    InvalidPattern = "invalid_pattern" ["error"],
+    WatchingNew = "watching_new" ["pattern"],
+    UnwatchNewUsage = "unwatch_new_usage" [],
+    UnwatchedNew = "unwatched_new" ["pattern"],
+    NotWatchingNew = "not_watching_new" ["pattern"],
+    EventsUsage = "events_usage" [],
+    InvalidEvents = "invalid_events" ["error"],
+    EventsSet = "events_set" ["crate", "events"],
+    NotSubscribed = "not_subscribed" ["crate"],
+    DigestUsage = "digest_usage" [],
+    InvalidDelivery = "invalid_delivery" ["error", "usage"],
+    DeliverySet = "delivery_set" ["delivery", "description"],
+    CurrentDelivery = "current_delivery" ["delivery", "usage"],
+    /// `description` of `delivery_set`
+    DeliveryImmediate = "delivery_immediate" [],
+    DeliveryHourly = "delivery_hourly" [],
+    DeliveryDaily = "delivery_daily" [],
+    ListEmpty = "list_empty" [],
+    List = "list" ["subscriptions"],
+    /// An entry of `subscriptions`, `details` are made of the templates below
+    ListItem = "list_item" ["crate", "version", "links", "details"],
+    /// An entry of `subscriptions` for a crate missing from the index
+    ListItemMissing = "list_item_missing" ["crate", "details"],
+    ListFilter = "list_filter" ["filter"],
+    ListEvents = "list_events" ["events"],
+    ListManifest = "list_manifest" ["manifest"],
+    /// An entry of `subscriptions` for a subscription to dependents
+    ListDependents = "list_dependents" ["crate", "dependents"],
+    /// An entry of `subscriptions` for watched migrations
+    ListMigrations = "list_migrations" ["crate"],
+    /// An entry of `subscriptions` for watched new crates
+    ListNewCrates = "list_new_crates" ["pattern"],
+    ManifestError = "manifest_error" ["file", "error"],
+    ManifestSynced = "manifest_synced" ["manifest", "found", "added", "removed", "pinned", "missing"],
+    /// `pinned` variable of `manifest_synced` (omitted if nothing is pinned)
+    ManifestPinned = "manifest_pinned" ["count"],
+    /// `missing` variable of `manifest_synced` (omitted if nothing is missing)
+    ManifestMissing = "manifest_missing" ["crates"],
+    Unblocked = "unblocked" [],
+
+    DigestHeader = "digest_header" [],
+    /// A line of a digest, `summary` is made of the templates below
+    DigestItem = "digest_item" ["crate", "summary"],
+    DigestReleased = "digest_released" ["version"],
+    DigestReleases = "digest_releases" ["first", "last", "count"],
+    DigestYanked = "digest_yanked" ["versions"],
+    DigestUnyanked = "digest_unyanked" ["versions"],
+    DigestDeleted = "digest_deleted" ["versions"],
+    DigestCrateDeleted = "digest_crate_deleted" [],
+}
+
+/// Markup of templates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Html,
+    MarkdownV2,
+    /// Text without markup (sent as escaped HTML)
+    Plain,
+}
+
+impl Format {
+    /// Parse mode to send messages in this format with.
+    pub fn parse_mode(self) -> ParseMode {
+        match self {
+            Self::Html | Self::Plain => ParseMode::Html,
+            Self::MarkdownV2 => ParseMode::MarkdownV2,
+        }
+    }
+
+    /// Escape `text` so it's displayed as is.
+    pub fn escape(self, text: &str) -> String {
+        match self {
+            Self::Html | Self::Plain => html::escape(text),
+            Self::MarkdownV2 => markdown::escape(text),
+        }
+    }
+
+    pub fn link(self, url: &str, text: &str) -> String {
+        match self {
+            Self::Html => format!("<a href='{}'>{}</a>", url, html::escape(text)),
+            Self::MarkdownV2 => {
+                // Only `)` and `\` are special inside of the url part of a link
+                let url = url.replace('\\', "\\\\").replace(')', "\\)");
+                format!("[{}]({})", markdown::escape(text), url)
+            }
+            Self::Plain => html::escape(&format!("{} ({})", text, url)),
+        }
+    }
+
+    /// Links to docs.rs, crates.io and lib.rs pages of the crate.
+    pub fn crate_links(self, krate: &Crate) -> String {
+        [
+            (krate.docsrs(), "[docs.rs]"),
+            (krate.cratesio(), "[crates.io]"),
+            (krate.librs(), "[lib.rs]"),
+        ]
+        .iter()
+        .map(|(url, text)| self.link(url, text))
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+}
+
+#[derive(Debug, derive_more::Display, derive_more::From, derive_more::Error)]
+pub enum TemplateError {
+    #[display(fmt = "couldn't read templates: {}", _0)]
+    Io(io::Error),
+    #[display(fmt = "couldn't parse templates: {}", _0)]
+    Toml(toml::de::Error),
+    #[display(fmt = "unknown template {:?}", _0)]
+    #[from(ignore)]
+    UnknownTemplate(#[error(not(source))] String),
+    #[display(fmt = "template {:?} is missing", _0)]
+    #[from(ignore)]
+    MissingTemplate(#[error(not(source))] &'static str),
+    #[display(fmt = "unknown variable {{{}}} in template {:?}", variable, template)]
+    #[from(ignore)]
+    UnknownVariable {
+        template: &'static str,
+        variable: String,
+    },
+    #[display(fmt = "unmatched brace in template {:?}", _0)]
+    #[from(ignore)]
+    UnmatchedBrace(#[error(not(source))] &'static str),
+    #[display(fmt = "unescaped character {:?} in template {:?}", character, template)]
+    #[from(ignore)]
+    UnescapedCharacter {
+        template: &'static str,
+        character: char,
+    },
+    #[display(fmt = "templates of {:?} are in a different format", _0)]
+    #[from(ignore)]
+    FormatMismatch(#[error(not(source))] String),
+    #[display(fmt = "{}: {}", language, source)]
+    #[from(ignore)]
+    Language {
+        language: String,
+        source: Box<TemplateError>,
+    },
+}
+
+impl TemplateError {
+    fn in_language(self, language: &str) -> Self {
+        Self::Language {
+            language: language.to_owned(),
+            source: Box::new(self),
+        }
+    }
+}
+
+/// Contents of a templates file.
+#[derive(Deserialize)]
+struct TemplatesFile {
+    format: Format,
+    #[serde(flatten)]
+    templates: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+pub struct Templates {
+    format: Format,
+    templates: HashMap<&'static str, Template>,
+}
+
+impl Templates {
+    fn read(path: &str) -> Result<TemplatesFile, TemplateError> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn from_file(
+        mut file: TemplatesFile,
+        mut fallback: Option<TemplatesFile>,
+    ) -> Result<Self, TemplateError> {
+        if let Some(name) = file
+            .templates
+            .keys()
+            .find(|name| Key::ALL.iter().all(|key| key.name() != name.as_str()))
+        {
+            return Err(TemplateError::UnknownTemplate(name.clone()));
+        }
+
+        let format = file.format;
+        let mut fallback = fallback
+            .as_mut()
+            .filter(|fallback| fallback.format == format);
+
+        let mut templates = HashMap::new();
+        for &key in Key::ALL {
+            let source = file
+                .templates
+                .remove(key.name())
+                .or_else(|| fallback.as_mut()?.templates.remove(key.name()))
+                .ok_or_else(|| TemplateError::MissingTemplate(key.name()))?;
+
+            templates.insert(key.name(), Template::parse(key, &source, format)?);
+        }
+
+        Ok(Self { format, templates })
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Render the template `key` with variables from `args`.
+    ///
+    /// Variables missing from `args` are rendered as empty strings.
+    pub fn render(&self, key: Key, args: &Args) -> String {
+        let template = &self.templates[key.name()];
+
+        let mut res = String::new();
+        for part in &template.parts {
+            match part {
+                Part::Text(text) => res.push_str(text),
+                Part::Var(var) => match args.get(var) {
+                    Some(Arg::Text(text)) => res.push_str(&self.format.escape(text)),
+                    Some(Arg::Markup(markup)) => res.push_str(markup),
+                    None => {}
+                },
+            }
+        }
+
+        res
+    }
+}
+
+/// Templates of all languages.
+#[derive(Debug)]
+pub struct Locales {
+    locales: BTreeMap<String, Templates>,
+}
+
+impl Locales {
+    /// Load built-in templates, overriding them with templates from the given
+    /// files (language => path).
+    pub fn load(paths: &BTreeMap<String, String>) -> Result<Self, TemplateError> {
+        let mut builtin = BTreeMap::new();
+        for &(language, source) in BUILTIN {
+            let file = toml::from_str::<TemplatesFile>(source)
+                .map_err(|err| TemplateError::from(err).in_language(language))?;
+            builtin.insert(language.to_owned(), file);
+        }
+
+        let mut locales = BTreeMap::new();
+        for (language, path) in paths {
+            let templates = Templates::read(path)
+                .and_then(|file| Templates::from_file(file, builtin.remove(language)))
+                .map_err(|err| err.in_language(language))?;
+            locales.insert(language.clone(), templates);
+        }
+
+        for (language, file) in builtin {
+            let templates =
+                Templates::from_file(file, None).map_err(|err| err.in_language(&language))?;
+            locales.insert(language, templates);
+        }
+
+        // All messages are sent with the same parse mode
+        let format = locales[DEFAULT_LANGUAGE].format;
+        if let Some(language) = locales
+            .iter()
+            .find(|(_, templates)| templates.format != format)
+            .map(|(language, _)| language)
+        {
+            return Err(TemplateError::FormatMismatch(language.clone()));
+        }
+
+        Ok(Self { locales })
+    }
+
+    /// Templates shipped with the bot.
+    pub fn builtin() -> Self {
+        Self::load(&BTreeMap::new()).expect("built-in templates are invalid")
+    }
+
+    /// Format of templates (the same for all languages).
+    pub fn format(&self) -> Format {
+        self.get(None).format
+    }
+
+    /// Templates of a language, `None` or an unknown language means the
+    /// default one.
+    pub fn get(&self, language: Option<&str>) -> &Templates {
+        language
+            .and_then(|language| self.locales.get(language))
+            .unwrap_or_else(|| &self.locales[DEFAULT_LANGUAGE])
+    }
+
+    pub fn languages(&self) -> impl Iterator<Item = &str> {
+        self.locales.keys().map(String::as_str)
+    }
+
+    /// Find a known language by a language tag (e.g. `ru-RU` or `ru`).
+    pub fn resolve(&self, tag: &str) -> Option<&str> {
+        let tag = tag.trim().to_lowercase().replace('_', "-");
+        let primary = tag.split('-').next().unwrap_or_default();
+
+        self.locales
+            .get_key_value(tag.as_str())
+            .or_else(|| self.locales.get_key_value(primary))
+            .map(|(language, _)| language.as_str())
+    }
+}
+
+impl Default for Locales {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+/// Parsed template.
+#[derive(Debug)]
+struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug)]
+enum Part {
+    /// Text, already escaped if needed
+    Text(String),
+    /// Name of a variable
+    Var(&'static str),
+}
+
+impl Template {
+    fn parse(key: Key, source: &str, format: Format) -> Result<Self, TemplateError> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| TemplateError::UnmatchedBrace(key.name()))?;
+                    let name = rest[..end].trim();
+                    let var = key
+                        .vars()
+                        .iter()
+                        .copied()
+                        .find(|var| *var == name)
+                        .ok_or_else(|| TemplateError::UnknownVariable {
+                            template: key.name(),
+                            variable: name.to_owned(),
+                        })?;
+
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                    parts.push(Part::Var(var));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(TemplateError::UnmatchedBrace(key.name())),
+                c => text.push(c),
+            }
+        }
+        parts.push(Part::Text(text));
+
+        match format {
+            Format::Html => {}
+            // Text without markup still needs to be escaped since it's sent as HTML
+            Format::Plain => {
+                for part in &mut parts {
+                    if let Part::Text(text) = part {
+                        *text = html::escape(text);
+                    }
+                }
+            }
+            Format::MarkdownV2 => {
+                if let Some(character) = unescaped_markdown(&parts) {
+                    return Err(TemplateError::UnescapedCharacter {
+                        template: key.name(),
+                        character,
+                    });
+                }
+            }
+        }
+
+        parts.retain(|part| !matches!(part, Part::Text(text) if text.is_empty()));
+
+        Ok(Self { parts })
+    }
+}
+
+/// Characters which must be escaped in MarkdownV2 everywhere except for code
+/// and urls of links, unless they are a part of markup.
+const MARKDOWN_RESERVED: &[char] = &['(', ')', '>', '#', '+', '-', '=', '{', '}', '.', '!'];
+
+/// Find a character of the text of a MarkdownV2 template which telegram
+/// requires to be escaped, but which isn't.
+///
+/// Variables are escaped when rendered, so they are skipped, but code spans
+/// and links may continue past them.
+fn unescaped_markdown(parts: &[Part]) -> Option<char> {
+    #[derive(PartialEq)]
+    enum State {
+        Text,
+        /// Inside of `code` or ```pre```
+        Code,
+        /// After `]`, where the url of a link may start
+        LinkText,
+        /// Inside of the url of a link
+        Url,
+    }
+
+    let mut state = State::Text;
+    for part in parts {
+        let text = match part {
+            Part::Text(text) => text,
+            Part::Var(_) => {
+                if state == State::LinkText {
+                    state = State::Text;
+                }
+                continue;
+            }
+        };
+
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                // Escapes must escape something
+                if chars.next().is_none() {
+                    return Some(c);
+                }
+                continue;
+            }
+
+            state = match (state, c) {
+                (State::Code, '`') => State::Text,
+                (State::Code, _) => State::Code,
+                (State::Url, ')') => State::Text,
+                (State::Url, _) => State::Url,
+                (State::LinkText, '(') => State::Url,
+                (_, '`') => State::Code,
+                (_, ']') => State::LinkText,
+                (_, c) if MARKDOWN_RESERVED.contains(&c) => return Some(c),
+                (_, _) => State::Text,
+            };
+        }
+    }
+
+    None
+}
+
+/// Values of template variables.
+#[derive(Debug, Default)]
+pub struct Args {
+    args: Vec<(&'static str, Arg)>,
+}
+
+#[derive(Debug)]
+enum Arg {
+    /// Text which is escaped when rendered
+    Text(String),
+    /// Already formatted text (e.g. links or other rendered templates)
+    Markup(String),
+}
+
+impl Args {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a variable with plain text value.
+    pub fn text(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.args.push((name, Arg::Text(value.to_string())));
+        self
+    }
+
+    /// Add a variable with already formatted value.
+    pub fn markup(mut self, name: &'static str, value: String) -> Self {
+        self.args.push((name, Arg::Markup(value)));
+        self
+    }
+
+    fn get(&self, name: &str) -> Option<&Arg> {
+        self.args
+            .iter()
+            .find(|(arg, _)| *arg == name)
+            .map(|(_, arg)| arg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, Key, Locales, Template, TemplateError, Templates, TemplatesFile, BUILTIN};
+
+    #[test]
+    fn builtin_templates_are_complete() {
+        for &(language, source) in BUILTIN {
+            let file: TemplatesFile = toml::from_str(source).unwrap();
+            // Without a fallback every template must be in the file and use
+            // only known variables
+            if let Err(err) = Templates::from_file(file, None) {
+                panic!("{}: {}", language, err);
+            }
+        }
+
+        let locales = Locales::builtin();
+        assert_eq!(locales.languages().collect::<Vec<_>>(), ["en", "ru"]);
+    }
+
+    #[test]
+    fn unknown_variable() {
+        let err =
+            Template::parse(Key::Unsubscribed, "{crate} {version}", Format::Html).unwrap_err();
+        assert!(matches!(
+            err,
+            TemplateError::UnknownVariable { template: "unsubscribed", variable } if variable == "version"
+        ));
+
+        let err = Template::parse(Key::Unsubscribed, "{crate", Format::Html).unwrap_err();
+        assert!(matches!(err, TemplateError::UnmatchedBrace("unsubscribed")));
+    }
+
+    fn markdown(source: &str) -> Result<Template, char> {
+        Template::parse(Key::Changes, source, Format::MarkdownV2).map_err(|err| match err {
+            TemplateError::UnescapedCharacter { character, .. } => character,
+            err => panic!("{}", err),
+        })
+    }
+
+    #[test]
+    fn markdown_reserved_characters() {
+        assert!(markdown("Changes since `{previous_version}`:\n{list}").is_ok());
+        assert!(markdown("*Changes* since _{previous_version}_\\.").is_ok());
+        assert!(markdown("`1.0 (beta)` and ```\nlet x = {{}};\n```").is_ok());
+        assert!(markdown("[docs\\.rs](https://docs.rs/a-b.c?x=1)").is_ok());
+        assert!(markdown("\\{{{list}\\}}").is_ok());
+
+        assert_eq!(
+            markdown("Changes since {previous_version}.").err(),
+            Some('.')
+        );
+        assert_eq!(
+            markdown("Changes (since {previous_version})").err(),
+            Some('(')
+        );
+        assert_eq!(markdown("[{list}] - {previous_version}").err(), Some('-'));
+        assert_eq!(markdown("{{{list}}}").err(), Some('{'));
+        assert_eq!(markdown("{list}\\").err(), Some('\\'));
+    }
+
+    #[test]
+    fn links() {
+        let url = "https://example.com/a)b\\c";
+        assert_eq!(
+            Format::MarkdownV2.link(url, "x.y"),
+            "[x\\.y](https://example.com/a\\)b\\\\c)"
+        );
+        assert_eq!(
+            Format::Html.link("https://example.com", "<x>"),
+            "<a href='https://example.com'>&lt;x&gt;</a>"
+        );
+    }
+}
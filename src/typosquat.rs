@@ -0,0 +1,213 @@
+//! Detection of new crates with names confusingly similar to names of
+//! existing crates (typosquatting).
+use crate::template::{Args, Key, Templates};
+
+/// Prefixes and suffixes commonly added to names of crates, compared with
+/// `-` and `_` removed (so `rs` covers `-rs`, `_rs` and `rs-`).
+const AFFIXES: &[&str] = &["rs", "rust", "lib"];
+
+/// Why a new crate name resembles the name of an existing crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resemblance {
+    /// Names differ only in `-`/`_` separators (e.g. `serdejson` and
+    /// `serde_json`)
+    Separators,
+    /// One name is the other with a common prefix or suffix (e.g. `tokio-rs`
+    /// and `tokio`)
+    Affix(&'static str),
+    /// Names are within a small edit distance (e.g. `tokoi` and `tokio`)
+    Typo(usize),
+}
+
+impl Resemblance {
+    /// Check whether the name of a `new` crate resembles the name of an
+    /// `existing` one.
+    ///
+    /// Names that crates.io considers the same (differing only in case and
+    /// `-`/`_`) never resemble each other.
+    pub fn check(new: &str, existing: &str) -> Option<Self> {
+        let (new, existing) = (new.to_lowercase(), existing.to_lowercase());
+        if new.replace('_', "-") == existing.replace('_', "-") {
+            return None;
+        }
+
+        let (new_stripped, existing_stripped) =
+            (strip_separators(&new), strip_separators(&existing));
+        if new_stripped == existing_stripped {
+            return Some(Self::Separators);
+        }
+
+        let affix = AFFIXES.iter().find(|affix| {
+            is_affixed(&new_stripped, &existing_stripped, affix)
+                || is_affixed(&existing_stripped, &new_stripped, affix)
+        });
+        if let Some(affix) = affix {
+            return Some(Self::Affix(affix));
+        }
+
+        let distance = edit_distance(&new.replace('_', "-"), &existing.replace('_', "-"));
+        (distance <= max_distance(existing.chars().count())).then(|| Self::Typo(distance))
+    }
+
+    /// Render an entry of `similar` of `typosquat`.
+    pub fn render(&self, existing: &str, templates: &Templates) -> String {
+        match self {
+            Self::Separators => {
+                let args = Args::new().text("crate", existing);
+                templates.render(Key::ResemblesSeparators, &args)
+            }
+            Self::Affix(affix) => {
+                let args = Args::new().text("crate", existing).text("affix", affix);
+                templates.render(Key::ResemblesAffix, &args)
+            }
+            Self::Typo(distance) => {
+                let args = Args::new()
+                    .text("crate", existing)
+                    .text("distance", distance);
+                templates.render(Key::ResemblesTypo, &args)
+            }
+        }
+    }
+}
+
+fn strip_separators(name: &str) -> String {
+    name.chars().filter(|&c| c != '-' && c != '_').collect()
+}
+
+/// Whether `name` is `base` with `affix` as a prefix or a suffix.
+fn is_affixed(name: &str, base: &str, affix: &str) -> bool {
+    name.len() == base.len() + affix.len()
+        && ((name.starts_with(affix) && name.ends_with(base))
+            || (name.starts_with(base) && name.ends_with(affix)))
+}
+
+/// Maximum edit distance to a name of the given length which is still
+/// considered a typo. Short names are close to too many other names to
+/// tell anything.
+fn max_distance(len: usize) -> usize {
+    match len {
+        0..=4 => 0,
+        5..=9 => 1,
+        _ => 2,
+    }
+}
+
+/// Optimal string alignment distance: the number of insertions, deletions,
+/// substitutions and transpositions of adjacent characters needed to turn
+/// `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<_>, Vec<_>) = (a.chars().collect(), b.chars().collect());
+
+    // Three last rows of the distance matrix
+    let mut before: Vec<usize> = vec![0; b.len() + 1];
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur = vec![0; b.len() + 1];
+
+    for i in 1..=a.len() {
+        cur[0] = i;
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            cur[j] = (prev[j] + 1).min(cur[j - 1] + 1).min(prev[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                cur[j] = cur[j].min(before[j - 2] + 1);
+            }
+        }
+
+        std::mem::swap(&mut before, &mut prev);
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edit_distance, max_distance, Resemblance};
+
+    #[test]
+    fn typos() {
+        assert_eq!(
+            Resemblance::check("tokoi", "tokio"),
+            Some(Resemblance::Typo(1))
+        );
+        assert_eq!(
+            Resemblance::check("tokip", "tokio"),
+            Some(Resemblance::Typo(1))
+        );
+        assert_eq!(
+            Resemblance::check("Tokioo", "tokio"),
+            Some(Resemblance::Typo(1))
+        );
+        assert_eq!(
+            Resemblance::check("serd_jsn", "serde_json"),
+            Some(Resemblance::Typo(2))
+        );
+        assert_eq!(Resemblance::check("sed_jsn", "serde_json"), None);
+        assert_eq!(Resemblance::check("axum", "tokio"), None);
+    }
+
+    #[test]
+    fn separators() {
+        assert_eq!(
+            Resemblance::check("serdejson", "serde_json"),
+            Some(Resemblance::Separators)
+        );
+        assert_eq!(
+            Resemblance::check("serde-js-on", "serde_json"),
+            Some(Resemblance::Separators)
+        );
+    }
+
+    #[test]
+    fn affixes() {
+        assert_eq!(
+            Resemblance::check("tokio-rs", "tokio"),
+            Some(Resemblance::Affix("rs"))
+        );
+        assert_eq!(
+            Resemblance::check("rust_tokio", "tokio"),
+            Some(Resemblance::Affix("rust"))
+        );
+        assert_eq!(
+            Resemblance::check("libtokio", "tokio"),
+            Some(Resemblance::Affix("lib"))
+        );
+        assert_eq!(
+            Resemblance::check("tokio", "tokio-rs"),
+            Some(Resemblance::Affix("rs"))
+        );
+    }
+
+    #[test]
+    fn same_name() {
+        // crates.io doesn't allow names differing only in case and `-`/`_`
+        assert_eq!(Resemblance::check("serde-json", "serde_json"), None);
+        assert_eq!(Resemblance::check("Serde_Json", "serde_json"), None);
+        assert_eq!(Resemblance::check("tokio", "tokio"), None);
+    }
+
+    #[test]
+    fn short_names() {
+        assert_eq!(max_distance(4), 0);
+        assert_eq!(max_distance(5), 1);
+        assert_eq!(max_distance(9), 1);
+        assert_eq!(max_distance(10), 2);
+
+        assert_eq!(Resemblance::check("rnad", "rand"), None);
+        assert_eq!(Resemblance::check("lgo", "log"), None);
+        assert_eq!(
+            Resemblance::check("rand-rs", "rand"),
+            Some(Resemblance::Affix("rs"))
+        );
+    }
+
+    #[test]
+    fn distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("tokio", ""), 5);
+        assert_eq!(edit_distance("tokio", "tokio"), 0);
+        assert_eq!(edit_distance("tokoi", "tokio"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("ca", "abc"), 3);
+    }
+}
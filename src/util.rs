@@ -1,4 +1,5 @@
 use std::{
+    fs,
     future::Future,
     path::{Path, PathBuf},
     time::Duration,
@@ -18,6 +19,64 @@ pub fn crate_path(name: &str) -> PathBuf {
     }
 }
 
+/// Normalized name of a crate.
+///
+/// crates.io considers names that differ only in case and `-`/`_` to be the
+/// same crate, so this is what names should be compared by.
+pub fn normalize_crate_name(name: &str) -> String {
+    name.to_lowercase().replace('-', "_")
+}
+
+/// Find file of a crate in crates.io-index checkout at `index`, ignoring case
+/// and `-`/`_` differences in `name`.
+pub fn find_crate_file(index: &Path, name: &str) -> Option<PathBuf> {
+    // Crate names are ASCII-only, this also makes byte indexing below safe
+    if name.is_empty() || !name.is_ascii() {
+        return None;
+    }
+
+    let normalized = normalize_crate_name(name);
+
+    // Directory of the file depends on the first 4 characters, so every
+    // spelling of `-`/`_` among them needs to be checked
+    let separators: Vec<_> = normalized
+        .bytes()
+        .take(4)
+        .enumerate()
+        .filter(|&(_, b)| b == b'_')
+        .map(|(i, _)| i)
+        .collect();
+
+    for mask in 0..1 << separators.len() {
+        let mut spelling = normalized.clone().into_bytes();
+        for (bit, &i) in separators.iter().enumerate() {
+            if mask & 1 << bit != 0 {
+                spelling[i] = b'-';
+            }
+        }
+        let spelling = String::from_utf8(spelling).expect("ASCII is valid UTF-8");
+
+        let path = index.join(crate_path(&spelling));
+        let dir = match path.parent().map(fs::read_dir) {
+            Some(Ok(dir)) => dir,
+            _ => continue,
+        };
+
+        let file = dir.filter_map(Result::ok).find(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .map_or(false, |file| normalize_crate_name(file) == normalized)
+        });
+
+        if let Some(file) = file {
+            return Some(file.path());
+        }
+    }
+
+    None
+}
+
 /// Try executing async function `f`. On error delay for `delay`. If after `n`
 /// tries `f` still fails, return last error.
 pub async fn tryn<F, Fut, T, E>(n: usize, delay: Duration, mut f: F) -> Result<T, E>
@@ -35,3 +94,83 @@ where
 
     f().await
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::{crate_path, find_crate_file, normalize_crate_name};
+
+    #[test]
+    fn normalized_names() {
+        assert_eq!(normalize_crate_name("serde_json"), "serde_json");
+        assert_eq!(normalize_crate_name("serde-json"), "serde_json");
+        assert_eq!(normalize_crate_name("Serde-JSON"), "serde_json");
+        assert_eq!(normalize_crate_name("a-b_c"), "a_b_c");
+        assert_eq!(normalize_crate_name("A"), "a");
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(crate_path("A"), PathBuf::from("1/a"));
+        assert_eq!(crate_path("cc"), PathBuf::from("2/cc"));
+        assert_eq!(crate_path("Syn"), PathBuf::from("3/s/syn"));
+        assert_eq!(crate_path("a-b"), PathBuf::from("3/a/a-b"));
+        assert_eq!(crate_path("serde_json"), PathBuf::from("se/rd/serde_json"));
+        assert_eq!(crate_path("a-bc-d"), PathBuf::from("a-/bc/a-bc-d"));
+    }
+
+    /// Index checkout with files of the given crates.
+    fn index(test: &str, names: &[&str]) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("crate_upd_bot-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        for name in names {
+            let path = root.join(crate_path(name));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        root
+    }
+
+    #[test]
+    fn find_files_with_any_spelling() {
+        let root = index(
+            "find",
+            &[
+                "serde_json",
+                "tokio-util",
+                "a",
+                "cc",
+                "a-b",
+                "a_bc-d",
+                "x-y_z",
+            ],
+        );
+        let find = |name| {
+            find_crate_file(&root, name).map(|path| path.strip_prefix(&root).unwrap().to_owned())
+        };
+
+        assert_eq!(find("serde_json"), Some(PathBuf::from("se/rd/serde_json")));
+        assert_eq!(find("serde-json"), Some(PathBuf::from("se/rd/serde_json")));
+        assert_eq!(find("Serde-JSON"), Some(PathBuf::from("se/rd/serde_json")));
+        assert_eq!(find("tokio_util"), Some(PathBuf::from("to/ki/tokio-util")));
+
+        // 1, 2 and 3 character names
+        assert_eq!(find("A"), Some(PathBuf::from("1/a")));
+        assert_eq!(find("cc"), Some(PathBuf::from("2/cc")));
+        assert_eq!(find("a_b"), Some(PathBuf::from("3/a/a-b")));
+
+        // Separators among the first 4 characters change the directory
+        assert_eq!(find("a-bc_d"), Some(PathBuf::from("a_/bc/a_bc-d")));
+        assert_eq!(find("x_y-z"), Some(PathBuf::from("x-/y_/x-y_z")));
+
+        assert_eq!(find("serde"), None);
+        assert_eq!(find("b"), None);
+        assert_eq!(find("ab"), None);
+        assert_eq!(find(""), None);
+        assert_eq!(find("сердце"), None);
+    }
+}